@@ -0,0 +1,30 @@
+/* C bindings for the keyls cryptographic token key lister. */
+#ifndef KEYLS_H
+#define KEYLS_H
+
+#include <stddef.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/*
+ * List the keys held by the key store at uri, e.g. "kmip:user:pass@host:5696" or "pkcs11:label:pin@/path/to/lib.so",
+ * using the same syntax as the keyls command line tool. Additional keyls command line options, e.g. "--insecure", may
+ * be given as num_options NUL terminated strings in options, which may be NULL if num_options is 0.
+ *
+ * On success returns 0 and sets *keys_json to a JSON array of objects with "id", "type", "name", "alg", "len" and
+ * "token" fields. On failure returns a non-zero value and sets *error to a description of the problem. The string
+ * returned via either pointer must be freed using keyls_free_string().
+ */
+int keyls_list_keys(const char *uri, const char *const *options, size_t num_options, char **keys_json,
+                    char **error);
+
+/* Free a string returned by keyls_list_keys(). Does nothing if s is NULL. */
+void keyls_free_string(char *s);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* KEYLS_H */
@@ -0,0 +1,87 @@
+//! C bindings for the keyls key listing API, see `include/keyls.h`.
+use std::{
+    ffi::{c_char, c_int, CStr, CString},
+    panic::catch_unwind,
+    ptr,
+};
+
+use anyhow::{anyhow, Result};
+use clap::Parser;
+
+use keyls::config::Opt;
+
+/// List the keys held by the key store at `uri` as a JSON array.
+///
+/// # Safety
+///
+/// `uri` must be a valid NUL terminated string, `options` must point to `num_options` valid NUL terminated strings (or
+/// may be null if `num_options` is zero) and `keys_json` and `error` must be valid pointers to write to.
+#[no_mangle]
+pub unsafe extern "C" fn keyls_list_keys(
+    uri: *const c_char,
+    options: *const *const c_char,
+    num_options: usize,
+    keys_json: *mut *mut c_char,
+    error: *mut *mut c_char,
+) -> c_int {
+    *keys_json = ptr::null_mut();
+    *error = ptr::null_mut();
+
+    let res = catch_unwind(|| list_keys(uri, options, num_options))
+        .unwrap_or_else(|_| Err(anyhow!("Internal error: keyls panicked")));
+
+    match res {
+        Ok(json) => {
+            *keys_json = into_c_string(json);
+            0
+        }
+        Err(err) => {
+            *error = into_c_string(format!("{:#}", err));
+            1
+        }
+    }
+}
+
+/// Free a string returned by [keyls_list_keys].
+///
+/// # Safety
+///
+/// `s` must be null or a string returned by [keyls_list_keys] that has not already been freed.
+#[no_mangle]
+pub unsafe extern "C" fn keyls_free_string(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+unsafe fn list_keys(
+    uri: *const c_char,
+    options: *const *const c_char,
+    num_options: usize,
+) -> Result<String> {
+    if uri.is_null() {
+        return Err(anyhow!("Missing key store URI"));
+    }
+
+    let mut args = vec![
+        "keyls".to_string(),
+        CStr::from_ptr(uri).to_str()?.to_string(),
+    ];
+    if num_options > 0 {
+        for &option in std::slice::from_raw_parts(options, num_options) {
+            args.push(CStr::from_ptr(option).to_str()?.to_string());
+        }
+    }
+    let opt =
+        Opt::try_parse_from(args).map_err(|err| anyhow!(err.to_string().trim_end().to_string()))?;
+
+    let keys = keyls::connect(&opt)?.list_keys()?;
+    Ok(serde_json::to_string(&keys)?)
+}
+
+fn into_c_string(s: String) -> *mut c_char {
+    // Interior NUL bytes cannot be represented in a C string so replace them rather than fail.
+    CString::new(s.replace('\0', "\u{FFFD}"))
+        .expect("NUL bytes were replaced")
+        .into_raw()
+}
@@ -0,0 +1,298 @@
+//! Names for the algorithms of keys, from the KMIP Cryptographic Algorithm enumeration, PKCS#11 key types and the
+//! named elliptic curves whose OIDs PKCS#11 EC keys hold.
+//!
+//! Every kind of key store names algorithms with the same canonical vocabulary, so that the same kind of key has the
+//! same name wherever it is held and --alg matches it the same way: the name of the algorithm family (e.g. `RSA`,
+//! `ECDSA`, `3DES` or `Ed25519`), followed for an elliptic curve key by the curve if it is known (e.g. `ECDSA P-256`)
+//! and for a block cipher key by its size (e.g. `AES-256`).
+use cryptoki::object::KeyType;
+
+/// The canonical name of a KMIP Cryptographic Algorithm enumeration value, as given by the KMIP 2.1 specification
+/// where not named otherwise by the canonical vocabulary, or its hex value if it is an extension or is not known.
+pub(crate) fn kmip_algorithm_name(alg: u32) -> String {
+    let name = match alg {
+        0x01 => "DES",
+        0x02 => "3DES",
+        0x03 => "AES",
+        0x04 => "RSA",
+        0x05 => "DSA",
+        0x06 => "ECDSA",
+        0x07 => "HMAC-SHA1",
+        0x08 => "HMAC-SHA224",
+        0x09 => "HMAC-SHA256",
+        0x0A => "HMAC-SHA384",
+        0x0B => "HMAC-SHA512",
+        0x0C => "HMAC-MD5",
+        0x0D => "DH",
+        0x0E => "ECDH",
+        0x0F => "ECMQV",
+        0x10 => "Blowfish",
+        0x11 => "Camellia",
+        0x12 => "CAST5",
+        0x13 => "IDEA",
+        0x14 => "MARS",
+        0x15 => "RC2",
+        0x16 => "RC4",
+        0x17 => "RC5",
+        0x18 => "SKIPJACK",
+        0x19 => "Twofish",
+        // A key for use with any elliptic curve algorithm, which is most commonly ECDSA
+        0x1A => "ECDSA",
+        0x1B => "One Time Pad",
+        0x1C => "ChaCha20",
+        0x1D => "Poly1305",
+        0x1E => "ChaCha20Poly1305",
+        0x1F => "SHA3-224",
+        0x20 => "SHA3-256",
+        0x21 => "SHA3-384",
+        0x22 => "SHA3-512",
+        0x23 => "HMAC-SHA3-224",
+        0x24 => "HMAC-SHA3-256",
+        0x25 => "HMAC-SHA3-384",
+        0x26 => "HMAC-SHA3-512",
+        0x27 => "SHAKE-128",
+        0x28 => "SHAKE-256",
+        0x29 => "ARIA",
+        0x2A => "SEED",
+        0x2B => "SM2",
+        0x2C => "SM3",
+        0x2D => "SM4",
+        // Named as PKCS#11 names them, which does not distinguish the 2001 and 2012 versions
+        0x2E => "GOST R 34.10",
+        0x2F => "GOST R 34.11",
+        0x30 => "GOST R 34.13-2015",
+        0x31 => "GOST 28147-89",
+        0x32 => "XMSS",
+        0x33 => "SPHINCS-256",
+        0x34 => "McEliece",
+        0x35 => "McEliece-6960119",
+        0x36 => "McEliece-8192128",
+        0x37 => "Ed25519",
+        0x38 => "Ed448",
+        _ => return format!("{:#010X}", alg),
+    };
+    name.to_string()
+}
+
+/// The canonical name of the algorithm of a PKCS#11 key with the given CKA_KEY_TYPE, which for an elliptic curve key
+/// is refined by its curve (see [Curve]).
+pub(crate) fn pkcs11_key_type_name(key_type: KeyType) -> String {
+    let name = match key_type {
+        KeyType::RSA => "RSA",
+        KeyType::DSA => "DSA",
+        KeyType::DH | KeyType::X9_42_DH => "DH",
+        // A key for use with ECDSA or ECDH, named as KMIP ECDSA keys are as it is most commonly used for ECDSA
+        KeyType::EC => "ECDSA",
+        KeyType::EC_EDWARDS => "EdDSA",
+        KeyType::EC_MONTGOMERY => "XDH",
+        KeyType::DES => "DES",
+        KeyType::DES3 => "3DES",
+        KeyType::AES => "AES",
+        KeyType::KEA => "KEA",
+        KeyType::GOSTR3410 => "GOST R 34.10",
+        KeyType::GOSTR3411 => "GOST R 34.11",
+        KeyType::GOST28147 => "GOST 28147-89",
+        // Otherwise the name of the CKK_ constant, e.g. CKK_AES
+        key_type => return key_type.to_string().trim_start_matches("CKK_").to_string(),
+    };
+    name.to_string()
+}
+
+/// Other names by which algorithms are known, e.g. to the kmip-protocol crate or plugins, and their canonical names.
+const ALIASES: &[(&str, &str)] = &[
+    ("TRIPLE_DES", "3DES"),
+    ("DES3", "3DES"),
+    ("DESede", "3DES"),
+    ("TDEA", "3DES"),
+    ("EC", "ECDSA"),
+    ("GOST R 34.10-2001", "GOST R 34.10"),
+    ("GOST R 34.10-2012", "GOST R 34.10"),
+    ("GOST R 34.11-2012", "GOST R 34.11"),
+];
+
+/// The canonical name of an algorithm named by some other source, ignoring case, or the name itself if it is not one
+/// that keyls knows.
+pub(crate) fn canonical_name(name: &str) -> String {
+    if let Some((_, canonical)) = ALIASES
+        .iter()
+        .find(|(alias, _)| alias.eq_ignore_ascii_case(name))
+    {
+        return canonical.to_string();
+    }
+    (0x01..=0x38)
+        .map(kmip_algorithm_name)
+        .chain(["EdDSA", "XDH", "X25519", "X448"].map(str::to_string))
+        .find(|canonical| canonical.eq_ignore_ascii_case(name))
+        .unwrap_or_else(|| name.to_string())
+}
+
+/// The canonical name of a key with the given algorithm family and size in bits, which for a block cipher includes
+/// the size as there are several, e.g. AES-128 and AES-256.
+pub(crate) fn with_key_size(alg: &str, len: &str) -> String {
+    let block_cipher = ["AES", "ARIA", "Camellia"].contains(&alg);
+    if block_cipher && !len.is_empty() && len.bytes().all(|b| b.is_ascii_digit()) {
+        format!("{}-{}", alg, len)
+    } else {
+        alg.to_string()
+    }
+}
+
+/// A named elliptic curve.
+pub(crate) struct Curve {
+    /// The DER encoded OID, or for some curves name, of the curve as held by the CKA_EC_PARAMS of a PKCS#11 key.
+    oid: &'static [u8],
+    /// The name of the curve, which follows the name of the algorithm of keys using it, e.g. `ECDSA P-256`.
+    pub(crate) name: Option<&'static str>,
+    /// The algorithm that keys using the curve are for, if the curve is specific to an algorithm, e.g. `Ed25519`.
+    pub(crate) alg: Option<&'static str>,
+    /// The size of the curve in bits.
+    pub(crate) bits: u32,
+}
+
+const CURVES: &[Curve] = &[
+    // Ed25519 (1.3.101.112), also identified by the PrintableString "edwards25519"
+    Curve {
+        oid: &[0x06, 0x03, 0x2B, 0x65, 0x70],
+        name: None,
+        alg: Some("Ed25519"),
+        bits: 255,
+    },
+    Curve {
+        oid: b"\x13\x0Cedwards25519",
+        name: None,
+        alg: Some("Ed25519"),
+        bits: 255,
+    },
+    // Ed448 (1.3.101.113), also identified by the PrintableString "edwards448"
+    Curve {
+        oid: &[0x06, 0x03, 0x2B, 0x65, 0x71],
+        name: None,
+        alg: Some("Ed448"),
+        bits: 448,
+    },
+    Curve {
+        oid: b"\x13\x0Aedwards448",
+        name: None,
+        alg: Some("Ed448"),
+        bits: 448,
+    },
+    // X25519 (1.3.101.110), also identified by the PrintableString "curve25519"
+    Curve {
+        oid: &[0x06, 0x03, 0x2B, 0x65, 0x6E],
+        name: None,
+        alg: Some("X25519"),
+        bits: 255,
+    },
+    Curve {
+        oid: b"\x13\x0Acurve25519",
+        name: None,
+        alg: Some("X25519"),
+        bits: 255,
+    },
+    // X448 (1.3.101.111), also identified by the PrintableString "curve448"
+    Curve {
+        oid: &[0x06, 0x03, 0x2B, 0x65, 0x6F],
+        name: None,
+        alg: Some("X448"),
+        bits: 448,
+    },
+    Curve {
+        oid: b"\x13\x08curve448",
+        name: None,
+        alg: Some("X448"),
+        bits: 448,
+    },
+    // secp256r1 aka P-256 (1.2.840.10045.3.1.7)
+    Curve {
+        oid: &[0x06, 0x08, 0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x03, 0x01, 0x07],
+        name: Some("P-256"),
+        alg: None,
+        bits: 256,
+    },
+    // secp384r1 aka P-384 (1.3.132.0.34)
+    Curve {
+        oid: &[0x06, 0x05, 0x2B, 0x81, 0x04, 0x00, 0x22],
+        name: Some("P-384"),
+        alg: None,
+        bits: 384,
+    },
+    // secp521r1 aka P-521 (1.3.132.0.35)
+    Curve {
+        oid: &[0x06, 0x05, 0x2B, 0x81, 0x04, 0x00, 0x23],
+        name: Some("P-521"),
+        alg: None,
+        bits: 521,
+    },
+    // secp256k1 (1.3.132.0.10)
+    Curve {
+        oid: &[0x06, 0x05, 0x2B, 0x81, 0x04, 0x00, 0x0A],
+        name: Some("secp256k1"),
+        alg: None,
+        bits: 256,
+    },
+    // SM2 (1.2.156.10197.1.301)
+    Curve {
+        oid: &[0x06, 0x08, 0x2A, 0x81, 0x1C, 0xCF, 0x55, 0x01, 0x82, 0x2D],
+        name: None,
+        alg: Some("SM2"),
+        bits: 256,
+    },
+    // GOST R 34.10-2001 CryptoPro parameter sets A, B and C (1.2.643.2.2.35.1-3)
+    Curve {
+        oid: &[0x06, 0x07, 0x2A, 0x85, 0x03, 0x02, 0x02, 0x23, 0x01],
+        name: None,
+        alg: Some("GOST R 34.10"),
+        bits: 256,
+    },
+    Curve {
+        oid: &[0x06, 0x07, 0x2A, 0x85, 0x03, 0x02, 0x02, 0x23, 0x02],
+        name: None,
+        alg: Some("GOST R 34.10"),
+        bits: 256,
+    },
+    Curve {
+        oid: &[0x06, 0x07, 0x2A, 0x85, 0x03, 0x02, 0x02, 0x23, 0x03],
+        name: None,
+        alg: Some("GOST R 34.10"),
+        bits: 256,
+    },
+    // GOST R 34.10-2012 256 bit parameter set A (1.2.643.7.1.2.1.1.1)
+    Curve {
+        oid: &[
+            0x06, 0x09, 0x2A, 0x85, 0x03, 0x07, 0x01, 0x02, 0x01, 0x01, 0x01,
+        ],
+        name: None,
+        alg: Some("GOST R 34.10"),
+        bits: 256,
+    },
+    // GOST R 34.10-2012 512 bit parameter sets A, B and C (1.2.643.7.1.2.1.2.1-3)
+    Curve {
+        oid: &[
+            0x06, 0x09, 0x2A, 0x85, 0x03, 0x07, 0x01, 0x02, 0x01, 0x02, 0x01,
+        ],
+        name: None,
+        alg: Some("GOST R 34.10"),
+        bits: 512,
+    },
+    Curve {
+        oid: &[
+            0x06, 0x09, 0x2A, 0x85, 0x03, 0x07, 0x01, 0x02, 0x01, 0x02, 0x02,
+        ],
+        name: None,
+        alg: Some("GOST R 34.10"),
+        bits: 512,
+    },
+    Curve {
+        oid: &[
+            0x06, 0x09, 0x2A, 0x85, 0x03, 0x07, 0x01, 0x02, 0x01, 0x02, 0x03,
+        ],
+        name: None,
+        alg: Some("GOST R 34.10"),
+        bits: 512,
+    },
+];
+
+/// The named curve identified by the CKA_EC_PARAMS of a PKCS#11 key, if it is one that keyls knows.
+pub(crate) fn curve(ec_params: &[u8]) -> Option<&'static Curve> {
+    CURVES.iter().find(|curve| curve.oid == ec_params)
+}
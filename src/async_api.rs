@@ -0,0 +1,48 @@
+//! An async variant of the key listing API for use with the Tokio runtime.
+//!
+//! The kmip-protocol crate cannot be built with both its sync and async transports, and the cryptoki crate offers no
+//! async API at all, so the blocking implementation is run on the Tokio blocking thread pool. This keeps the async
+//! runtime worker threads free while keys are enumerated on many key stores concurrently.
+use std::ops::ControlFlow;
+
+use anyhow::{anyhow, Result};
+use tokio::sync::mpsc;
+
+use crate::{config::Opt, key::Key};
+
+/// Connect to the key store identified by the server option and retrieve all of the keys that it holds.
+pub async fn list_keys(opt: Opt) -> Result<Vec<Key>> {
+    tokio::task::spawn_blocking(move || crate::connect(&opt)?.list_keys())
+        .await
+        .map_err(|err| anyhow!("Key listing task failed: {}", err))?
+}
+
+/// Connect to the key store identified by the server option and receive each key that it holds as soon as it has been
+/// retrieved.
+///
+/// The channel is closed once all keys have been retrieved. An error is sent for each key that could not be retrieved,
+/// once all other keys have been, and a failure to connect or to enumerate the keys is sent as the final item.
+/// Dropping the receiver stops the retrieval of further keys.
+pub fn stream_keys(opt: Opt, buffer: usize) -> mpsc::Receiver<Result<Key>> {
+    let (tx, rx) = mpsc::channel(buffer.max(1));
+    tokio::task::spawn_blocking(move || {
+        let res = crate::connect(&opt).and_then(|mut store| {
+            store.get_keys(&|key| match tx.blocking_send(Ok(key)) {
+                Ok(()) => ControlFlow::Continue(()),
+                // The receiver has been dropped so there is no point retrieving more keys
+                Err(_) => ControlFlow::Break(()),
+            })
+        });
+        match res {
+            Ok(failures) => {
+                for failure in failures {
+                    let _ = tx.blocking_send(Err(failure.into()));
+                }
+            }
+            Err(err) => {
+                let _ = tx.blocking_send(Err(err));
+            }
+        }
+    });
+    rx
+}
@@ -0,0 +1,143 @@
+//! Decoding of the DER encoded X.509 certificate attributes of token objects into human readable form.
+use anyhow::{bail, Result};
+use openssl::{
+    asn1::{Asn1Time, Asn1TimeRef},
+    nid::Nid,
+    pkey::Id,
+    x509::{X509Name, X509NameRef, X509},
+};
+
+use crate::util::format_unix_time;
+
+/// The details of a certificate that are only available by parsing the certificate itself.
+pub(crate) struct ParsedCert {
+    pub(crate) alg: String,
+    pub(crate) len: String,
+    pub(crate) not_before: String,
+    pub(crate) not_after: String,
+}
+
+/// The DER encoded names and serial number of a certificate, as held by the attributes of a PKCS#11 certificate.
+pub(crate) struct CertNames {
+    pub(crate) subject: Vec<u8>,
+    pub(crate) issuer: Vec<u8>,
+    pub(crate) serial: Vec<u8>,
+}
+
+/// Parse a DER encoded certificate for its DER encoded subject, issuer and serial number.
+pub(crate) fn cert_names(der: &[u8]) -> Result<CertNames> {
+    let cert = X509::from_der(der)?;
+    Ok(CertNames {
+        subject: cert.subject_name().to_der()?,
+        issuer: cert.issuer_name().to_der()?,
+        serial: encode_serial(&cert.serial_number().to_bn()?.to_vec())?,
+    })
+}
+
+/// Decode a DER encoded distinguished name, e.g. `C=NL, O=Example, CN=www.example.com`, with the attributes in the
+/// order that they appear in the certificate.
+pub(crate) fn decode_name(der: &[u8]) -> Result<String> {
+    let name = X509Name::from_der(der)?;
+    Ok(format_name(&name))
+}
+
+/// Decode a DER encoded INTEGER serial number to hex.
+pub(crate) fn decode_serial(der: &[u8]) -> Result<String> {
+    let content = match der {
+        [0x02, len, content @ ..] if *len < 0x80 => {
+            Some(content).filter(|c| c.len() == *len as usize)
+        }
+        [0x02, 0x81, len, content @ ..] => Some(content).filter(|c| c.len() == *len as usize),
+        [0x02, 0x82, hi, lo, content @ ..] => {
+            Some(content).filter(|c| c.len() == u16::from_be_bytes([*hi, *lo]) as usize)
+        }
+        _ => None,
+    };
+    // Leave out the leading zero byte that keeps a serial number with its high bit set positive
+    match content {
+        Some([0, rest @ ..]) if !rest.is_empty() => Ok(hex::encode_upper(rest)),
+        Some(content) if !content.is_empty() => Ok(hex::encode_upper(content)),
+        _ => bail!("Invalid DER encoded serial number"),
+    }
+}
+
+/// DER encode a big-endian serial number as an INTEGER, the reverse of [decode_serial].
+fn encode_serial(serial: &[u8]) -> Result<Vec<u8>> {
+    // Add a leading zero byte to keep a serial number with its high bit set positive
+    let content = match serial {
+        [first, ..] if *first < 0x80 => serial.to_vec(),
+        _ => [&[0], serial].concat(),
+    };
+    let len = match content.len() {
+        len if len < 0x80 => vec![len as u8],
+        len if len <= 0xFF => vec![0x81, len as u8],
+        len => bail!("Unsupported serial number of {} bytes", len),
+    };
+    Ok([&[0x02], &len[..], &content].concat())
+}
+
+/// Parse a DER encoded certificate for the algorithm and size of its public key and its validity period.
+pub(crate) fn parse_cert(der: &[u8]) -> Result<ParsedCert> {
+    let cert = X509::from_der(der)?;
+    let key = cert.public_key()?;
+    let alg = match key.id() {
+        Id::RSA => "RSA".to_string(),
+        Id::DSA => "DSA".to_string(),
+        Id::DH => "DH".to_string(),
+        Id::EC => {
+            let curve = match key.ec_key()?.group().curve_name() {
+                Some(Nid::X9_62_PRIME256V1) => Some("P-256"),
+                Some(Nid::SECP384R1) => Some("P-384"),
+                Some(Nid::SECP521R1) => Some("P-521"),
+                Some(Nid::SECP256K1) => Some("secp256k1"),
+                _ => None,
+            };
+            match curve {
+                Some(curve) => format!("ECDSA {}", curve),
+                None => "ECDSA".to_string(),
+            }
+        }
+        Id::ED25519 => "Ed25519".to_string(),
+        Id::ED448 => "Ed448".to_string(),
+        Id::X25519 => "X25519".to_string(),
+        Id::X448 => "X448".to_string(),
+        _ => "unknown".to_string(),
+    };
+
+    Ok(ParsedCert {
+        alg,
+        len: key.bits().to_string(),
+        not_before: format_time(cert.not_before())?,
+        not_after: format_time(cert.not_after())?,
+    })
+}
+
+fn format_name(name: &X509NameRef) -> String {
+    name.entries()
+        .map(|entry| {
+            let field = entry
+                .object()
+                .nid()
+                .short_name()
+                .map(str::to_string)
+                .unwrap_or_else(|_| entry.object().to_string());
+            let value = entry
+                .data()
+                .as_utf8()
+                .map(|value| value.to_string())
+                .unwrap_or_else(|_| hex::encode_upper(entry.data().as_slice()));
+            format!("{}={}", field, value)
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Format an ASN.1 time in RFC 3339 format, e.g. `2024-01-31T12:00:00Z`.
+fn format_time(time: &Asn1TimeRef) -> Result<String> {
+    let diff = Asn1Time::from_unix(0)?.diff(time)?;
+    let secs = i64::from(diff.days) * 86400 + i64::from(diff.secs);
+    if secs < 0 {
+        bail!("Unsupported certificate time before 1970: {}", time);
+    }
+    Ok(format_unix_time(secs as u64))
+}
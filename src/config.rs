@@ -1,5 +1,7 @@
+use std::net::{IpAddr, Ipv6Addr};
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::Duration;
 
 use anyhow::bail;
 use anyhow::Result;
@@ -10,32 +12,430 @@ use clap::StructOpt;
 #[clap(about = "A cryptographic token key lister")]
 #[rustfmt::skip]
 pub struct Opt {
-    #[structopt(parse(try_from_str = parse_server), help = "Server location (e.g. kmip:[user[:pass]@]ip_or_fqdn[:port] or pkcs11:slot_id_or_label[:user_pin]@path/to/lib.so)")]
+    #[structopt(parse(try_from_str = parse_server), help = "Server location (e.g. kmip:[user[:pass]@]ip_or_fqdn[:port] or pkcs11:slot_id_or_label[:user_pin]@path/to/lib.so (or lib.dll on Windows) where the slot can also be serial=token_serial_number, leaving the slot empty with --all-slots, or plugin:name[:settings] to use the keyls-plugin-name program)")]
     pub server: ServerOpt,
 
+    #[structopt(long = "format", default_value = "text", parse(try_from_str = parse_format), help = "Output format, text or json (which also reports failures as JSON on stderr)")]
+    pub format: OutputFormat,
+
+    #[structopt(long = "schema", exclusive = true, require_equals = true, value_name = "COMMAND", possible_values = &["list"], help = "Print the JSON Schema of the --format json output of the given command (list, the default) and exit")]
+    pub schema: Option<Option<String>>,
+
+    #[structopt(long = "sort", help = "Print keys sorted by ID once all have been retrieved, instead of as they are retrieved")]
+    pub sort: bool,
+
+    #[structopt(long = "tree", help = "Print keys once all have been retrieved as a tree of the components of their names (e.g. app1/signing/2024), or with --format json as nested objects")]
+    pub tree: bool,
+
+    #[structopt(long = "separator", default_value = "/", requires = "tree", parse(try_from_str = parse_separator), help = "Separator between the components of key names shown as a tree with --tree")]
+    pub separator: String,
+
+    #[structopt(long = "columns", parse(try_from_str = parse_column), use_value_delimiter = true, help = "Comma separated columns to output, only retrieving the key attributes that they need (token, id, type, name, alg, len, group, app-info, always-auth, expires, subject, issuer, serial, not-before, not-after, split-key) [default: all but those only output with --long]")]
+    pub columns: Vec<KeyColumn>,
+
+    #[structopt(long = "column-width", parse(try_from_str = parse_column_width), use_value_delimiter = true, value_name = "COLUMN=WIDTH", help = "Comma separated maximum widths in characters of text output columns, beyond which values are truncated or wrapped (e.g. id=64,name=20) [default: 40]")]
+    pub column_widths: Vec<ColumnWidth>,
+
+    #[structopt(long = "wrap", conflicts_with = "full", help = "Wrap values that are too long for their column onto further lines instead of truncating them")]
+    pub wrap: bool,
+
+    #[structopt(long = "full", help = "Output values in full however long they are, instead of truncating them to the column width")]
+    pub full: bool,
+
+    #[structopt(short = 'l', long = "long", help = "Also output the KMIP Object Groups and Application Specific Information of each key, whether PKCS#11 private keys require the PIN to be entered for every use, when keys expire, the subject, issuer, serial number and validity of certificates and how split keys were split, which JSON output always includes")]
+    pub long: bool,
+
+    #[structopt(long = "group", help = "Only list KMIP keys in an Object Group matching this pattern, in which * matches any characters and ? any one character")]
+    pub group: Option<String>,
+
+    #[structopt(long = "app-info", parse(try_from_str = parse_app_info_filter), help = "Only list KMIP keys with Application Specific Information matching NAMESPACE[=DATA], both patterns in which * matches any characters and ? any one character")]
+    pub app_info: Option<AppInfoFilter>,
+
+    #[structopt(long = "alg", help = "Only list keys whose algorithm matches this pattern ignoring case (e.g. RSA, ECDSA, ECDSA P-256, Ed25519 or AES-256), in which * matches any characters and ? any one character, and an algorithm family also matches its curves and key sizes")]
+    pub alg: Option<String>,
+
+    #[structopt(long = "attr", multiple_occurrences(true), value_name = "NAME", help = "Also output the named attribute of each key as an extra column, a PKCS#11 attribute type such as CKA_MODULUS_BITS or a KMIP attribute name such as x-Owner (may be repeated)")]
+    pub attrs: Vec<String>,
+
+    #[structopt(long = "include", parse(try_from_str = parse_include), use_value_delimiter = true, help = "Comma separated kinds of object to list as well as keys and secret data: certs (PKCS#11 certificates) or all (also KMIP Opaque Objects and Split Keys)")]
+    pub include: Vec<Include>,
+
+    #[structopt(long = "limit", help = "Stop after retrieving this many keys")]
+    pub limit: Option<usize>,
+
+    #[structopt(long = "fail-if-empty", help = "Exit with a non-zero exit code if no keys were found")]
+    pub fail_if_empty: bool,
+
+    #[structopt(long = "warn-expiring", value_name = "DAYS", help = "Output when each key expires (its KMIP Deactivation Date, PKCS#11 CKA_END_DATE or certificate notAfter), marking and warning about those that have expired or expire within this many days")]
+    pub warn_expiring: Option<u32>,
+
+    #[structopt(long = "fail-if-expiring", requires = "warn-expiring", help = "Exit with a non-zero exit code if any key has expired or expires within the --warn-expiring window")]
+    pub fail_if_expiring: bool,
+
+    #[structopt(long = "all-slots", help = "List the keys in every PKCS#11 slot with an initialized token instead of a single slot")]
+    pub all_slots: bool,
+
+    #[structopt(long = "token-manufacturer", help = "Only use a PKCS#11 token whose manufacturer matches this pattern, in which * matches any characters and ? any one character (e.g. to tell apart tokens with the same label)")]
+    pub token_manufacturer: Option<String>,
+
+    #[structopt(long = "token-model", help = "Only use a PKCS#11 token whose model matches this pattern, in which * matches any characters and ? any one character")]
+    pub token_model: Option<String>,
+
+    #[structopt(short = 'v', long = "verbose", parse(from_occurrences), help = "Log progress to stderr, repeat for more detail (-v connection setup, -vv each request and its timing, -vvv everything)")]
+    pub verbose: u8,
+
+    #[structopt(long = "log-file", parse(from_os_str), conflicts_with = "log-syslog", help = "Append log output to this file instead of writing it to stderr")]
+    pub log_file: Option<PathBuf>,
+
+    #[structopt(long = "log-syslog", help = "Send log output to the local syslog daemon or journald instead of writing it to stderr")]
+    pub log_syslog: bool,
+
+    #[structopt(long = "timings", help = "Report to stderr how long connection setup, object enumeration and attribute retrieval took, with per key percentiles")]
+    pub timings: bool,
+
+    #[structopt(long = "trace-kmip", require_equals = true, value_name = "FILE", help = "Dump every KMIP message sent and received, in hex and decoded, to stderr, or appended to FILE if given, with any username and password redacted")]
+    pub trace_kmip: Option<Option<PathBuf>>,
+
+    #[structopt(long = "trace-pkcs11", require_equals = true, value_name = "FILE", help = "Log every Cryptoki call made to the PKCS#11 library with its arguments, return code and result to stderr, or appended to FILE if given")]
+    pub trace_pkcs11: Option<Option<PathBuf>>,
+
+    #[structopt(long = "retries", default_value = "0", help = "Number of times to retry connecting and retrieving keys on transient failures, with exponential backoff")]
+    pub retries: u32,
+
+    #[structopt(long = "jobs", default_value = "1", help = "Number of KMIP server connections, or PKCS#11 slots with --all-slots, to use in parallel to retrieve key details")]
+    pub jobs: u16,
+
+    #[structopt(long = "batch-size", default_value = "50", help = "Maximum number of keys to retrieve per KMIP request message, or 1 to disable batching")]
+    pub batch_size: u16,
+
+    #[structopt(long = "kmip-version", parse(try_from_str = parse_kmip_version), help = "KMIP protocol version to use (1.0, 1.1, 1.2, 1.3, 1.4, 2.0 or 2.1) instead of the highest version supported by both keyls and the server, for servers that misbehave when offered newer versions")]
+    pub kmip_version: Option<KmipVersion>,
+
+    #[structopt(long = "locate-page-size", parse(try_from_str = parse_locate_page_size), help = "Locate KMIP keys this many at a time, for servers that cap the number of keys returned (requires KMIP 1.3)")]
+    pub locate_page_size: Option<u16>,
+
+    #[structopt(long = "max-rps", help = "Maximum number of requests per second to send to the KMIP server or PKCS#11 library, across all jobs (default: no limit)")]
+    pub max_rps: Option<u32>,
+
     #[structopt(long = "insecure", help = "Disable secure checks (e.g. verification of the server certificate)")]
     pub insecure: bool,
 
-    #[structopt(long = "client-cert", parse(from_os_str), help = "Path to the client certificate file in PEM format")]
+    #[structopt(long = "client-cert", parse(from_os_str), help = "Path to the client certificate file in PEM or DER format")]
     pub client_cert_path: Option<PathBuf>,
 
-    #[structopt(long = "client-key", parse(from_os_str), help = "Path to the client certificate key file in PEM format")]
+    #[structopt(long = "client-key", parse(from_os_str), help = "Path to the client certificate key file in PEM or DER format")]
     pub client_key_path: Option<PathBuf>,
 
     #[structopt(long = "client-cert-and-key", parse(from_os_str), help = "Path to the client certificate and key file in PKCS#12 format")]
     pub client_pkcs12_path: Option<PathBuf>,
 
-    #[structopt(long = "server-cert", parse(from_os_str), help = "Path to the server certificate file in PEM format")]
+    #[structopt(long = "server-cert", parse(from_os_str), help = "Path to the server certificate file in PEM or DER format")]
     pub server_cert_path: Option<PathBuf>,
 
-    #[structopt(long = "server-ca-cert", parse(from_os_str), help = "Path to the server CA certificate file in PEM format")]
+    #[structopt(long = "server-ca-cert", parse(from_os_str), help = "Path to the server CA certificate file in PEM or DER format, which if PEM can be a bundle of several CA certificates")]
     pub ca_cert_path: Option<PathBuf>,
+
+    #[structopt(long = "connect-timeout", parse(try_from_str = humantime::parse_duration), default_value = "5s", help = "Maximum time to wait for the KMIP server TCP connection to be established (e.g. 500ms, 10s, 1m, or 0 to wait forever)")]
+    pub connect_timeout: Duration,
+
+    #[structopt(long = "read-timeout", parse(try_from_str = humantime::parse_duration), default_value = "5s", help = "Maximum time to wait for data from the KMIP server (e.g. 500ms, 10s, 1m, or 0 to wait forever)")]
+    pub read_timeout: Duration,
+
+    #[structopt(long = "write-timeout", parse(try_from_str = humantime::parse_duration), default_value = "5s", help = "Maximum time to wait when sending data to the KMIP server (e.g. 500ms, 10s, 1m, or 0 to wait forever)")]
+    pub write_timeout: Duration,
+
+    #[structopt(long = "max-response-bytes", help = "Maximum size in bytes of a single KMIP server response to accept (default: no limit)")]
+    pub max_response_bytes: Option<u32>,
+
+    #[structopt(long = "proxy", parse(try_from_str = parse_proxy), help = "Proxy to connect to the KMIP server through (e.g. socks5://[user[:pass]@]host[:port], socks5h://... or http://host[:port], with an IPv6 host in brackets), defaults to the HTTPS_PROXY environment variable")]
+    pub proxy: Option<ProxyOpt>,
+
+    #[structopt(long = "resolve", parse(try_from_str = parse_resolve), multiple_occurrences(true), help = "Connect to the given address instead of resolving the KMIP server host and port, as host:port:addr (may be repeated)")]
+    pub resolve: Vec<ResolveOpt>,
+
+    #[structopt(long = "sni", conflicts_with = "insecure", help = "Server name to present to the KMIP server via TLS SNI instead of the server address")]
+    pub sni: Option<String>,
+
+    #[structopt(long = "verify-hostname", conflicts_with = "insecure", help = "Name to verify the KMIP server certificate against instead of the server address (defaults to the --sni name if given)")]
+    pub verify_hostname: Option<String>,
+
+    #[structopt(long = "crl", parse(from_os_str), multiple_occurrences(true), conflicts_with = "insecure", help = "Path to a certificate revocation list file in PEM format to check the KMIP server certificate chain against (may be repeated)")]
+    pub crl_paths: Vec<PathBuf>,
+
+    #[structopt(long = "ocsp", conflicts_with = "insecure", help = "Require the KMIP server to staple a valid OCSP response showing that its certificate is not revoked")]
+    pub ocsp: bool,
+
+    #[structopt(long = "tls-min-version", parse(try_from_str = parse_tls_version), help = "Minimum TLS version to negotiate with the KMIP server (1.0, 1.1, 1.2 or 1.3)")]
+    pub tls_min_version: Option<TlsVersion>,
+
+    #[structopt(long = "tls-max-version", parse(try_from_str = parse_tls_version), help = "Maximum TLS version to negotiate with the KMIP server (1.0, 1.1, 1.2 or 1.3)")]
+    pub tls_max_version: Option<TlsVersion>,
+
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// An operation to perform on a key instead of listing keys.
+#[derive(clap::Subcommand, Debug)]
+#[rustfmt::skip]
+pub enum Command {
+    #[clap(name = "gen-csr", about = "Generate a PKCS#10 certificate signing request for a key pair, signed by its private key on the token (RSA and ECDSA keys, only RSA keys for KMIP)")]
+    GenCsr {
+        #[clap(help = "ID of the key pair, as listed by keyls (for KMIP, the ID of the private key which must be linked to its public key), or - to read one ID per line from stdin and write a request for each")]
+        id: String,
+
+        #[clap(long = "subject", help = "Subject distinguished name of the request, e.g. \"CN=www.example.com, O=Example\" or /CN=www.example.com/O=Example, escaping special characters in values with a backslash as in RFC 4514")]
+        subject: String,
+
+        #[clap(long = "out", parse(from_os_str), help = "Path to write the PEM encoded request (or requests) to instead of stdout")]
+        out: Option<PathBuf>,
+    },
+
+    #[clap(name = "import-cert", about = "Store an X.509 certificate with a key pair on the token, with the same CKA_ID for PKCS#11 or registered and linked to the public key for KMIP, and print the ID of the certificate")]
+    ImportCert {
+        #[clap(parse(try_from_str = parse_cert_key_id), help = "ID of the key pair, as listed by keyls (for KMIP, the ID of the public key or of the private key which must be linked to its public key)")]
+        id: String,
+
+        #[clap(parse(from_os_str), help = "Path to the certificate file in PEM or DER format, which must be for the public key of the key pair")]
+        cert: PathBuf,
+    },
+
+    #[clap(name = "export-wrapped", about = "Export a private key wrapped (i.e. encrypted) by another key on the token, e.g. for backup, writing the wrapped key and a JSON file of the metadata needed to later unwrap it (the wrapping key must be an AES key)")]
+    ExportWrapped {
+        #[clap(help = "ID of the key to export, as listed by keyls, or - to read one ID per line from stdin")]
+        id: String,
+
+        #[clap(long = "wrapping-key", value_name = "ID", help = "ID of the key to wrap the exported key with")]
+        wrapping_key: String,
+
+        #[clap(long = "out", parse(from_os_str), help = "Path to write the wrapped key to, which must not exist, with the metadata written to the same path with .json appended (with the ID -, the directory to write each key to, in a file named after its ID)")]
+        out: PathBuf,
+    },
+
+    #[clap(name = "random", about = "Generate random data with the random number generator of the token (PKCS#11 C_GenerateRandom or KMIP RNG Retrieve), e.g. to check it when commissioning an HSM, writing it to stdout in hex")]
+    Random {
+        #[clap(long = "bytes", value_name = "N", parse(try_from_str = parse_random_bytes), help = "Number of random bytes to generate")]
+        bytes: u32,
+
+        #[clap(long = "out", parse(from_os_str), help = "Path to write the random data to as raw bytes instead of writing it to stdout in hex")]
+        out: Option<PathBuf>,
+
+        #[clap(long = "stats", help = "Report to stderr sanity statistics of the random data (entropy, monobit, runs and byte frequency), failing if it fails any of the tests")]
+        stats: bool,
+    },
 }
 
 #[derive(Debug)]
 pub enum ServerOpt {
     Kmip(KmipServerOpt),
     Pkcs11(Pkcs11ServerOpt),
+    Plugin(PluginServerOpt),
+}
+
+impl Opt {
+    /// Should the given column be output, and thus the key attributes that it shows be retrieved?
+    pub fn wants_column(&self, column: KeyColumn) -> bool {
+        if !self.lists_objects_for(column) {
+            false
+        } else if self.columns.is_empty() {
+            !column.is_long()
+                || self.long
+                || self.format == OutputFormat::Json
+                || (column == KeyColumn::Expires && self.warn_expiring.is_some())
+        } else {
+            self.columns.contains(&column)
+        }
+    }
+
+    /// Should the key attributes shown by the given column be retrieved, either to output them or to filter by them?
+    pub fn needs_column(&self, column: KeyColumn) -> bool {
+        self.wants_column(column)
+            || match column {
+                KeyColumn::Algorithm => self.alg.is_some(),
+                KeyColumn::Name => self.tree,
+                // The size of a block cipher key is part of the name of its algorithm, e.g. AES-256
+                KeyColumn::Length => self.needs_column(KeyColumn::Algorithm),
+                KeyColumn::Group => self.group.is_some(),
+                KeyColumn::AppInfo => self.app_info.is_some(),
+                KeyColumn::Expires => self.warn_expiring.is_some(),
+                _ => false,
+            }
+    }
+
+    /// Are the objects that the given column is for being listed, if it is only for a kind of object that is not
+    /// listed by default, which only the PKCS#11 (certificates) or KMIP (the rest) key stores can list?
+    fn lists_objects_for(&self, column: KeyColumn) -> bool {
+        match column.requires() {
+            Some(Include::Certs) => {
+                self.includes(Include::Certs) && matches!(self.server, ServerOpt::Pkcs11(_))
+            }
+            Some(kind) => self.includes(kind) && matches!(self.server, ServerOpt::Kmip(_)),
+            None => true,
+        }
+    }
+
+    /// Should objects of the given kind be listed as well as keys?
+    pub fn includes(&self, kind: Include) -> bool {
+        self.include.contains(&kind) || self.include.contains(&Include::All)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyColumn {
+    Token,
+    Id,
+    Type,
+    Name,
+    Algorithm,
+    Length,
+    Group,
+    AppInfo,
+    AlwaysAuth,
+    Expires,
+    Subject,
+    Issuer,
+    Serial,
+    NotBefore,
+    NotAfter,
+    SplitKey,
+}
+
+impl KeyColumn {
+    /// Is this a column that is only output with --long (or if selected explicitly)?
+    pub fn is_long(&self) -> bool {
+        matches!(
+            self,
+            KeyColumn::Group | KeyColumn::AppInfo | KeyColumn::AlwaysAuth | KeyColumn::Expires
+        ) || self.requires().is_some()
+    }
+
+    /// The kind of object that must be included for this column to have a value, if the column is only for a kind of
+    /// object that is not listed by default.
+    pub fn requires(&self) -> Option<Include> {
+        match self {
+            KeyColumn::Subject
+            | KeyColumn::Issuer
+            | KeyColumn::Serial
+            | KeyColumn::NotBefore
+            | KeyColumn::NotAfter => Some(Include::Certs),
+            KeyColumn::SplitKey => Some(Include::All),
+            _ => None,
+        }
+    }
+}
+
+/// A kind of object to list as well as keys and secret data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Include {
+    Certs,
+    All,
+}
+
+/// The maximum width in characters of a text output column.
+#[derive(Clone, Copy, Debug)]
+pub struct ColumnWidth {
+    pub column: KeyColumn,
+    pub width: usize,
+}
+
+/// Matches KMIP Application Specific Information by application namespace and optionally data.
+#[derive(Clone, Debug)]
+pub struct AppInfoFilter {
+    pub namespace: String,
+    pub data: Option<String>,
+}
+
+/// A KMIP protocol version.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct KmipVersion {
+    pub major: i32,
+    pub minor: i32,
+}
+
+impl KmipVersion {
+    pub const V1_0: KmipVersion = KmipVersion::new(1, 0);
+    pub const V1_1: KmipVersion = KmipVersion::new(1, 1);
+    pub const V1_2: KmipVersion = KmipVersion::new(1, 2);
+    pub const V1_3: KmipVersion = KmipVersion::new(1, 3);
+    pub const V1_4: KmipVersion = KmipVersion::new(1, 4);
+    pub const V2_0: KmipVersion = KmipVersion::new(2, 0);
+    pub const V2_1: KmipVersion = KmipVersion::new(2, 1);
+
+    /// The versions supported by keyls, highest first.
+    pub const SUPPORTED: [KmipVersion; 7] = [
+        KmipVersion::V2_1,
+        KmipVersion::V2_0,
+        KmipVersion::V1_4,
+        KmipVersion::V1_3,
+        KmipVersion::V1_2,
+        KmipVersion::V1_1,
+        KmipVersion::V1_0,
+    ];
+
+    pub const fn new(major: i32, minor: i32) -> Self {
+        Self { major, minor }
+    }
+
+    /// KMIP 2.0 replaced the named Attribute structures of KMIP 1.x with attributes identified by their tag.
+    pub fn has_tagged_attributes(&self) -> bool {
+        self.major >= 2
+    }
+}
+
+impl std::fmt::Display for KmipVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TlsVersion {
+    Tls1_0,
+    Tls1_1,
+    Tls1_2,
+    Tls1_3,
+}
+
+impl std::fmt::Display for TlsVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TlsVersion::Tls1_0 => f.write_str("TLS 1.0"),
+            TlsVersion::Tls1_1 => f.write_str("TLS 1.1"),
+            TlsVersion::Tls1_2 => f.write_str("TLS 1.2"),
+            TlsVersion::Tls1_3 => f.write_str("TLS 1.3"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum ProxyOpt {
+    Socks5 {
+        addr: String,
+        port: u16,
+        user: Option<String>,
+        pass: Option<String>,
+        /// Let the proxy resolve the KMIP server hostname (socks5h://)
+        remote_dns: bool,
+    },
+    Http {
+        addr: String,
+        port: u16,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct ResolveOpt {
+    pub host: String,
+
+    pub port: u16,
+
+    pub addr: IpAddr,
 }
 
 #[derive(StructOpt, Debug)]
@@ -49,6 +449,14 @@ pub struct KmipServerOpt {
     pub pass: Option<String>,
 }
 
+#[derive(Debug)]
+pub struct PluginServerOpt {
+    pub name: String,
+
+    /// Passed as is to the plugin
+    pub settings: String,
+}
+
 #[derive(StructOpt, Debug)]
 pub struct Pkcs11ServerOpt {
     pub lib_path: PathBuf,
@@ -57,6 +465,10 @@ pub struct Pkcs11ServerOpt {
 
     pub slot_label: Option<String>,
 
+    /// Selects the slot by the serial number of its token, which unlike the slot id does not change when a network
+    /// HSM is restarted
+    pub token_serial: Option<String>,
+
     pub user_pin: Option<String>,
 }
 
@@ -68,7 +480,10 @@ fn parse_server(input: &str) -> Result<ServerOpt> {
         Some(("pkcs11", settings)) => {
             Ok(ServerOpt::Pkcs11(parse_pkcs11_server(settings)?))
         }
-        _ => bail!("Expected: kmip:[user[:pass]@]ip_or_fqdn[:port] or pkcs11:slot_id_or_label[:user_pin]@path/to/lib.so")
+        Some(("plugin", settings)) => {
+            Ok(ServerOpt::Plugin(parse_plugin_server(settings)?))
+        }
+        _ => bail!("Expected: kmip:[user[:pass]@]ip_or_fqdn[:port], pkcs11:slot_id_or_label[:user_pin]@path/to/lib.so (or lib.dll) or plugin:name[:settings]")
     }
 }
 
@@ -103,45 +518,416 @@ fn parse_user_pass(input: &str) -> Result<(String, Option<String>)> {
 }
 
 fn parse_addr_port(input: &str) -> Result<(String, u16)> {
-    // input should be of the form: ip_or_fqdn[:port]
-    match input.split_once(':') {
+    parse_addr_port_with_default(input, 5696)
+}
+
+fn parse_addr_port_with_default(input: &str, default_port: u16) -> Result<(String, u16)> {
+    // input should be of the form: ip_or_fqdn[:port] where an IPv6 address is bracketed, e.g. [2001:db8::1]:3128,
+    // unless it is given without a port
+    if let Some(rest) = input.strip_prefix('[') {
+        let (addr, port) = match rest.split_once(']') {
+            Some((addr, port)) if addr.parse::<Ipv6Addr>().is_ok() => (addr, port),
+            _ => bail!("Expected an IPv6 address in brackets, e.g. [2001:db8::1]:3128"),
+        };
+        let port = match port {
+            "" => default_port,
+            _ => match port.strip_prefix(':') {
+                Some(port) => port.parse::<u16>()?,
+                None => bail!("Expected a port after the bracketed IPv6 address, e.g. [2001:db8::1]:3128"),
+            },
+        };
+        return Ok((addr.to_string(), port));
+    }
+    if input.parse::<Ipv6Addr>().is_ok() {
+        return Ok((input.to_string(), default_port));
+    }
+    match input.rsplit_once(':') {
         Some((ip_or_fqdn, port)) => Ok((ip_or_fqdn.to_string(), port.parse::<u16>()?)),
-        None => Ok((input.to_string(), 5696)),
+        None => Ok((input.to_string(), default_port)),
+    }
+}
+
+pub fn parse_proxy(input: &str) -> Result<ProxyOpt> {
+    // input should be of the form: scheme://[user[:pass]@]ip_or_fqdn[:port][/]
+    let (scheme, rest) = match input.split_once("://") {
+        Some((scheme, rest)) => (scheme.to_ascii_lowercase(), rest.trim_end_matches('/')),
+        None => bail!("Expected: socks5://[user[:pass]@]host[:port], socks5h://[user[:pass]@]host[:port] or http://host[:port]"),
+    };
+    let (user, pass, rest) = match rest.rsplit_once('@') {
+        Some((user_pass, rest)) => {
+            let (user, pass) = parse_user_pass(user_pass)?;
+            (Some(user), pass, rest)
+        }
+        None => (None, None, rest),
+    };
+
+    match scheme.as_str() {
+        "socks5" | "socks5h" => {
+            let (addr, port) = parse_addr_port_with_default(rest, 1080)?;
+            Ok(ProxyOpt::Socks5 {
+                addr,
+                port,
+                user,
+                pass,
+                remote_dns: scheme == "socks5h",
+            })
+        }
+        "http" => {
+            if user.is_some() {
+                bail!("HTTP proxy authentication is not supported");
+            }
+            let (addr, port) = parse_addr_port_with_default(rest, 8080)?;
+            Ok(ProxyOpt::Http { addr, port })
+        }
+        _ => bail!(
+            "Unsupported proxy scheme '{}', expected one of: socks5, socks5h or http",
+            scheme
+        ),
+    }
+}
+
+fn parse_resolve(input: &str) -> Result<ResolveOpt> {
+    // input should be of the form: host:port:addr where addr is an IPv4 or optionally bracketed IPv6 address
+    match input.splitn(3, ':').collect::<Vec<_>>()[..] {
+        [host, port, addr] if !host.is_empty() => {
+            let port = port.parse::<u16>()?;
+            let addr = addr
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .parse::<IpAddr>()?;
+            Ok(ResolveOpt {
+                host: host.to_string(),
+                port,
+                addr,
+            })
+        }
+        _ => bail!("Expected: host:port:addr"),
     }
 }
 
+fn parse_plugin_server(input: &str) -> Result<PluginServerOpt> {
+    // input should be of the form: name[:settings]
+    let (name, settings) = input.split_once(':').unwrap_or((input, ""));
+    if name.is_empty() {
+        bail!("Missing plugin name");
+    }
+
+    Ok(PluginServerOpt {
+        name: name.to_string(),
+        settings: settings.to_string(),
+    })
+}
+
 fn parse_pkcs11_server(input: &str) -> Result<Pkcs11ServerOpt> {
-    // input should be of the form: slot_id_or_label[:user_pin]@path/to/lib.so
-    let (lib_path, slot_id, slot_label, user_pin) = match input.split_once('@') {
+    // input should be of the form: slot_id_or_label[:user_pin]@path/to/lib.so or on Windows @C:\path\to\lib.dll
+    let (lib_path, slot, user_pin) = match split_lib_path(input) {
         Some((slot_pin, lib_path)) => {
-            let (slot_id, slot_label, user_pin) = match slot_pin.split_once(':') {
+            let (slot, user_pin) = match slot_pin.split_once(':') {
                 Some((slot_id_or_label, user_pin)) => {
-                    let (slot_id, slot_label) = parse_slot_id_or_label(slot_id_or_label)?;
-                    (slot_id, slot_label, Some(user_pin.to_string()))
-                }
-                None => {
-                    let (slot_id, slot_label) = parse_slot_id_or_label(slot_pin)?;
-                    (slot_id, slot_label, None)
+                    (parse_slot_id_or_label(slot_id_or_label)?, Some(user_pin.to_string()))
                 }
+                None => (parse_slot_id_or_label(slot_pin)?, None),
             };
             let lib_path = PathBuf::from_str(lib_path)?;
-            (lib_path, slot_id, slot_label, user_pin)
+            (lib_path, slot, user_pin)
         }
         None => bail!("Missing '@' character in PKCS#11 server specification"),
     };
 
+    let (slot_id, slot_label, token_serial) = slot;
     Ok(Pkcs11ServerOpt {
         lib_path,
         slot_id,
         slot_label,
+        token_serial,
         user_pin,
     })
 }
 
-fn parse_slot_id_or_label(input: &str) -> Result<(Option<u64>, Option<String>)> {
-    // input should be of the form: slot_id_or_label
+/// Split a PKCS#11 server specification at the '@' before the library path. This is the first '@' unless a later one
+/// is followed by an absolute Windows path, i.e. one starting with a drive letter (e.g. C:\) or \\ for a UNC path, so
+/// that the user PIN can contain '@' when the library is given by such a path.
+fn split_lib_path(input: &str) -> Option<(&str, &str)> {
+    let is_windows_absolute = |path: &str| {
+        path.starts_with("\\\\")
+            || matches!(path.as_bytes(), [drive, b':', b'\\' | b'/', ..] if drive.is_ascii_alphabetic())
+    };
+    let idx = input
+        .match_indices('@')
+        .map(|(idx, _)| idx)
+        .find(|idx| is_windows_absolute(&input[idx + 1..]))
+        .or_else(|| input.find('@'))?;
+    Some((&input[..idx], &input[idx + 1..]))
+}
+
+fn parse_slot_id_or_label(input: &str) -> Result<(Option<u64>, Option<String>, Option<String>)> {
+    // input should be of the form: slot_id_or_label or serial=token_serial_number, or empty when using --all-slots
+    if input.is_empty() {
+        return Ok((None, None, None));
+    }
+    if let Some(serial) = input.strip_prefix("serial=") {
+        if serial.is_empty() {
+            bail!("Missing token serial number");
+        }
+        return Ok((None, None, Some(serial.to_string())));
+    }
     match input.parse::<u64>() {
-        Ok(slot_id) => Ok((Some(slot_id), None)),
-        Err(_) => Ok((None, Some(input.to_string()))),
+        Ok(slot_id) => Ok((Some(slot_id), None, None)),
+        Err(_) => Ok((None, Some(input.to_string()), None)),
+    }
+}
+
+fn parse_format(input: &str) -> Result<OutputFormat> {
+    match input {
+        "text" => Ok(OutputFormat::Text),
+        "json" => Ok(OutputFormat::Json),
+        _ => bail!("Expected one of: text or json"),
+    }
+}
+
+fn parse_column(input: &str) -> Result<KeyColumn> {
+    match input.trim().to_ascii_lowercase().as_str() {
+        "token" => Ok(KeyColumn::Token),
+        "id" => Ok(KeyColumn::Id),
+        "type" => Ok(KeyColumn::Type),
+        "name" => Ok(KeyColumn::Name),
+        "alg" | "algorithm" => Ok(KeyColumn::Algorithm),
+        "len" | "length" => Ok(KeyColumn::Length),
+        "group" => Ok(KeyColumn::Group),
+        "app-info" => Ok(KeyColumn::AppInfo),
+        "always-auth" => Ok(KeyColumn::AlwaysAuth),
+        "expires" => Ok(KeyColumn::Expires),
+        "subject" => Ok(KeyColumn::Subject),
+        "issuer" => Ok(KeyColumn::Issuer),
+        "serial" => Ok(KeyColumn::Serial),
+        "not-before" => Ok(KeyColumn::NotBefore),
+        "not-after" => Ok(KeyColumn::NotAfter),
+        "split-key" => Ok(KeyColumn::SplitKey),
+        _ => bail!("Expected one of: token, id, type, name, alg, len, group, app-info, always-auth, expires, subject, issuer, serial, not-before, not-after or split-key"),
+    }
+}
+
+fn parse_column_width(input: &str) -> Result<ColumnWidth> {
+    // input should be of the form: column=width
+    let (column, width) = match input.split_once('=') {
+        Some((column, width)) => (parse_column(column)?, width.trim().parse::<usize>()),
+        None => bail!("Expected COLUMN=WIDTH"),
+    };
+    match width {
+        // Leave room for at least one character of the value as well as the ... marking that it was truncated
+        Ok(width) if width >= 4 => Ok(ColumnWidth { column, width }),
+        _ => bail!("Expected a column width of at least 4"),
+    }
+}
+
+fn parse_separator(input: &str) -> Result<String> {
+    match input {
+        "" => bail!("Expected a separator of at least one character"),
+        _ => Ok(input.to_string()),
+    }
+}
+
+fn parse_locate_page_size(input: &str) -> Result<u16> {
+    match input.trim().parse::<u16>() {
+        Ok(size) if size > 0 => Ok(size),
+        _ => bail!("Expected a page size of at least 1 and at most {}", u16::MAX),
+    }
+}
+
+/// The ID of the key pair to import a certificate for, which unlike the IDs given to other commands cannot be - as
+/// there is only the one certificate to import.
+fn parse_cert_key_id(input: &str) -> Result<String> {
+    if input == "-" {
+        bail!("Expected the ID of a single key pair, IDs cannot be read from stdin to import a certificate");
+    }
+    Ok(input.to_string())
+}
+
+fn parse_random_bytes(input: &str) -> Result<u32> {
+    match input.trim().parse::<u32>() {
+        Ok(bytes) if bytes > 0 => Ok(bytes),
+        _ => bail!("Expected a number of bytes of at least 1"),
+    }
+}
+
+fn parse_include(input: &str) -> Result<Include> {
+    match input.trim().to_ascii_lowercase().as_str() {
+        "certs" => Ok(Include::Certs),
+        "all" => Ok(Include::All),
+        _ => bail!("Expected one of: certs or all"),
+    }
+}
+
+fn parse_app_info_filter(input: &str) -> Result<AppInfoFilter> {
+    // input should be of the form: namespace[=data]
+    let (namespace, data) = match input.split_once('=') {
+        Some((namespace, data)) => (namespace, Some(data.to_string())),
+        None => (input, None),
+    };
+    if namespace.is_empty() {
+        bail!("Expected NAMESPACE[=DATA]");
+    }
+    Ok(AppInfoFilter {
+        namespace: namespace.to_string(),
+        data,
+    })
+}
+
+fn parse_kmip_version(input: &str) -> Result<KmipVersion> {
+    // input should be of the form: major.minor
+    match KmipVersion::SUPPORTED.iter().find(|version| version.to_string() == input) {
+        Some(version) => Ok(*version),
+        None => bail!("Expected one of: 1.0, 1.1, 1.2, 1.3, 1.4, 2.0 or 2.1"),
+    }
+}
+
+fn parse_tls_version(input: &str) -> Result<TlsVersion> {
+    // input should be of the form: 1.x, optionally prefixed with "TLS" or "TLSv"
+    let version = input
+        .trim_start_matches("TLS")
+        .trim_start_matches("tls")
+        .trim_start_matches('v');
+    match version {
+        "1.0" | "1" => Ok(TlsVersion::Tls1_0),
+        "1.1" => Ok(TlsVersion::Tls1_1),
+        "1.2" => Ok(TlsVersion::Tls1_2),
+        "1.3" => Ok(TlsVersion::Tls1_3),
+        _ => bail!("Expected one of: 1.0, 1.1, 1.2 or 1.3"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &str) -> Pkcs11ServerOpt {
+        match parse_server(input) {
+            Ok(ServerOpt::Pkcs11(server_opt)) => server_opt,
+            res => panic!("Expected a PKCS#11 server for {}, got {:?}", input, res),
+        }
+    }
+
+    #[test]
+    fn pkcs11_unix_path() {
+        let server_opt = parse("pkcs11:1:1234@/usr/lib/softhsm/libsofthsm2.so");
+        assert_eq!(server_opt.slot_id, Some(1));
+        assert_eq!(server_opt.user_pin.as_deref(), Some("1234"));
+        assert_eq!(server_opt.lib_path, PathBuf::from("/usr/lib/softhsm/libsofthsm2.so"));
+    }
+
+    #[test]
+    fn pkcs11_unix_path_containing_at() {
+        let server_opt = parse("pkcs11:token:1234@/opt/vendor@2/lib/p11.so");
+        assert_eq!(server_opt.slot_label.as_deref(), Some("token"));
+        assert_eq!(server_opt.user_pin.as_deref(), Some("1234"));
+        assert_eq!(server_opt.lib_path, PathBuf::from("/opt/vendor@2/lib/p11.so"));
+    }
+
+    #[test]
+    fn pkcs11_windows_drive_path() {
+        let server_opt = parse(r"pkcs11:1:pin@C:\proj\p11.dll");
+        assert_eq!(server_opt.slot_id, Some(1));
+        assert_eq!(server_opt.user_pin.as_deref(), Some("pin"));
+        assert_eq!(server_opt.lib_path, PathBuf::from(r"C:\proj\p11.dll"));
+
+        let server_opt = parse("pkcs11:1@c:/Program Files/HSM/p11.dll");
+        assert_eq!(server_opt.slot_id, Some(1));
+        assert_eq!(server_opt.user_pin, None);
+        assert_eq!(server_opt.lib_path, PathBuf::from("c:/Program Files/HSM/p11.dll"));
+    }
+
+    #[test]
+    fn pkcs11_windows_path_with_pin_containing_at() {
+        let server_opt = parse(r"pkcs11:serial=ABC123:p@ss:w@rd@C:\proj\p11.dll");
+        assert_eq!(server_opt.token_serial.as_deref(), Some("ABC123"));
+        assert_eq!(server_opt.user_pin.as_deref(), Some("p@ss:w@rd"));
+        assert_eq!(server_opt.lib_path, PathBuf::from(r"C:\proj\p11.dll"));
+    }
+
+    #[test]
+    fn pkcs11_windows_unc_path() {
+        let server_opt = parse(r"pkcs11::pin@\\server\share\p11.dll");
+        assert_eq!(server_opt.slot_id, None);
+        assert_eq!(server_opt.slot_label, None);
+        assert_eq!(server_opt.user_pin.as_deref(), Some("pin"));
+        assert_eq!(server_opt.lib_path, PathBuf::from(r"\\server\share\p11.dll"));
+    }
+
+    #[test]
+    fn pkcs11_relative_dll() {
+        let server_opt = parse("pkcs11:0@p11.dll");
+        assert_eq!(server_opt.slot_id, Some(0));
+        assert_eq!(server_opt.lib_path, PathBuf::from("p11.dll"));
+    }
+
+    #[test]
+    fn pkcs11_missing_at() {
+        assert!(parse_server(r"pkcs11:1:C:\proj\p11.dll").is_err());
+    }
+
+    #[test]
+    fn locate_page_size() {
+        assert_eq!(parse_locate_page_size("1").unwrap(), 1);
+        assert_eq!(parse_locate_page_size("65535").unwrap(), 65535);
+        assert!(parse_locate_page_size("0").is_err());
+        assert!(parse_locate_page_size("65536").is_err());
+        let opt =
+            |size| Opt::try_parse_from(["keyls", "--locate-page-size", size, "kmip:localhost"]);
+        assert_eq!(opt("10").unwrap().locate_page_size, Some(10));
+        assert!(opt("0").is_err());
+    }
+
+    #[test]
+    fn addr_port() {
+        let parse = |input| parse_addr_port_with_default(input, 1080).unwrap();
+        let expected = |addr: &str, port| (addr.to_string(), port);
+        assert_eq!(parse("proxy.example.com"), expected("proxy.example.com", 1080));
+        assert_eq!(parse("proxy.example.com:3128"), expected("proxy.example.com", 3128));
+        assert_eq!(parse("192.0.2.1:3128"), expected("192.0.2.1", 3128));
+        assert_eq!(parse("[::1]:1080"), expected("::1", 1080));
+        assert_eq!(parse("[2001:db8::1]:3128"), expected("2001:db8::1", 3128));
+        assert_eq!(parse("[2001:db8::1]"), expected("2001:db8::1", 1080));
+        assert_eq!(parse("2001:db8::1"), expected("2001:db8::1", 1080));
+        for input in ["[::1", "[::1]3128", "[proxy.example.com]:3128", "[::1]:x", "proxy:x"] {
+            assert!(parse_addr_port_with_default(input, 1080).is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn proxy_ipv6() {
+        assert!(matches!(
+            parse_proxy("socks5://[::1]:1080").unwrap(),
+            ProxyOpt::Socks5 { addr, port: 1080, .. } if addr == "::1"
+        ));
+        assert!(matches!(
+            parse_proxy("http://[2001:db8::1]:3128/").unwrap(),
+            ProxyOpt::Http { addr, port: 3128 } if addr == "2001:db8::1"
+        ));
+    }
+
+    #[test]
+    fn insecure_conflicts() {
+        let opt = |args: &[&str]| {
+            Opt::try_parse_from([&["keyls"], args, &["kmip:localhost"]].concat())
+        };
+        assert!(opt(&["--crl", "crl.pem"]).is_ok());
+        assert!(opt(&["--ocsp"]).is_ok());
+        assert!(opt(&["--insecure", "--crl", "crl.pem"]).is_err());
+        assert!(opt(&["--insecure", "--ocsp"]).is_err());
+        assert!(opt(&["--sni", "kmip.example.com"]).is_ok());
+        assert!(opt(&["--verify-hostname", "kmip.example.com"]).is_ok());
+        assert!(opt(&["--insecure", "--sni", "kmip.example.com"]).is_err());
+        assert!(opt(&["--insecure", "--verify-hostname", "kmip.example.com"]).is_err());
+    }
+
+    #[test]
+    fn import_cert_single_id() {
+        let opt =
+            |id| Opt::try_parse_from(["keyls", "kmip:localhost", "import-cert", id, "cert.pem"]);
+        assert!(matches!(
+            opt("01").unwrap().command,
+            Some(Command::ImportCert { id, .. }) if id == "01"
+        ));
+        assert!(opt("-").is_err());
     }
 }
@@ -0,0 +1,504 @@
+//! Enrolment of key pairs whose private key is held by the key store: generation of PKCS#10 certificate signing
+//! requests, which the key store signs itself so that the private key never leaves it, and import of the certificates
+//! issued for them.
+use anyhow::{bail, Context, Result};
+use openssl::{
+    bn::BigNum,
+    ecdsa::EcdsaSig,
+    hash::{hash, MessageDigest},
+    pkey::{Id, PKey},
+    rsa::Rsa,
+    x509::{X509NameBuilder, X509Req, X509},
+};
+
+use crate::{
+    error::{ClassifiedError, ErrorClass},
+    source::SignatureAlgorithm,
+    KeyStore,
+};
+
+const TAG_INTEGER: u8 = 0x02;
+const TAG_BIT_STRING: u8 = 0x03;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_SEQUENCE: u8 = 0x30;
+/// The context specific tag of the attributes of a CertificationRequestInfo.
+const TAG_ATTRIBUTES: u8 = 0xA0;
+
+/// The DER encoded OID of id-ecPublicKey (1.2.840.10045.2.1).
+const OID_EC_PUBLIC_KEY: &[u8] = &[0x06, 0x07, 0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x02, 0x01];
+
+/// Generate a PEM encoded certificate signing request for the key pair with the given ID, with the given subject,
+/// e.g. `CN=www.example.com, O=Example` or `/CN=www.example.com/O=Example`.
+///
+/// The public key is read from the key store and the request signed by the private key with SHA-256 for RSA keys, or
+/// for ECDSA keys with the hash that matches the size of the curve.
+pub fn gen_csr(store: &mut KeyStore<'_>, id: &str, subject: &str) -> Result<String> {
+    let subject = parse_subject(subject)?;
+    let spki = store.public_key(id)?;
+    let public_key = PKey::public_key_from_der(&spki).context("Invalid public key")?;
+    let (alg, alg_id) = match public_key.id() {
+        // sha256WithRSAEncryption (1.2.840.113549.1.1.11)
+        Id::RSA => (
+            SignatureAlgorithm::RsaPkcs1Sha256,
+            &[
+                0x30, 0x0D, 0x06, 0x09, 0x2A, 0x86, 0x48, 0x86, 0xF7, 0x0D, 0x01, 0x01, 0x0B, 0x05,
+                0x00,
+            ][..],
+        ),
+        // ecdsa-with-SHA256, -SHA384 and -SHA512 (1.2.840.10045.4.3.2-4)
+        Id::EC if public_key.bits() <= 256 => (
+            SignatureAlgorithm::EcdsaSha256,
+            &[0x30, 0x0A, 0x06, 0x08, 0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x04, 0x03, 0x02][..],
+        ),
+        Id::EC if public_key.bits() <= 384 => (
+            SignatureAlgorithm::EcdsaSha384,
+            &[0x30, 0x0A, 0x06, 0x08, 0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x04, 0x03, 0x03][..],
+        ),
+        Id::EC => (
+            SignatureAlgorithm::EcdsaSha512,
+            &[0x30, 0x0A, 0x06, 0x08, 0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x04, 0x03, 0x04][..],
+        ),
+        _ => bail!("Unsupported key algorithm, only RSA and ECDSA keys can sign a certificate signing request"),
+    };
+
+    // CertificationRequestInfo ::= SEQUENCE { version INTEGER (0), subject Name, subjectPKInfo, attributes [0] }
+    let info = der(
+        TAG_SEQUENCE,
+        &[
+            der(TAG_INTEGER, &[0]),
+            subject,
+            spki,
+            der(TAG_ATTRIBUTES, &[]),
+        ]
+        .concat(),
+    );
+    let signature = store.sign(id, alg, &info)?;
+    let csr = der(
+        TAG_SEQUENCE,
+        &[
+            info,
+            alg_id.to_vec(),
+            der(TAG_BIT_STRING, &[&[0], &signature[..]].concat()),
+        ]
+        .concat(),
+    );
+
+    // Check that the request was signed by the private key of the key pair, in case the ID is shared by keys that do
+    // not belong together
+    let req = X509Req::from_der(&csr)?;
+    if !req.verify(&public_key)? {
+        bail!(
+            "The signature made by the private key with ID '{}' does not match its public key",
+            id
+        );
+    }
+    Ok(String::from_utf8(req.to_pem()?)?)
+}
+
+/// Store a PEM or DER encoded X.509 certificate with the key pair with the given ID, returning the ID of the new
+/// certificate object.
+///
+/// The certificate must be for the public key of the key pair, so that it is not linked to the wrong key.
+pub fn import_cert(store: &mut KeyStore<'_>, id: &str, cert: &[u8]) -> Result<String> {
+    let cert = X509::from_pem(cert)
+        .or_else(|_| X509::from_der(cert))
+        .map_err(|_| {
+            ClassifiedError::new(
+                ErrorClass::Argument,
+                "Invalid certificate, expected an X.509 certificate in PEM or DER format",
+            )
+        })?;
+    let spki = store.public_key(id)?;
+    if cert.public_key()?.public_key_to_der()? != spki {
+        return Err(ClassifiedError::new(
+            ErrorClass::Argument,
+            format!(
+                "The certificate is not for the public key of the key pair with ID '{}'",
+                id
+            ),
+        )
+        .into());
+    }
+    store.import_cert(id, &cert.to_der()?)
+}
+
+/// Parse a subject distinguished name into its DER encoding, with the attributes in the order given.
+///
+/// Separators and other special characters in attribute values can be escaped as described by RFC 4514 section 2.4,
+/// e.g. `O=Example\, Inc.`.
+fn parse_subject(subject: &str) -> Result<Vec<u8>> {
+    let invalid = |msg: String| ClassifiedError::new(ErrorClass::Argument, msg);
+    let parts = match subject.strip_prefix('/') {
+        Some(subject) => split_unescaped(subject, '/'),
+        None => split_unescaped(subject, ','),
+    };
+    let mut name = X509NameBuilder::new()?;
+    // An escaped space may end the value of the attribute, so only the value strips trailing spaces
+    for part in parts
+        .iter()
+        .map(|part| part.trim_start())
+        .filter(|part| !part.is_empty())
+    {
+        let (field, value) = part.split_once('=').ok_or_else(|| {
+            invalid(format!(
+                "Invalid subject attribute '{}', expected e.g. CN=www.example.com",
+                part
+            ))
+        })?;
+        name.append_entry_by_text(field.trim(), &unescape_value(value)?)
+            .map_err(|_| invalid(format!("Unknown subject attribute '{}'", field.trim())))?;
+    }
+    let name = name.build();
+    if name.entries().count() == 0 {
+        return Err(invalid("The subject must not be empty".to_string()).into());
+    }
+    Ok(name.to_der()?)
+}
+
+/// Split a subject at each separator that is not escaped by a backslash, leaving the escapes in place.
+fn split_unescaped(subject: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let (mut start, mut escaped) = (0, false);
+    for (idx, c) in subject.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            c if c == separator => {
+                parts.push(&subject[start..idx]);
+                start = idx + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&subject[start..]);
+    parts
+}
+
+/// Undo the escaping of an attribute value, i.e. a backslash followed by a special character or by two hex digits
+/// giving a byte of its UTF-8 encoding, removing the spaces at either end that are not escaped.
+fn unescape_value(value: &str) -> Result<String> {
+    let invalid = || {
+        ClassifiedError::new(
+            ErrorClass::Argument,
+            format!(
+                "Invalid escape in subject attribute value '{}', expected a backslash to be followed by one of \
+                 ,+\"\\<>;=/# or a space, or by two hex digits",
+                value.trim()
+            ),
+        )
+    };
+    let mut out = Vec::new();
+    // The length of the value without the spaces that follow its last character that is not an unescaped space
+    let mut len = 0;
+    let mut chars = value.trim_start().chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some(hi) if hi.is_ascii_hexdigit() => {
+                    let lo = chars
+                        .next()
+                        .and_then(|lo| lo.to_digit(16))
+                        .ok_or_else(invalid)?;
+                    out.push((hi.to_digit(16).unwrap_or_default() << 4 | lo) as u8);
+                }
+                Some(c) if " ,+\"\\<>;=/#".contains(c) => {
+                    out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes())
+                }
+                _ => return Err(invalid().into()),
+            }
+            len = out.len();
+        } else {
+            out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+            if c != ' ' {
+                len = out.len();
+            }
+        }
+    }
+    out.truncate(len);
+    String::from_utf8(out).map_err(|_| invalid().into())
+}
+
+/// The DER encoded SubjectPublicKeyInfo of an RSA public key with the given big-endian modulus and public exponent.
+pub(crate) fn rsa_public_key(modulus: &[u8], exponent: &[u8]) -> Result<Vec<u8>> {
+    let rsa =
+        Rsa::from_public_components(BigNum::from_slice(modulus)?, BigNum::from_slice(exponent)?)?;
+    Ok(PKey::from_rsa(rsa)?.public_key_to_der()?)
+}
+
+/// The DER encoded SubjectPublicKeyInfo of an EC public key with the given curve, as held by the CKA_EC_PARAMS of a
+/// PKCS#11 key, and point, as held by its CKA_EC_POINT.
+pub(crate) fn ec_public_key(params: &[u8], point: &[u8]) -> Result<Vec<u8>> {
+    // The point should be DER encoded as an OCTET STRING but some tokens hold the bare point
+    let point = match point {
+        [TAG_OCTET_STRING, rest @ ..] => match der_content(rest) {
+            Some(content) => content,
+            None => point,
+        },
+        _ => point,
+    };
+    let alg_id = der(TAG_SEQUENCE, &[OID_EC_PUBLIC_KEY, params].concat());
+    let key = der(TAG_BIT_STRING, &[&[0], point].concat());
+    Ok(der(TAG_SEQUENCE, &[alg_id, key].concat()))
+}
+
+/// The hash of the data that an ECDSA signature with the given algorithm signs.
+pub(crate) fn ecdsa_digest(alg: SignatureAlgorithm, data: &[u8]) -> Result<Vec<u8>> {
+    let digest = match alg {
+        SignatureAlgorithm::EcdsaSha256 => MessageDigest::sha256(),
+        SignatureAlgorithm::EcdsaSha384 => MessageDigest::sha384(),
+        SignatureAlgorithm::EcdsaSha512 => MessageDigest::sha512(),
+        SignatureAlgorithm::RsaPkcs1Sha256 => bail!("Not an ECDSA signature algorithm"),
+    };
+    Ok(hash(digest, data)?.to_vec())
+}
+
+/// DER encode an ECDSA signature made by a PKCS#11 token, which is the r and s values of the signature concatenated.
+pub(crate) fn ecdsa_signature(raw: &[u8]) -> Result<Vec<u8>> {
+    if raw.is_empty() || !raw.len().is_multiple_of(2) {
+        bail!("Invalid ECDSA signature of {} bytes", raw.len());
+    }
+    let (r, s) = raw.split_at(raw.len() / 2);
+    Ok(
+        EcdsaSig::from_private_components(BigNum::from_slice(r)?, BigNum::from_slice(s)?)?
+            .to_der()?,
+    )
+}
+
+/// DER encode a value with the given tag and content.
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = content.len().to_be_bytes();
+    match content.len() {
+        len if len < 0x80 => out.push(len as u8),
+        _ => {
+            let len = &len[len.iter().position(|byte| *byte != 0).unwrap_or(0)..];
+            out.push(0x80 | len.len() as u8);
+            out.extend_from_slice(len);
+        }
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+/// The content of a DER encoded value given without its tag, if its length is that of the rest of the value.
+fn der_content(value: &[u8]) -> Option<&[u8]> {
+    let (len, content) = match value {
+        [len, content @ ..] if *len < 0x80 => (usize::from(*len), content),
+        [0x81, len, content @ ..] => (usize::from(*len), content),
+        [0x82, hi, lo, content @ ..] => (usize::from(u16::from_be_bytes([*hi, *lo])), content),
+        _ => return None,
+    };
+    Some(content).filter(|content| content.len() == len)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::ControlFlow;
+
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid::Nid;
+    use openssl::pkey::Private;
+    use openssl::x509::X509Name;
+
+    use super::*;
+    use crate::{config::Opt, error::KeyError, key::Key, source::*, timings::Timings};
+
+    /// A key store holding one EC key pair, which signs as a PKCS#11 token does with CKM_ECDSA, i.e. a digest of the
+    /// data giving the r and s values of the signature concatenated.
+    struct TokenStore {
+        key: PKey<Private>,
+        timings: Timings,
+    }
+
+    impl<'a> KeySource<'a> for TokenStore {
+        fn connect(_opt: &'a Opt) -> Result<Self> {
+            bail!("Not a real key store")
+        }
+
+        fn capabilities(&self) -> Capabilities {
+            Capabilities::default()
+        }
+
+        fn timings(&self) -> &Timings {
+            &self.timings
+        }
+
+        fn get_keys(
+            &mut self,
+            _on_key: &(dyn Fn(Key) -> ControlFlow<()> + Sync),
+        ) -> Result<Vec<KeyError>> {
+            Ok(vec![])
+        }
+
+        fn get_keys_by_id(&mut self, _id: &str) -> Result<Vec<Key>> {
+            Ok(vec![])
+        }
+
+        fn public_key(&mut self, _id: &str) -> Result<Vec<u8>> {
+            Ok(self.key.public_key_to_der()?)
+        }
+
+        fn sign(&mut self, _id: &str, alg: SignatureAlgorithm, data: &[u8]) -> Result<Vec<u8>> {
+            let digest = ecdsa_digest(alg, data)?;
+            let sig = EcdsaSig::sign(&digest, &*self.key.ec_key()?)?;
+            let len = (self.key.bits() as i32 + 7) / 8;
+            let raw = [sig.r().to_vec_padded(len)?, sig.s().to_vec_padded(len)?].concat();
+            ecdsa_signature(&raw)
+        }
+    }
+
+    fn subject_entries(subject: &str) -> Vec<(Nid, String)> {
+        let der = parse_subject(subject).unwrap();
+        X509Name::from_der(&der)
+            .unwrap()
+            .entries()
+            .map(|entry| {
+                (
+                    entry.object().nid(),
+                    entry.data().as_utf8().unwrap().to_string(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn der_length_boundaries() {
+        for (len, header) in [
+            (0, &[0x04, 0x00][..]),
+            (127, &[0x04, 0x7F]),
+            (128, &[0x04, 0x81, 0x80]),
+            (255, &[0x04, 0x81, 0xFF]),
+            (256, &[0x04, 0x82, 0x01, 0x00]),
+            (65535, &[0x04, 0x82, 0xFF, 0xFF]),
+            (65536, &[0x04, 0x83, 0x01, 0x00, 0x00]),
+        ] {
+            let content = vec![0xAB; len];
+            let encoded = der(TAG_OCTET_STRING, &content);
+            assert_eq!(&encoded[..header.len()], header, "length {}", len);
+            assert_eq!(&encoded[header.len()..], &content[..], "length {}", len);
+            if len <= 0xFFFF {
+                assert_eq!(
+                    der_content(&encoded[1..]),
+                    Some(&content[..]),
+                    "length {}",
+                    len
+                );
+            }
+        }
+        // The length must match that of the rest of the value
+        assert_eq!(der_content(&[0x81, 0x80, 0x00]), None);
+    }
+
+    #[test]
+    fn subject_styles() {
+        let expected = vec![
+            (Nid::COMMONNAME, "www.example.com".to_string()),
+            (Nid::ORGANIZATIONNAME, "Example".to_string()),
+        ];
+        assert_eq!(subject_entries("CN=www.example.com, O=Example"), expected);
+        assert_eq!(
+            subject_entries("CN = www.example.com,O= Example ,"),
+            expected
+        );
+        assert_eq!(subject_entries("/CN=www.example.com/O=Example"), expected);
+        // Commas are not separators of the slash style
+        assert_eq!(
+            subject_entries("/CN=www.example.com/O=Example, Inc."),
+            vec![
+                (Nid::COMMONNAME, "www.example.com".to_string()),
+                (Nid::ORGANIZATIONNAME, "Example, Inc.".to_string()),
+            ]
+        );
+        assert!(parse_subject("").is_err());
+        assert!(parse_subject(" , /").is_err());
+        assert!(parse_subject("CN").is_err());
+        assert!(parse_subject("XX=www.example.com").is_err());
+    }
+
+    #[test]
+    fn subject_escapes() {
+        assert_eq!(
+            subject_entries(r"CN=www.example.com, O=Example\, Inc."),
+            vec![
+                (Nid::COMMONNAME, "www.example.com".to_string()),
+                (Nid::ORGANIZATIONNAME, "Example, Inc.".to_string()),
+            ]
+        );
+        assert_eq!(
+            subject_entries(r"/CN=a\/b\\c\=d"),
+            vec![(Nid::COMMONNAME, r"a/b\c=d".to_string())]
+        );
+        assert_eq!(
+            subject_entries(r"CN=\ padded\ , O=caf\C3\A9"),
+            vec![
+                (Nid::COMMONNAME, " padded ".to_string()),
+                (Nid::ORGANIZATIONNAME, "caf\u{e9}".to_string()),
+            ]
+        );
+        for subject in [r"CN=a\x", r"CN=a\", r"CN=a\4", r"CN=\FF"] {
+            assert!(parse_subject(subject).is_err(), "{}", subject);
+        }
+    }
+
+    #[test]
+    fn ecdsa_signature_to_der() {
+        // Values with the high bit set need a leading zero byte to be positive
+        let raw = [[0x80; 32], [0xFF; 32]].concat();
+        let expected = [
+            &[0x30, 0x46, 0x02, 0x21, 0x00][..],
+            &[0x80; 32],
+            &[0x02, 0x21, 0x00],
+            &[0xFF; 32],
+        ]
+        .concat();
+        assert_eq!(ecdsa_signature(&raw).unwrap(), expected);
+
+        // Leading zero bytes are dropped
+        let mut raw = [0; 64];
+        raw[31] = 0x01;
+        raw[32] = 0x7F;
+        let expected = [
+            &[0x30, 0x25, 0x02, 0x01, 0x01, 0x02, 0x20, 0x7F][..],
+            &[0; 31],
+        ]
+        .concat();
+        assert_eq!(ecdsa_signature(&raw).unwrap(), expected);
+
+        assert!(ecdsa_signature(&[]).is_err());
+        assert!(ecdsa_signature(&[0x80; 63]).is_err());
+    }
+
+    #[test]
+    fn csr_signed_by_token() {
+        for nid in [Nid::X9_62_PRIME256V1, Nid::SECP384R1, Nid::SECP521R1] {
+            let group = EcGroup::from_curve_name(nid).unwrap();
+            let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+            let mut store: KeyStore<'_> = Box::new(TokenStore {
+                key,
+                timings: Timings::default(),
+            });
+            // gen_csr fails if the signature does not verify
+            let pem = gen_csr(&mut store, "01", r"CN=www.example.com, O=Example\, Inc.").unwrap();
+            let req = X509Req::from_pem(pem.as_bytes()).unwrap();
+            let entries = req
+                .subject_name()
+                .entries()
+                .map(|entry| {
+                    (
+                        entry.object().nid(),
+                        entry.data().as_utf8().unwrap().to_string(),
+                    )
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(
+                entries,
+                vec![
+                    (Nid::COMMONNAME, "www.example.com".to_string()),
+                    (Nid::ORGANIZATIONNAME, "Example, Inc.".to_string()),
+                ],
+                "{:?}",
+                nid
+            );
+        }
+    }
+}
@@ -0,0 +1,310 @@
+//! Classification of failures so that they can be reported in a machine-readable form.
+use std::fmt::Display;
+
+use cryptoki::error::RvError;
+use serde::Serialize;
+
+use crate::config::{Opt, ServerOpt};
+
+/// The broad kind of a failure, which determines how a caller might react to it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorClass {
+    /// The command line arguments or settings are invalid.
+    Argument,
+    /// The key store could not be reached, or the connection to it failed.
+    Connection,
+    /// A secure connection could not be established with the KMIP server.
+    Tls,
+    /// The key store rejected the given credentials.
+    Authentication,
+    /// The slot, token or key does not exist.
+    NotFound,
+    /// The key store reported some other failure.
+    Server,
+    /// A failure within keyls itself or an unexpected response from the key store.
+    Internal,
+}
+
+impl ErrorClass {
+    /// The class of the given failure.
+    pub fn of(err: &anyhow::Error) -> Self {
+        classify(err).0
+    }
+}
+
+/// An error with the class that [ErrorReport] should report it as, for failures that cannot otherwise be told apart.
+#[derive(Debug)]
+pub struct ClassifiedError {
+    pub class: ErrorClass,
+    pub message: String,
+}
+
+impl ClassifiedError {
+    pub fn new(class: ErrorClass, message: impl Display) -> Self {
+        Self {
+            class,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for ClassifiedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ClassifiedError {}
+
+/// A key which could not be retrieved, and which was skipped rather than failing the whole listing.
+#[derive(Debug)]
+pub struct KeyError {
+    /// Describes the key, and if known the operation that failed.
+    pub what: String,
+    pub error: anyhow::Error,
+}
+
+impl KeyError {
+    pub fn new(what: impl Display, error: anyhow::Error) -> Self {
+        Self {
+            what: what.to_string(),
+            error,
+        }
+    }
+}
+
+impl std::fmt::Display for KeyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {:#}", self.what, self.error)
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+/// A machine-readable description of a failure.
+#[derive(Debug, Serialize)]
+pub struct ErrorReport {
+    pub class: ErrorClass,
+    /// The kind of key store that failed, i.e. kmip, pkcs11 or plugin.
+    pub backend: &'static str,
+    /// The underlying PKCS#11 return value (e.g. CKR_PIN_INCORRECT) or KMIP result reason, if known.
+    pub code: Option<String>,
+    pub message: String,
+    /// What the code means and what to do about it, if it is a common one.
+    pub explanation: Option<&'static str>,
+    pub hint: Option<&'static str>,
+}
+
+impl ErrorReport {
+    pub fn new(err: &anyhow::Error, opt: &Opt) -> Self {
+        let backend = match opt.server {
+            ServerOpt::Kmip(_) => "kmip",
+            ServerOpt::Pkcs11(_) => "pkcs11",
+            ServerOpt::Plugin(_) => "plugin",
+        };
+        let (class, code) = classify(err);
+        let hint = match class {
+            ErrorClass::Argument => Some("Run keyls --help for usage"),
+            ErrorClass::Connection => match opt.server {
+                ServerOpt::Kmip(_) => Some("Check the server address, port, proxy and timeouts"),
+                ServerOpt::Pkcs11(_) => Some("Check the PKCS#11 library path"),
+                ServerOpt::Plugin(_) => Some("Check that the plugin program is on the PATH"),
+            },
+            ErrorClass::Tls => {
+                Some("Check the server certificate, CA certificate and client certificate settings")
+            }
+            ErrorClass::Authentication => {
+                Some("Check the username and password, client certificate or user PIN")
+            }
+            ErrorClass::NotFound => Some("Check the slot id or label, or the key id"),
+            ErrorClass::Server | ErrorClass::Internal => None,
+        };
+
+        Self {
+            class,
+            backend,
+            explanation: code.as_deref().and_then(explain),
+            code,
+            message: format!("{:#}", err),
+            hint,
+        }
+    }
+}
+
+/// An explanation of the PKCS#11 return value or KMIP result reason underlying the error, prefixed by the code, if it
+/// is a common one.
+pub fn explanation(err: &anyhow::Error) -> Option<String> {
+    let code = classify(err).1?;
+    explain(&code).map(|explanation| format!("{}: {}", code, explanation))
+}
+
+/// Explain what a common PKCS#11 return value (e.g. CKR_PIN_INCORRECT) or KMIP result reason (e.g. PermissionDenied)
+/// means in terms of what to do about it.
+pub fn explain(code: &str) -> Option<&'static str> {
+    let explanation = match code {
+        "CKR_PIN_INCORRECT" => {
+            "the user PIN is wrong, beware that after a number of failed attempts the token may lock the PIN"
+        }
+        "CKR_PIN_INVALID" => "the user PIN contains characters that the token does not accept",
+        "CKR_PIN_LEN_RANGE" => "the user PIN is too long or too short for the token",
+        "CKR_PIN_EXPIRED" => "the user PIN has expired and must be changed before the token can be used",
+        "CKR_PIN_LOCKED" => {
+            "the user PIN is locked after too many failed login attempts, the Security Officer must unlock it"
+        }
+        "CKR_USER_PIN_NOT_INITIALIZED" => {
+            "the token has no user PIN yet, the Security Officer must initialize it"
+        }
+        "CKR_USER_NOT_LOGGED_IN" => {
+            "the token requires a login, give the user PIN in the server location"
+        }
+        "CKR_SLOT_ID_INVALID" => "there is no slot with this ID, check the slot id or label",
+        "CKR_TOKEN_NOT_PRESENT" => {
+            "there is no token in the slot, check that it is inserted or that the HSM partition is assigned to it"
+        }
+        "CKR_TOKEN_NOT_RECOGNIZED" => "the PKCS#11 library does not recognise the token in the slot",
+        "CKR_DEVICE_REMOVED" => {
+            "the token was removed or the connection to the network HSM was lost during the operation"
+        }
+        "CKR_DEVICE_ERROR" => "the token reported a hardware or connection problem, check its logs",
+        "CKR_DEVICE_MEMORY" => "the token ran out of memory, try again with fewer --jobs",
+        "CKR_SESSION_COUNT" => {
+            "the token has no sessions to spare, try again with fewer --jobs or once other applications have finished"
+        }
+        "CKR_FUNCTION_FAILED" => {
+            "the PKCS#11 library gave no reason, check its logs, trying again with --retries may help"
+        }
+        "CKR_GENERAL_ERROR" => {
+            "the PKCS#11 library failed unrecoverably, check its configuration and logs"
+        }
+        "CKR_CRYPTOKI_ALREADY_INITIALIZED" => {
+            "another part of this process has already initialized the PKCS#11 library"
+        }
+        "ItemNotFound" => "the server has no object with this identifier",
+        "AuthenticationNotSuccessful" => {
+            "the server rejected the credentials, check the username and password or client certificate"
+        }
+        "PermissionDenied" => {
+            "the credentials are valid but do not allow access to these keys, check the access rights on the server"
+        }
+        "ResponseTooLarge" => {
+            "the response would be too large, use --locate-page-size or a smaller --batch-size"
+        }
+        "OperationNotSupported" | "FeatureNotSupported" => {
+            "the server does not support this request, try --batch-size 1 or without --locate-page-size"
+        }
+        "InvalidMessage" | "InvalidField" | "MissingData" => {
+            "the server could not process the request, it may not support the KMIP version or attributes used"
+        }
+        "ObjectArchived" => "the object has been archived and must be recovered before it can be read",
+        _ => return None,
+    };
+    Some(explanation)
+}
+
+fn classify(err: &anyhow::Error) -> (ErrorClass, Option<String>) {
+    for cause in err.chain() {
+        if let Some(err) = cause.downcast_ref::<ClassifiedError>() {
+            return (err.class, None);
+        }
+        if let Some(err) = cause.downcast_ref::<cryptoki::error::Error>() {
+            return classify_pkcs11(err);
+        }
+        if let Some(err) = cause.downcast_ref::<kmip::client::Error>() {
+            return classify_kmip(err);
+        }
+        if let Some(err) = cause.downcast_ref::<std::io::Error>() {
+            return (classify_io(err), None);
+        }
+        if cause.is::<openssl::error::ErrorStack>() || cause.is::<openssl::ssl::Error>() {
+            return (ErrorClass::Tls, None);
+        }
+    }
+    (ErrorClass::Internal, None)
+}
+
+fn classify_pkcs11(err: &cryptoki::error::Error) -> (ErrorClass, Option<String>) {
+    use cryptoki::error::Error;
+
+    match err {
+        Error::LibraryLoading(_) => (ErrorClass::Connection, None),
+        Error::Pkcs11(rv) => {
+            let class = match rv {
+                RvError::PinIncorrect
+                | RvError::PinInvalid
+                | RvError::PinLenRange
+                | RvError::PinExpired
+                | RvError::PinLocked
+                | RvError::UserNotLoggedIn
+                | RvError::UserPinNotInitialized => ErrorClass::Authentication,
+                RvError::SlotIdInvalid
+                | RvError::TokenNotPresent
+                | RvError::TokenNotRecognized
+                | RvError::DeviceRemoved => ErrorClass::NotFound,
+                _ => ErrorClass::Server,
+            };
+            (class, Some(ckr_name(*rv)))
+        }
+        _ => (ErrorClass::Internal, None),
+    }
+}
+
+fn classify_kmip(err: &kmip::client::Error) -> (ErrorClass, Option<String>) {
+    use kmip::client::Error;
+
+    match err {
+        Error::ConfigurationError(_) => (ErrorClass::Argument, None),
+        Error::RequestWriteError(_) | Error::ResponseReadError(_) => (ErrorClass::Connection, None),
+        Error::ItemNotFound(msg) => (
+            ErrorClass::NotFound,
+            crate::message::result_reason(msg).or_else(|| Some("ItemNotFound".to_string())),
+        ),
+        Error::ServerError(msg) => {
+            let reason = crate::message::result_reason(msg);
+            let class = match reason.as_deref() {
+                Some("AuthenticationNotSuccessful" | "PermissionDenied") => {
+                    ErrorClass::Authentication
+                }
+                Some(_) => ErrorClass::Server,
+                // The kmip-protocol crate does not include the result reason in its errors, so fall back to the
+                // result message that the server gave.
+                None => {
+                    let msg = msg.to_ascii_lowercase();
+                    if ["auth", "permission", "denied", "credential"]
+                        .iter()
+                        .any(|word| msg.contains(word))
+                    {
+                        ErrorClass::Authentication
+                    } else {
+                        ErrorClass::Server
+                    }
+                }
+            };
+            (class, reason)
+        }
+        _ => (ErrorClass::Internal, None),
+    }
+}
+
+fn classify_io(err: &std::io::Error) -> ErrorClass {
+    use std::io::ErrorKind::*;
+
+    match err.kind() {
+        NotFound | PermissionDenied | AlreadyExists | InvalidInput | InvalidData => {
+            ErrorClass::Argument
+        }
+        _ => ErrorClass::Connection,
+    }
+}
+
+/// The name of the PKCS#11 return value, e.g. CKR_PIN_INCORRECT for [RvError::PinIncorrect].
+pub(crate) fn ckr_name(rv: RvError) -> String {
+    let mut name = "CKR".to_string();
+    for c in format!("{:?}", rv).chars() {
+        if c.is_ascii_uppercase() {
+            name.push('_');
+        }
+        name.push(c.to_ascii_uppercase());
+    }
+    name
+}
@@ -0,0 +1,59 @@
+use std::{
+    ops::ControlFlow,
+    sync::mpsc::{self, Receiver},
+    thread,
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{config::Opt, key::Key};
+
+/// An iterator over the keys held by a key store, see [iter_keys].
+pub struct KeyIter {
+    rx: Receiver<Result<Key>>,
+}
+
+impl Iterator for KeyIter {
+    type Item = Result<Key>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.rx.recv().ok()
+    }
+}
+
+/// Connect to the key store identified by the server option and iterate over the keys that it holds as they are
+/// retrieved.
+///
+/// Keys are retrieved on a background thread, at most `buffer` keys ahead of the consumer. An error is returned for
+/// each key that could not be retrieved, once all other keys have been, and a failure to connect or to enumerate the
+/// keys is returned as the final item. Dropping the iterator stops the retrieval of further keys, e.g. once enough
+/// keys have been seen.
+pub fn iter_keys(opt: Opt, buffer: usize) -> KeyIter {
+    let (tx, rx) = mpsc::sync_channel(buffer.max(1));
+    let spawned = thread::Builder::new().name("keyls".to_string()).spawn({
+        let tx = tx.clone();
+        move || {
+            let res = crate::connect(&opt).and_then(|mut store| {
+                store.get_keys(&|key| match tx.send(Ok(key)) {
+                    Ok(()) => ControlFlow::Continue(()),
+                    // The iterator has been dropped so there is no point retrieving more keys
+                    Err(_) => ControlFlow::Break(()),
+                })
+            });
+            match res {
+                Ok(failures) => {
+                    for failure in failures {
+                        let _ = tx.send(Err(failure.into()));
+                    }
+                }
+                Err(err) => {
+                    let _ = tx.send(Err(err));
+                }
+            }
+        }
+    });
+    if let Err(err) = spawned {
+        let _ = tx.send(Err(anyhow!("Cannot start key listing thread: {}", err)));
+    }
+    KeyIter { rx }
+}
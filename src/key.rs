@@ -1,16 +1,160 @@
-#[derive(Debug)]
+use std::{
+    collections::BTreeMap,
+    time::{Duration, SystemTime},
+};
+
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use crate::{
+    algorithm,
+    config::{AppInfoFilter, Opt},
+    util::glob_match,
+};
+
+#[derive(Debug, Serialize, JsonSchema)]
 pub struct Key {
     pub id: String,
+    #[serde(rename = "type")]
     pub typ: KeyType,
     pub name: String,
+    /// The cryptographic algorithm of the key by its canonical name, e.g. RSA, ECDSA P-256 or AES-256, or for secret
+    /// data the type of data, e.g. Password.
     pub alg: String,
     pub len: String,
+    pub token: Option<String>,
+    /// The KMIP Object Groups that the key belongs to.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<String>,
+    /// The KMIP Application Specific Information of the key.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub app_info: Vec<AppInfo>,
+    /// Whether a PKCS#11 private key requires the user to log in again before each use (CKA_ALWAYS_AUTHENTICATE),
+    /// only present for private keys if it was retrieved.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub always_authenticate: Option<bool>,
+    /// When the key may no longer be used, in RFC 3339 format, i.e. its KMIP Deactivation Date, PKCS#11 CKA_END_DATE
+    /// or for a certificate its notAfter time, if it has one and it was retrieved.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires: Option<String>,
+    /// The details of a certificate, only present if the object is a certificate.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cert: Option<CertInfo>,
+    /// How a KMIP Split Key was split, only present if the object is a split key.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub split_key: Option<SplitKey>,
+    /// The attributes requested with --attr that the key has, by the name they were requested by, as text.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub attrs: BTreeMap<String, String>,
+}
+
+impl Key {
+    /// Does the key pass the --group and --app-info filters, if given?
+    pub(crate) fn matches_filters(&self, opt: &Opt) -> bool {
+        let group_matches =
+            |pattern: &String| self.groups.iter().any(|group| glob_match(pattern, group));
+        let app_info_matches = |filter: &AppInfoFilter| {
+            self.app_info.iter().any(|app_info| {
+                glob_match(&filter.namespace, &app_info.namespace)
+                    && filter
+                        .data
+                        .as_ref()
+                        .is_none_or(|data| glob_match(data, &app_info.data))
+            })
+        };
+        opt.group.as_ref().is_none_or(group_matches)
+            && opt.app_info.as_ref().is_none_or(app_info_matches)
+            && opt
+                .alg
+                .as_ref()
+                .is_none_or(|pattern| self.alg_matches(pattern))
+    }
+
+    /// Does the algorithm of the key match the --alg pattern, ignoring case? A pattern naming an algorithm family also
+    /// matches the names of its curves and key sizes, e.g. ECDSA matches ECDSA P-256 and AES matches AES-256, and one
+    /// naming an algorithm by another name matches its canonical name, e.g. EC matches ECDSA.
+    pub(crate) fn alg_matches(&self, pattern: &str) -> bool {
+        let pattern = algorithm::canonical_name(pattern).to_lowercase();
+        let alg = self.alg.to_lowercase();
+        glob_match(&pattern, &alg)
+            || glob_match(&format!("{} *", pattern), &alg)
+            || glob_match(&format!("{}-*", pattern), &alg)
+    }
+
+    /// Has the key expired, or does it expire within the given number of days from now?
+    pub fn expires_within(&self, days: u32) -> bool {
+        let deadline = SystemTime::now() + Duration::from_secs(u64::from(days) * 24 * 60 * 60);
+        self.expires
+            .as_deref()
+            .and_then(|expires| humantime::parse_rfc3339(expires).ok())
+            .is_some_and(|expires| expires <= deadline)
+    }
+}
+
+/// Data about a key belonging to an application, identified by the namespace of the application.
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct AppInfo {
+    pub namespace: String,
+    pub data: String,
+}
+
+impl std::fmt::Display for AppInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.namespace, self.data)
+    }
+}
+
+/// The decoded details of a certificate, each only present if it was retrieved.
+#[derive(Clone, Debug, Default, Serialize, JsonSchema)]
+pub struct CertInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub issuer: Option<String>,
+    /// The serial number in hex.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub serial: Option<String>,
+    /// The start of the validity period, in RFC 3339 format.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub not_before: Option<String>,
+    /// The end of the validity period, in RFC 3339 format.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub not_after: Option<String>,
+}
+
+/// How a key was split into parts, of which a split key is one.
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct SplitKey {
+    /// Which part this is, starting from 1.
+    pub part: i32,
+    pub parts: i32,
+    /// The number of parts needed to reconstruct the key.
+    pub threshold: i32,
+    pub method: String,
+}
+
+impl std::fmt::Display for SplitKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "part {} of {}, {} needed ({})",
+            self.part, self.parts, self.threshold, self.method
+        )
+    }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
 pub enum KeyType {
     Public,
     Private,
+    #[serde(rename = "secret")]
+    SecretData,
+    #[serde(rename = "opaque")]
+    OpaqueObject,
+    #[serde(rename = "split_key")]
+    SplitKey,
+    Certificate,
 }
 
 impl std::fmt::Display for KeyType {
@@ -18,6 +162,10 @@ impl std::fmt::Display for KeyType {
         match self {
             KeyType::Public => f.write_str("Public Key"),
             KeyType::Private => f.write_str("Private Key"),
+            KeyType::SecretData => f.write_str("Secret Data"),
+            KeyType::OpaqueObject => f.write_str("Opaque Object"),
+            KeyType::SplitKey => f.write_str("Split Key"),
+            KeyType::Certificate => f.write_str("Certificate"),
         }
     }
 }
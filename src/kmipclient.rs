@@ -1,120 +1,999 @@
-use log::error;
-use std::time::Duration;
+use std::{
+    collections::{BTreeMap, HashSet},
+    ops::ControlFlow,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tracing::{debug, info, warn};
 
 use anyhow::{bail, Result};
 use kmip::{
-    client::{Client, ClientCertificate, ConnectionSettings},
+    client::{ClientCertificate, ConnectionSettings},
     types::{
-        common::{AttributeName, AttributeValue, ObjectType, UniqueIdentifier},
-        request::{Attribute, RequestPayload},
+        common::{
+            AttributeValue, BlockCipherMode, CryptographicParameters, Data, DataLength,
+            DigitalSignatureAlgorithm, HashingAlgorithm, KeyFormatType, KeyMaterial, LinkType,
+            ObjectType, PaddingMethod, UniqueIdentifier,
+        },
+        request::RequestPayload,
         response::{GetResponsePayload, ManagedObject, ResponsePayload},
-        traits::ReadWrite,
     },
 };
+use openssl::{pkey::PKey, rsa::Rsa};
 
 use crate::{
-    config::{Opt, ServerOpt},
-    key::{Key, KeyType},
-    util::load_binary_file,
+    algorithm::{self, kmip_algorithm_name},
+    config::{Include, KeyColumn, KmipVersion, Opt, ServerOpt},
+    csr,
+    error::{ClassifiedError, ErrorClass, KeyError},
+    key::{AppInfo, Key, KeyType, SplitKey},
+    message::{self, ObjectDetails, Request, RequestSettings},
+    ratelimit::RateLimiter,
+    retry,
+    source::{Capabilities, KeySource, SignatureAlgorithm, WrappedKey},
+    timings::Timings,
+    tls::{self, KmipClient},
+    util::{format_unix_time, load_binary_file},
 };
 
-pub(crate) fn get_keys(opt: Opt) -> Result<Vec<Key>> {
-    let client = kmip::client::tls::openssl::connect(&opt.try_into()?)?;
+/// A connection to a KMIP server which is reused by every operation performed against the server, and which is
+/// transparently re-established if it breaks.
+pub struct KmipConnection<'a> {
+    opt: &'a Opt,
+    conn_settings: ConnectionSettings,
+    request_settings: RequestSettings,
+    client: KmipClient,
+    /// Set when the connection must be re-established before it is next used.
+    broken: bool,
+    /// Cleared if the server fails to handle a request message containing multiple batch items.
+    batching: bool,
+    /// Shared with the additional connections opened to retrieve keys in parallel.
+    limiter: Arc<RateLimiter>,
+    /// Shared with the additional connections opened to retrieve keys in parallel.
+    timings: Arc<Timings>,
+}
 
-    let mut keys = Vec::new();
-    for key_id in get_key_ids(&client, ObjectType::PrivateKey)? {
-        match get_key(&client, &key_id) {
-            Ok(key) => keys.push(key),
-            Err(err) => error!("GET private key '{:?}' failed: {}", &key_id, err),
+impl<'a> KeySource<'a> for KmipConnection<'a> {
+    fn connect(opt: &'a Opt) -> Result<Self> {
+        let conn_settings: ConnectionSettings = opt.try_into()?;
+        let client = retry::with_retries(
+            opt.retries,
+            "Connecting to the KMIP server",
+            is_transient_error,
+            || tls::connect(&conn_settings, opt),
+        )?;
+        let version = match opt.kmip_version {
+            Some(version) => version,
+            None => negotiate_version(&client, &conn_settings)?,
+        };
+        info!("Using KMIP {}", version);
+        if version.has_tagged_attributes() {
+            if let Some(name) = opt
+                .attrs
+                .iter()
+                .find(|name| !message::can_reference_attribute(name))
+            {
+                return Err(ClassifiedError::new(
+                    ErrorClass::Argument,
+                    format!(
+                        "Attribute '{}' cannot be requested with KMIP {}, which only knows standard attributes and vendor attributes named VENDOR-NAME, e.g. x-Owner",
+                        name, version
+                    ),
+                )
+                .into());
+            }
         }
+
+        Ok(Self {
+            opt,
+            request_settings: RequestSettings::new(&conn_settings, version),
+            conn_settings,
+            client,
+            broken: false,
+            batching: opt.batch_size > 1,
+            limiter: Arc::new(RateLimiter::new(opt.max_rps)),
+            timings: Default::default(),
+        })
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            parallel: true,
+            batching: true,
+            multiple_tokens: false,
+        }
+    }
+
+    fn timings(&self) -> &Timings {
+        &self.timings
+    }
+
+    fn get_keys(
+        &mut self,
+        on_key: &(dyn Fn(Key) -> ControlFlow<()> + Sync),
+    ) -> Result<Vec<KeyError>> {
+        let start = Instant::now();
+        let mut key_ids = Vec::new();
+        let mut object_types = vec![
+            ObjectType::PrivateKey,
+            ObjectType::PublicKey,
+            ObjectType::SecretData,
+        ];
+        if self.opt.includes(Include::All) {
+            object_types.extend([ObjectType::OpaqueObject, ObjectType::SplitKey]);
+        }
+        for object_type in object_types {
+            match self.get_key_ids(Some(object_type)) {
+                Ok(ids) => {
+                    debug!("Located {} {} objects", ids.len(), object_type);
+                    for key_id in ids {
+                        key_ids.push((Some(object_type), key_id));
+                    }
+                }
+                Err(err) if is_server_error(&err) => {
+                    // The type of each object is then instead determined when retrieving its attributes
+                    warn!(
+                        "Locating keys by object type failed, locating all objects instead: {}",
+                        err
+                    );
+                    let ids = self.get_key_ids(None)?;
+                    debug!("Located {} objects", ids.len());
+                    key_ids = ids.into_iter().map(|key_id| (None, key_id)).collect();
+                    break;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        self.timings.record_enumeration(start.elapsed());
+
+        // Workers take the next batch of keys to retrieve from the shared list until none remain, so that a slow
+        // server response or a worker that failed to connect does not hold up the remaining keys.
+        let batch_size = usize::from(self.opt.batch_size.max(1));
+        let next = AtomicUsize::new(0);
+        let stopped = AtomicBool::new(false);
+        let failures = Mutex::new(Vec::new());
+        let opt = self.opt;
+        let on_key = |key: Key| {
+            if key.matches_filters(opt) {
+                on_key(key)
+            } else {
+                ControlFlow::Continue(())
+            }
+        };
+        let work = |conn: &mut KmipConnection| loop {
+            let start = next.fetch_add(batch_size, Ordering::Relaxed);
+            if start >= key_ids.len() || stopped.load(Ordering::Relaxed) {
+                break;
+            }
+            let end = (start + batch_size).min(key_ids.len());
+            let started = Instant::now();
+            let flow = conn.get_key_batch(&key_ids[start..end], &on_key, &failures);
+            conn.timings.record_keys(started.elapsed(), end - start);
+            if flow.is_break() {
+                stopped.store(true, Ordering::Relaxed);
+                break;
+            }
+        };
+
+        let jobs = usize::from(self.opt.jobs).clamp(1, key_ids.len().max(1));
+        let limiter = self.limiter.clone();
+        let timings = self.timings.clone();
+        std::thread::scope(|scope| {
+            for _ in 1..jobs {
+                scope.spawn(|| match KmipConnection::connect(opt) {
+                    Ok(mut conn) => {
+                        conn.limiter = limiter.clone();
+                        conn.timings = timings.clone();
+                        work(&mut conn)
+                    }
+                    Err(err) => {
+                        warn!(
+                            "Failed to open an additional KMIP server connection: {}",
+                            err
+                        );
+                    }
+                });
+            }
+
+            work(self);
+        });
+
+        Ok(failures.into_inner().unwrap())
+    }
+
+    fn get_keys_by_id(&mut self, id: &str) -> Result<Vec<Key>> {
+        let key_id = UniqueIdentifier(id.to_string());
+        let what = format!("GET ATTRIBUTES '{}'", id);
+        let wanted = KeyAttributes::wanted(self.opt);
+        let key = self.do_op(&what, |client, settings| {
+            get_key(client, settings, &key_id, &wanted)
+        })?;
+        Ok(Some(key)
+            .filter(|key| key.matches_filters(self.opt))
+            .into_iter()
+            .collect())
     }
-    for key_id in get_key_ids(&client, ObjectType::PublicKey)? {
-        match get_key(&client, &key_id) {
-            Ok(key) => keys.push(key),
-            Err(err) => error!("GET public key '{:?}' failed: {}", &key_id, err),
+
+    /// The ID can be that of the public key or of a private key linked to its public key. Only RSA public keys are
+    /// supported, as the kmip-protocol crate cannot deserialize EC public keys.
+    fn public_key(&mut self, id: &str) -> Result<Vec<u8>> {
+        let public_key_id = self.public_key_id(id)?;
+
+        let what = format!("GET PublicKey '{}'", *public_key_id);
+        let key_material = self.do_op(&what, |client, settings| {
+            let payload = RequestPayload::Get(
+                Some(public_key_id.clone()),
+                Some(KeyFormatType::PKCS1),
+                None,
+                None,
+            );
+            match message::do_request(client, settings, payload)? {
+                ResponsePayload::Get(GetResponsePayload {
+                    cryptographic_object: ManagedObject::PublicKey(key),
+                    ..
+                }) => Ok(key.key_block.key_value.key_material),
+                ResponsePayload::Get(_) => {
+                    bail!("KMIP object '{}' is not a public key", *public_key_id)
+                }
+                _ => bail!("Unexpected response payload"),
+            }
+        })?;
+        match key_material {
+            KeyMaterial::Bytes(der) => {
+                Ok(PKey::from_rsa(Rsa::public_key_from_der_pkcs1(&der)?)?.public_key_to_der()?)
+            }
+            KeyMaterial::TransparentRSAPublicKey(key) => {
+                csr::rsa_public_key(&key.modulus, &key.public_exponent)
+            }
+            _ => bail!("Unsupported public key, only RSA public keys are supported for KMIP"),
         }
     }
 
-    keys.sort_by_key(|v| v.id.clone());
+    fn sign(&mut self, id: &str, alg: SignatureAlgorithm, data: &[u8]) -> Result<Vec<u8>> {
+        let params = match alg {
+            SignatureAlgorithm::RsaPkcs1Sha256 => CryptographicParameters::default()
+                .with_padding_method(PaddingMethod::PKCS1_v1_5)
+                .with_hashing_algorithm(HashingAlgorithm::SHA256),
+            SignatureAlgorithm::EcdsaSha256 => CryptographicParameters::default()
+                .with_digital_signature_algorithm(DigitalSignatureAlgorithm::ECDSAWithSHA256),
+            SignatureAlgorithm::EcdsaSha384 => CryptographicParameters::default()
+                .with_digital_signature_algorithm(DigitalSignatureAlgorithm::ECDSAWithSHA384),
+            SignatureAlgorithm::EcdsaSha512 => CryptographicParameters::default()
+                .with_digital_signature_algorithm(DigitalSignatureAlgorithm::ECDSAWithSHA512),
+        };
+        let key_id = UniqueIdentifier(id.to_string());
+        let what = format!("SIGN '{}'", id);
+        self.do_op(&what, |client, settings| {
+            let payload =
+                RequestPayload::Sign(Some(key_id.clone()), Some(params), Data(data.to_vec()));
+            match message::do_request(client, settings, payload)? {
+                ResponsePayload::Sign(res) => Ok(res.signature_data),
+                _ => bail!("Unexpected response payload"),
+            }
+        })
+    }
+
+    /// The certificate is registered as a new object linked to the public key, which is that linked to the private
+    /// key if the ID is that of a private key.
+    fn import_cert(&mut self, id: &str, cert: &[u8]) -> Result<String> {
+        let public_key_id = self.public_key_id(id)?;
+        let what = format!("REGISTER Certificate for '{}'", *public_key_id);
+        let cert_id = self.do_op(&what, |client, settings| {
+            message::register_certificate(client, settings, cert, &public_key_id)
+        })?;
+        Ok(cert_id.0)
+    }
+
+    /// AES wrapping keys wrap with the NIST Key Wrap block cipher mode (0x0D), i.e. AES Key Wrap without padding as
+    /// specified by RFC 3394, and RSA wrapping keys with OAEP and SHA-256. RSA wrapping keys cannot wrap a private
+    /// key, as OAEP can only encrypt data much shorter than the modulus of the key.
+    fn export_wrapped(&mut self, id: &str, wrapping_key_id: &str) -> Result<WrappedKey> {
+        let key_id = UniqueIdentifier(id.to_string());
+        let wrapping_key_id = UniqueIdentifier(wrapping_key_id.to_string());
 
-    Ok(keys)
+        let what = format!("GET ATTRIBUTES '{}'", *wrapping_key_id);
+        let wrapping_alg = self.do_op(&what, |client, settings| {
+            let request =
+                message::get_attributes(settings, &wrapping_key_id, &["Cryptographic Algorithm"]);
+            match message::do_request(client, settings, request)? {
+                ResponsePayload::GetAttributes(res) => Ok(res
+                    .attributes
+                    .unwrap_or_default()
+                    .into_iter()
+                    .find_map(|attr| match (attr.name.0.as_str(), attr.value) {
+                        (message::RAW_CRYPTOGRAPHIC_ALGORITHM, AttributeValue::Integer(alg)) => {
+                            Some(alg as u32)
+                        }
+                        _ => None,
+                    })),
+                _ => bail!("Unexpected response payload"),
+            }
+        })?;
+
+        let what = format!("GET ATTRIBUTES '{}'", id);
+        let wanted = KeyAttributes::wanted(self.opt);
+        let key = self.do_op(&what, |client, settings| {
+            get_key(client, settings, &key_id, &wanted)
+        })?;
+
+        let (params, mechanism) = match wrapping_alg {
+            Some(0x03) => (
+                CryptographicParameters::default()
+                    .with_block_cipher_mode(BlockCipherMode::NISTKeyWrap),
+                format!("{} (RFC 3394)", BlockCipherMode::NISTKeyWrap),
+            ),
+            Some(0x04) if key.typ == KeyType::Private => {
+                return Err(ClassifiedError::new(
+                    ErrorClass::Argument,
+                    "An RSA wrapping key cannot wrap a private key, as RSA OAEP can only encrypt data shorter than its modulus, use an AES wrapping key instead",
+                )
+                .into())
+            }
+            Some(0x04) => (
+                CryptographicParameters::default()
+                    .with_padding_method(PaddingMethod::OAEP)
+                    .with_hashing_algorithm(HashingAlgorithm::SHA256),
+                format!("{} {}", PaddingMethod::OAEP, HashingAlgorithm::SHA256),
+            ),
+            alg => {
+                return Err(ClassifiedError::new(
+                    ErrorClass::Argument,
+                    format!(
+                        "Unsupported wrapping key algorithm {}, only AES and RSA wrapping keys are supported",
+                        alg.map(kmip_algorithm_name).as_deref().unwrap_or("(unknown)")
+                    ),
+                )
+                .into())
+            }
+        };
+
+        let what = format!("GET wrapped '{}'", id);
+        let wrapped = self.do_op(&what, |client, settings| {
+            message::get_wrapped_key(client, settings, &key_id, &wrapping_key_id, params)
+        })?;
+
+        Ok(WrappedKey {
+            blob: wrapped.blob,
+            key,
+            wrapping_key: wrapping_key_id.0,
+            mechanism,
+            format: wrapped.format,
+        })
+    }
+
+    fn random(&mut self, len: u32) -> Result<Vec<u8>> {
+        let data_length = i32::try_from(len).map_err(|_| {
+            ClassifiedError::new(
+                ErrorClass::Argument,
+                format!(
+                    "Cannot retrieve {} random bytes, the most is {}",
+                    len,
+                    i32::MAX
+                ),
+            )
+        })?;
+        let what = format!("RNG RETRIEVE {} bytes", len);
+        self.do_op(&what, |client, settings| {
+            let payload = RequestPayload::RNGRetrieve(DataLength(data_length));
+            match message::do_request(client, settings, payload)? {
+                ResponsePayload::RNGRetrieve(res) => Ok(res.data),
+                _ => bail!("Unexpected response payload"),
+            }
+        })
+    }
 }
 
-fn get_key<T: ReadWrite>(client: &Client<T>, key_id: &UniqueIdentifier) -> Result<Key> {
-    let key: GetResponsePayload = client.get_key(key_id)?;
+impl<'a> KmipConnection<'a> {
+    /// The ID of the public key with the given ID, or of the public key linked to the private key with the given ID.
+    fn public_key_id(&mut self, id: &str) -> Result<UniqueIdentifier> {
+        let key_id = UniqueIdentifier(id.to_string());
+        let what = format!("GET ATTRIBUTES '{}'", id);
+        self.do_op(&what, |client, settings| {
+            let request = message::get_attributes(settings, &key_id, &["Object Type", "Link"]);
+            let attrs = match message::do_request(client, settings, request)? {
+                ResponsePayload::GetAttributes(res) => res.attributes.unwrap_or_default(),
+                _ => bail!("Unexpected response payload"),
+            };
+            if attrs
+                .iter()
+                .any(|attr| attr.value == AttributeValue::ObjectType(ObjectType::PublicKey))
+            {
+                return Ok(key_id.clone());
+            }
+            attrs
+                .into_iter()
+                .find_map(|attr| match attr.value {
+                    AttributeValue::Link(LinkType::PublicKeyLink, linked_id) => {
+                        Some(UniqueIdentifier(linked_id.0))
+                    }
+                    _ => None,
+                })
+                .ok_or_else(|| {
+                    ClassifiedError::new(
+                        ErrorClass::NotFound,
+                        format!("KMIP object '{}' is not a public key or linked to one", id),
+                    )
+                    .into()
+                })
+        })
+    }
 
-    let (typ, alg, len) = match key.cryptographic_object {
-        ManagedObject::PublicKey(k) => (
-            KeyType::Public,
-            k.key_block.cryptographic_algorithm,
-            k.key_block.cryptographic_length,
-        ),
-        ManagedObject::PrivateKey(k) => (
-            KeyType::Private,
-            k.key_block.cryptographic_algorithm,
-            k.key_block.cryptographic_length,
-        ),
-        _ => bail!("Unsupported type"),
-    };
+    /// Locate the objects of the given type, or all objects if no type is given.
+    fn get_key_ids(&mut self, object_type: Option<ObjectType>) -> Result<Vec<UniqueIdentifier>> {
+        match self.opt.locate_page_size {
+            Some(page_size) => self.get_key_ids_paged(object_type, page_size),
+            None => self.do_op("Locate", |client, settings| {
+                message::locate(client, settings, object_type)
+            }),
+        }
+    }
 
-    let payload = RequestPayload::GetAttributes(
-        Some(key_id.clone()),
-        Some(vec![AttributeName("Name".to_string())]),
-    );
-    let name = match client.do_request(payload)? {
-        ResponsePayload::GetAttributes(res) => match res.attributes {
-            Some(attrs) if !attrs.is_empty() => match &attrs[0].value {
-                AttributeValue::Name(t, _) => t.to_string(),
-                AttributeValue::TextString(t) => t.to_string(),
-                _ => "None".to_string(),
-            },
-            _ => "None".to_string(),
-        },
+    /// Locate the objects a page at a time, for servers which cap the number of identifiers returned by a single
+    /// Locate.
+    fn get_key_ids_paged(
+        &mut self,
+        object_type: Option<ObjectType>,
+        page_size: u16,
+    ) -> Result<Vec<UniqueIdentifier>> {
+        let page_size = i32::from(page_size);
+
+        let mut seen = HashSet::new();
+        let mut key_ids = Vec::new();
+        loop {
+            let offset = i32::try_from(key_ids.len())?;
+            let what = match object_type {
+                Some(object_type) => format!("Locate {} (offset {})", object_type, offset),
+                None => format!("Locate (offset {})", offset),
+            };
+            let page = self.do_op(&what, |client, settings| {
+                message::locate_page(client, settings, object_type, page_size, offset)
+            })?;
+
+            let page_len = page.len();
+            for key_id in page {
+                if !seen.insert(key_id.to_string()) {
+                    bail!("Server ignored the Locate offset and returned '{}' again, try without --locate-page-size", *key_id);
+                }
+                key_ids.push(key_id);
+            }
+            if page_len < page_size as usize {
+                return Ok(key_ids);
+            }
+        }
+    }
+
+    /// Retrieve the given keys, using a single request message for all of them if the server supports batching, and
+    /// falling back to individual requests for any keys which could not be retrieved that way. Keys which cannot be
+    /// retrieved at all are added to `failures`.
+    ///
+    /// Objects whose type was not known when they were located are skipped if they turn out not to be keys.
+    fn get_key_batch(
+        &mut self,
+        key_ids: &[(Option<ObjectType>, UniqueIdentifier)],
+        on_key: &dyn Fn(Key) -> ControlFlow<()>,
+        failures: &Mutex<Vec<KeyError>>,
+    ) -> ControlFlow<()> {
+        let mut remaining = key_ids.iter().collect::<Vec<_>>();
+        let mut incomplete = Vec::new();
+        let wanted = KeyAttributes::wanted(self.opt);
+        let is_not_a_key = |object_type: &Option<ObjectType>, err: &anyhow::Error| {
+            object_type.is_none() && err.is::<UnsupportedType>()
+        };
+        let fail = |(object_type, key_id): &(Option<ObjectType>, UniqueIdentifier),
+                    what: String,
+                    err: anyhow::Error| {
+            if is_not_a_key(object_type, &err) {
+                debug!("Skipping '{}': {}", **key_id, err);
+            } else {
+                failures.lock().unwrap().push(KeyError::new(what, err));
+            }
+        };
+
+        if self.batching && key_ids.len() > 1 {
+            let requests = key_ids
+                .iter()
+                .map(|(_, key_id)| wanted.request(&self.request_settings, key_id))
+                .collect::<Vec<_>>();
+            let what = format!("Batched GET ATTRIBUTES of {} keys", key_ids.len());
+            match self.do_op(&what, |client, settings| {
+                message::do_batch(client, settings, requests.clone())
+            }) {
+                Ok(results) => {
+                    remaining.clear();
+                    for (key_id, result) in key_ids.iter().zip(results) {
+                        match result
+                            .and_then(|res| key_attributes_from_response(res, wanted.clone()))
+                        {
+                            Ok(key) if key.is_complete() => match key.into_key(&key_id.1) {
+                                Ok(key) => on_key(key)?,
+                                Err(_) => remaining.push(key_id),
+                            },
+                            Ok(key) => incomplete.push((key_id, key)),
+                            Err(err) if is_not_a_key(&key_id.0, &err) => {
+                                debug!("Skipping '{}': {}", *key_id.1, err)
+                            }
+                            Err(_) => remaining.push(key_id),
+                        }
+                    }
+                }
+                Err(err) => {
+                    warn!(
+                        "{} failed, retrieving keys individually instead: {}",
+                        what, err
+                    );
+                    self.batching = false;
+                }
+            }
+        }
+
+        for (located, key) in incomplete {
+            let what = describe_op("GET", located);
+            match self.do_op(&what, |client, settings| {
+                complete_key(client, settings, &located.1, key.clone())
+            }) {
+                Ok(key) => on_key(key)?,
+                Err(err) => fail(located, what, err),
+            }
+        }
+
+        for located in remaining {
+            let what = describe_op("GET ATTRIBUTES", located);
+            match self.do_op(&what, |client, settings| {
+                get_key(client, settings, &located.1, &wanted)
+            }) {
+                Ok(key) => on_key(key)?,
+                Err(err) => fail(located, what, err),
+            }
+        }
+
+        ControlFlow::Continue(())
+    }
+
+    /// Perform a KMIP operation, retrying on transient failure and first re-establishing the connection to the
+    /// server if the previous attempt broke it.
+    fn do_op<T, F>(&mut self, what: &str, f: F) -> Result<T>
+    where
+        F: Fn(&KmipClient, &RequestSettings) -> Result<T>,
+    {
+        let Self {
+            opt,
+            conn_settings,
+            request_settings,
+            client,
+            broken,
+            limiter,
+            ..
+        } = self;
+        retry::with_retries(opt.retries, what, is_transient_error, || {
+            limiter.wait();
+            if *broken || client.connection_error_count() > 0 {
+                *client = tls::connect(conn_settings, opt)?;
+                *broken = false;
+            }
+            let res = f(client, request_settings);
+            if let Err(err) = &res {
+                *broken = is_connection_error(err);
+            }
+            res
+        })
+    }
+}
+
+fn describe_op(op: &str, (object_type, key_id): &(Option<ObjectType>, UniqueIdentifier)) -> String {
+    match object_type {
+        Some(object_type) => format!("{} {} '{}'", op, object_type, **key_id),
+        None => format!("{} '{}'", op, **key_id),
+    }
+}
+
+fn is_transient_error(err: &anyhow::Error) -> bool {
+    if is_connection_error(err) {
+        return true;
+    }
+    match err.downcast_ref::<kmip::client::Error>() {
+        // KMIP 1.x has no result reason for a busy server, so look for vendor result messages saying so instead
+        Some(kmip::client::Error::ServerError(msg)) => msg.to_ascii_lowercase().contains("busy"),
+        _ => false,
+    }
+}
+
+/// Did the server process the request but report that it failed?
+fn is_server_error(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<kmip::client::Error>(),
+        Some(kmip::client::Error::ServerError(_))
+    )
+}
+
+/// Is this an error which means that the connection to the server can no longer be used?
+fn is_connection_error(err: &anyhow::Error) -> bool {
+    retry::is_transient_io_error(err)
+        || matches!(err.downcast_ref::<kmip::client::Error>(), Some(err) if err.is_connection_error())
+}
+
+/// The error for an object which is not of a type that keyls lists.
+#[derive(Debug)]
+struct UnsupportedType(ObjectType);
+
+impl std::fmt::Display for UnsupportedType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Unsupported type {}", self.0)
+    }
+}
+
+impl std::error::Error for UnsupportedType {}
+
+/// The attributes of a key that are listed, any of which the server may not have returned.
+///
+/// Attributes which are not needed for the columns being output are set to an empty value up front so that they are
+/// neither requested from the server nor cause the key to be considered incomplete.
+#[derive(Clone, Default)]
+struct KeyAttributes {
+    typ: Option<KeyType>,
+    name: Option<String>,
+    alg: Option<String>,
+    len: Option<String>,
+    groups: Option<Vec<String>>,
+    app_info: Option<Vec<AppInfo>>,
+    /// Only known by getting the split key, so only set if wanted.
+    split_key: Option<SplitKey>,
+    wants_split_key: bool,
+    /// Not all keys have a deactivation date, so this is only requested if wanted and not required.
+    expires: Option<String>,
+    wants_expires: bool,
+    /// Whether to list Opaque Objects and Split Keys.
+    include_all: bool,
+    /// The names of the attributes requested with --attr, which are not required as not all keys have them.
+    wanted_attrs: Vec<String>,
+    attrs: BTreeMap<String, String>,
+}
+
+impl KeyAttributes {
+    /// Create an empty set of attributes, with those that are not needed for the columns being output or to filter
+    /// keys by already filled.
+    fn wanted(opt: &Opt) -> Self {
+        let unwanted = |column| Some(String::new()).filter(|_| !opt.needs_column(column));
+        Self {
+            typ: None,
+            name: unwanted(KeyColumn::Name),
+            alg: unwanted(KeyColumn::Algorithm),
+            len: unwanted(KeyColumn::Length),
+            groups: Some(vec![]).filter(|_| !opt.needs_column(KeyColumn::Group)),
+            app_info: Some(vec![]).filter(|_| !opt.needs_column(KeyColumn::AppInfo)),
+            split_key: None,
+            wants_split_key: opt.needs_column(KeyColumn::SplitKey),
+            expires: None,
+            wants_expires: opt.needs_column(KeyColumn::Expires),
+            include_all: opt.includes(Include::All),
+            wanted_attrs: opt.attrs.clone(),
+            attrs: BTreeMap::new(),
+        }
+    }
+
+    /// Request the attributes that have not yet been filled, to be processed by [key_attributes_from_response].
+    ///
+    /// This deliberately avoids the Get operation as that would transfer the key material, which for private keys is
+    /// sensitive and which servers often refuse to do.
+    fn request(&self, settings: &RequestSettings, key_id: &UniqueIdentifier) -> Request {
+        let mut names = vec!["Object Type"];
+        for (missing, name) in [
+            (self.name.is_none(), "Name"),
+            (self.alg.is_none(), "Cryptographic Algorithm"),
+            (self.len.is_none(), "Cryptographic Length"),
+            (self.groups.is_none(), "Object Group"),
+            (self.app_info.is_none(), "Application Specific Information"),
+            (
+                self.wants_expires && self.expires.is_none(),
+                "Deactivation Date",
+            ),
+        ] {
+            if missing {
+                names.push(name);
+            }
+        }
+        for name in &self.wanted_attrs {
+            if !self.attrs.contains_key(name) && !names.contains(&name.as_str()) {
+                names.push(name);
+            }
+        }
+        message::get_attributes(settings, key_id, &names)
+    }
+
+    fn is_complete(&self) -> bool {
+        let split_key_known = self.typ != Some(KeyType::SplitKey) || !self.wants_split_key;
+        self.typ.is_some()
+            && self.alg.is_some()
+            && self.len.is_some()
+            && (split_key_known || self.split_key.is_some())
+    }
+
+    /// Fill in missing attributes from the key block of the key, as not all servers return all attributes via
+    /// GetAttributes.
+    fn merge_get_response(&mut self, key: GetResponsePayload) -> Result<()> {
+        let (typ, alg, len) = match key.cryptographic_object {
+            ManagedObject::PublicKey(k) => (
+                KeyType::Public,
+                k.key_block.cryptographic_algorithm,
+                k.key_block.cryptographic_length,
+            ),
+            ManagedObject::PrivateKey(k) => (
+                KeyType::Private,
+                k.key_block.cryptographic_algorithm,
+                k.key_block.cryptographic_length,
+            ),
+            _ => return Err(UnsupportedType(key.object_type).into()),
+        };
+        self.typ.get_or_insert(typ);
+        if self.alg.is_none() {
+            self.alg = alg.map(|v| algorithm::canonical_name(&v.to_string()));
+        }
+        if self.len.is_none() {
+            self.len = len.map(|v| v.to_string());
+        }
+        Ok(())
+    }
+
+    /// Fill in missing attributes from an object that the kmip-protocol crate cannot deserialize, using the type of
+    /// data held by Secret Data or an Opaque Object in place of the algorithm.
+    fn merge_object_details(&mut self, details: ObjectDetails) {
+        if self.alg.is_none() {
+            self.alg = details.data_type;
+        }
+        if self.len.is_none() {
+            self.len = details.len.map(|v| v.to_string());
+        }
+        if self.split_key.is_none() {
+            self.split_key = details.split_key;
+        }
+    }
+
+    fn into_key(self, key_id: &UniqueIdentifier) -> Result<Key> {
+        let typ = match self.typ {
+            Some(typ) => typ,
+            None => bail!("Unsupported type"),
+        };
+
+        let len = self.len.unwrap_or_else(|| "unknown".to_string());
+        let alg = match self.alg {
+            Some(alg) => algorithm::with_key_size(&alg, &len),
+            None => "unknown".to_string(),
+        };
+        Ok(Key {
+            id: key_id.to_string(),
+            typ,
+            name: self.name.unwrap_or_else(|| "None".to_string()),
+            alg,
+            len,
+            token: None,
+            groups: self.groups.unwrap_or_default(),
+            app_info: self.app_info.unwrap_or_default(),
+            always_authenticate: None,
+            expires: self.expires,
+            cert: None,
+            split_key: self.split_key,
+            attrs: self.attrs,
+        })
+    }
+}
+
+fn key_attributes_from_response(
+    response: ResponsePayload,
+    mut key: KeyAttributes,
+) -> Result<KeyAttributes> {
+    let attrs = match response {
+        ResponsePayload::GetAttributes(res) => res.attributes.unwrap_or_default(),
         _ => bail!("Unexpected response payload"),
     };
 
-    let alg = alg
-        .map(|v| v.to_string())
-        .unwrap_or_else(|| "unknown".to_string());
-    let len = len
-        .map(|v| v.to_string())
-        .unwrap_or_else(|| "unknown".to_string());
-    let id = key_id.to_string();
-
-    Ok(Key {
-        id,
-        typ,
-        name,
-        alg,
-        len,
-    })
-}
-
-fn get_key_ids<T: ReadWrite>(
-    client: &Client<T>,
-    object_type: ObjectType,
-) -> Result<Vec<UniqueIdentifier>> {
-    let payload = RequestPayload::Locate(vec![Attribute::ObjectType(object_type)]);
-    match client.do_request(payload)? {
-        ResponsePayload::Locate(res) => Ok(res.unique_identifiers),
-        _ => bail!("Unexpected response payload"),
+    for attr in attrs {
+        let requested = key
+            .wanted_attrs
+            .iter()
+            .find(|name| message::normalized_attribute_name(name) == attr.name.0);
+        if let Some(name) = requested {
+            let value = format_attribute_value(&attr.name.0, &attr.value);
+            match key.attrs.get_mut(name) {
+                // An attribute can have multiple instances, e.g. Object Group
+                Some(values) => {
+                    values.push_str(", ");
+                    values.push_str(&value);
+                }
+                None => {
+                    key.attrs.insert(name.clone(), value);
+                }
+            }
+        }
+
+        match (attr.name.0.as_str(), attr.value) {
+            ("Object Type", AttributeValue::ObjectType(ObjectType::PublicKey)) => {
+                key.typ = Some(KeyType::Public)
+            }
+            ("Object Type", AttributeValue::ObjectType(ObjectType::PrivateKey)) => {
+                key.typ = Some(KeyType::Private)
+            }
+            ("Object Type", AttributeValue::ObjectType(ObjectType::SecretData)) => {
+                key.typ = Some(KeyType::SecretData)
+            }
+            ("Object Type", AttributeValue::ObjectType(ObjectType::OpaqueObject))
+                if key.include_all =>
+            {
+                key.typ = Some(KeyType::OpaqueObject)
+            }
+            ("Object Type", AttributeValue::ObjectType(ObjectType::SplitKey))
+                if key.include_all =>
+            {
+                key.typ = Some(KeyType::SplitKey)
+            }
+            ("Object Type", AttributeValue::ObjectType(object_type)) => {
+                return Err(UnsupportedType(object_type).into())
+            }
+            ("Object Type", _) => bail!("Unsupported type"),
+            ("Name", AttributeValue::Name(t, _)) => key.name = Some(t.to_string()),
+            ("Name", AttributeValue::TextString(t)) => key.name = Some(t),
+            (message::RAW_CRYPTOGRAPHIC_ALGORITHM, AttributeValue::Integer(alg)) => {
+                key.alg = Some(kmip_algorithm_name(alg as u32))
+            }
+            ("Cryptographic Length", AttributeValue::Integer(len)) => {
+                key.len = Some(len.to_string())
+            }
+            ("Object Group", AttributeValue::ObjectGroup(group)) => {
+                key.groups.get_or_insert_with(Vec::new).push(group)
+            }
+            (
+                "Application Specific Information",
+                AttributeValue::ApplicationSpecificInformation(namespace, data),
+            ) => key.app_info.get_or_insert_with(Vec::new).push(AppInfo {
+                namespace: namespace.0,
+                data: data.0,
+            }),
+            // Date-Time values are normalized to Long Integers, see message::normalize_response()
+            ("Deactivation Date", AttributeValue::LongInteger(secs)) if secs >= 0 => {
+                key.expires = Some(format_unix_time(secs as u64))
+            }
+            _ => {
+                // ignore unexpected attributes
+            }
+        }
     }
+
+    Ok(key)
 }
 
-impl TryFrom<Opt> for ConnectionSettings {
+/// Format the value of an attribute requested with --attr as text.
+fn format_attribute_value(name: &str, value: &AttributeValue) -> String {
+    match value {
+        AttributeValue::TextString(text)
+        | AttributeValue::ObjectGroup(text)
+        | AttributeValue::ContactInformation(text) => text.clone(),
+        AttributeValue::Name(name, _) => name.to_string(),
+        AttributeValue::Link(link_type, id) => format!("{} {}", link_type, id.0),
+        AttributeValue::ObjectType(typ) => typ.to_string(),
+        AttributeValue::ApplicationSpecificInformation(namespace, data) => {
+            format!("{}={}", namespace.0, data.0)
+        }
+        AttributeValue::Boolean(value) => value.to_string(),
+        AttributeValue::ByteString(bytes) => hex::encode_upper(bytes),
+        // Some values are retyped by message::normalize_response()
+        AttributeValue::Integer(alg) if name == message::RAW_CRYPTOGRAPHIC_ALGORITHM => {
+            kmip_algorithm_name(*alg as u32)
+        }
+        AttributeValue::LongInteger(secs) if name.ends_with(" Date") && *secs >= 0 => {
+            format_unix_time(*secs as u64)
+        }
+        AttributeValue::Integer(value) => value.to_string(),
+        AttributeValue::LongInteger(value) => value.to_string(),
+        value => format!("{:?}", value),
+    }
+}
+
+/// Fall back to the Get operation for any attributes that the server did not return via GetAttributes, which is
+/// always needed to learn the type of data held by secret data or an opaque object or how a split key was split, as
+/// these are not attributes.
+///
+/// If the server refuses the Get, as servers commonly do for private keys, the key is listed with the attributes that
+/// are already known provided that its type is one of them.
+fn complete_key(
+    client: &KmipClient,
+    settings: &RequestSettings,
+    key_id: &UniqueIdentifier,
+    mut key: KeyAttributes,
+) -> Result<Key> {
+    if !key.is_complete() {
+        let res = match key.typ {
+            Some(KeyType::SecretData | KeyType::OpaqueObject | KeyType::SplitKey) => {
+                message::get_object_details(client, settings, key_id)
+                    .map(|details| key.merge_object_details(details))
+            }
+            _ => {
+                let payload = RequestPayload::Get(Some(key_id.clone()), None, None, None);
+                match message::do_request(client, settings, payload) {
+                    Ok(ResponsePayload::Get(res)) => key.merge_get_response(res),
+                    Ok(_) => bail!("Unexpected response payload"),
+                    Err(err) => Err(err),
+                }
+            }
+        };
+        match res {
+            Ok(()) => {}
+            Err(err) if key.typ.is_some() && is_server_error(&err) && !is_transient_error(&err) => {
+                warn!(
+                    "Listing key '{}' with incomplete attributes as GET failed: {}",
+                    **key_id, err
+                );
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    key.into_key(key_id)
+}
+
+fn get_key(
+    client: &KmipClient,
+    settings: &RequestSettings,
+    key_id: &UniqueIdentifier,
+    wanted: &KeyAttributes,
+) -> Result<Key> {
+    let res = message::do_request(client, settings, wanted.request(settings, key_id))?;
+    let key = key_attributes_from_response(res, wanted.clone())?;
+    complete_key(client, settings, key_id, key)
+}
+
+/// Use the highest protocol version supported by both keyls and the server, or KMIP 1.0 if the server is too old to
+/// say which versions it supports.
+fn negotiate_version(
+    client: &KmipClient,
+    conn_settings: &ConnectionSettings,
+) -> Result<KmipVersion> {
+    // Discover Versions was introduced in KMIP 1.1
+    let settings = RequestSettings::new(conn_settings, KmipVersion::V1_1);
+    match message::discover_versions(client, &settings, &KmipVersion::SUPPORTED) {
+        Ok(versions) => {
+            debug!(
+                "Server supports KMIP versions: {}",
+                versions
+                    .iter()
+                    .map(|version| version.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+            // The server lists the versions that it supports in order of preference
+            match versions
+                .into_iter()
+                .find(|version| KmipVersion::SUPPORTED.contains(version))
+            {
+                Some(version) => Ok(version),
+                None => Err(ClassifiedError::new(
+                    ErrorClass::Server,
+                    "The server supports none of the KMIP versions supported by keyls",
+                )
+                .into()),
+            }
+        }
+        Err(err) if is_connection_error(&err) => Err(err),
+        Err(err) => {
+            debug!("Discover Versions failed, assuming KMIP 1.0: {}", err);
+            Ok(KmipVersion::V1_0)
+        }
+    }
+}
+
+impl TryFrom<&Opt> for ConnectionSettings {
     type Error = anyhow::Error;
 
-    fn try_from(opt: Opt) -> Result<Self> {
+    fn try_from(opt: &Opt) -> Result<Self> {
         if let ServerOpt::Kmip(server_opt) = &opt.server {
-            let client_cert = load_client_cert(&opt)?;
+            let client_cert = load_client_cert(opt)?;
 
-            let server_cert = if let Some(p) = opt.server_cert_path {
-                Some(load_binary_file(&p)?)
+            let server_cert = if let Some(p) = &opt.server_cert_path {
+                Some(load_binary_file(p)?)
             } else {
                 None
             };
-            let ca_cert = if let Some(p) = opt.ca_cert_path {
-                Some(load_binary_file(&p)?)
+            let ca_cert = if let Some(p) = &opt.ca_cert_path {
+                Some(load_binary_file(p)?)
             } else {
                 None
             };
@@ -128,10 +1007,10 @@ impl TryFrom<Opt> for ConnectionSettings {
                 client_cert,
                 server_cert,
                 ca_cert,
-                connect_timeout: Some(Duration::from_secs(5)),
-                read_timeout: Some(Duration::from_secs(5)),
-                write_timeout: Some(Duration::from_secs(5)),
-                max_response_bytes: None,
+                connect_timeout: non_zero(opt.connect_timeout),
+                read_timeout: non_zero(opt.read_timeout),
+                write_timeout: non_zero(opt.write_timeout),
+                max_response_bytes: opt.max_response_bytes,
             })
         } else {
             bail!("Expected KMIP settings")
@@ -139,6 +1018,11 @@ impl TryFrom<Opt> for ConnectionSettings {
     }
 }
 
+fn non_zero(timeout: Duration) -> Option<Duration> {
+    // A zero timeout is rejected by the O/S so treat it as meaning no timeout
+    Some(timeout).filter(|timeout| !timeout.is_zero())
+}
+
 fn load_client_cert(opt: &Opt) -> Result<Option<ClientCertificate>> {
     let client_cert = {
         match (
@@ -158,7 +1042,7 @@ fn load_client_cert(opt: &Opt) -> Result<Option<ClientCertificate>> {
                 bail!("Client certificate key path requires a client certificate path")
             }
             (_, Some(_), Some(_)) | (Some(_), _, Some(_)) => {
-                bail!("Use either but not both of: client certificate and key PEM file paths, or a PCKS#12 certficate file path")
+                bail!("Use either but not both of: client certificate and key file paths, or a PCKS#12 certficate file path")
             }
             (Some(cert_path), Some(key_path), None) => Some(ClientCertificate::SeparatePem {
                 cert_bytes: load_binary_file(cert_path)?,
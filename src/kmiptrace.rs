@@ -0,0 +1,215 @@
+//! Wire level tracing of the KMIP messages exchanged with the server, enabled by `--trace-kmip`.
+use std::{
+    fmt::Write as _,
+    io::{Read, Write},
+    path::PathBuf,
+};
+
+use anyhow::Result;
+use kmip::tag_map::make_kmip_tag_map;
+use kmip_ttlv::PrettyPrinter;
+
+use crate::util::open_trace_output;
+
+/// The size of the tag, type and length fields that precede the value of every TTLV item.
+const TTLV_HEADER_LEN: usize = 8;
+
+/// The TTLV item type of a structure, whose value is the items that it contains.
+const TTLV_TYPE_STRUCTURE: u8 = 0x01;
+
+/// The tag of the Credential Value of the Authentication of a request, e.g. a username and password.
+const TAG_CREDENTIAL_VALUE: u32 = 0x420025;
+
+/// A stream which passes data through unchanged, dumping each complete TTLV message written to or read from it if
+/// tracing is enabled.
+///
+/// Messages are identified by the length in their TTLV header rather than by how they are written or read, so that
+/// every message is traced whether it is sent by the kmip-protocol crate or by keyls itself.
+pub(crate) struct TracingStream<S> {
+    inner: S,
+    tracer: Option<Tracer>,
+}
+
+struct Tracer {
+    out: Box<dyn Write + Send>,
+    printer: PrettyPrinter,
+    written: Vec<u8>,
+    read: Vec<u8>,
+}
+
+impl<S> TracingStream<S> {
+    /// Wrap the given stream, tracing to stderr if `trace` is `Some(None)` or appending to the given file if it is
+    /// `Some(Some(path))`.
+    pub(crate) fn new(inner: S, trace: Option<&Option<PathBuf>>) -> Result<Self> {
+        let out = match trace {
+            Some(path) => Some(open_trace_output(path.as_deref())?),
+            None => None,
+        };
+
+        Ok(Self {
+            inner,
+            tracer: out.map(|out| {
+                let mut printer = PrettyPrinter::new();
+                printer.with_tag_map(make_kmip_tag_map());
+                Tracer {
+                    out,
+                    printer,
+                    written: Vec::new(),
+                    read: Vec::new(),
+                }
+            }),
+        })
+    }
+}
+
+impl<S: Read> Read for TracingStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        if let Some(tracer) = &mut self.tracer {
+            tracer.read.extend_from_slice(&buf[..len]);
+            while let Some(msg) = take_message(&mut tracer.read) {
+                tracer.dump("response from", &msg);
+            }
+        }
+        Ok(len)
+    }
+}
+
+impl<S: Write> Write for TracingStream<S> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        if let Some(tracer) = &mut self.tracer {
+            tracer.written.extend_from_slice(&buf[..len]);
+            while let Some(msg) = take_message(&mut tracer.written) {
+                tracer.dump("request to", &msg);
+            }
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Tracer {
+    fn dump(&mut self, direction: &str, msg: &[u8]) {
+        let mut msg = msg.to_vec();
+        redact(&mut msg, false);
+        let msg = &msg[..];
+        let mut report = format!("KMIP {} server ({} bytes):\n", direction, msg.len());
+        for (idx, line) in msg.chunks(16).enumerate() {
+            let _ = writeln!(report, "  {:08x}  {}", idx * 16, hex::encode_upper(line));
+        }
+        for line in self.printer.to_string(msg).lines() {
+            // kmip-ttlv v0.3 prints the indentation depth of each line in place of the indentation itself
+            let line = line.trim_start();
+            let rest = line.trim_start_matches(|c: char| c.is_ascii_digit());
+            let depth = line[..line.len() - rest.len()].parse().unwrap_or(0);
+            let _ = writeln!(report, "  {:depth$}{}", "", rest, depth = depth);
+        }
+
+        // Tracing is a diagnostic aid so a failure to write the trace must not break the connection
+        let _ = self.out.write_all(report.as_bytes());
+        let _ = self.out.flush();
+    }
+}
+
+/// Remove and return the first message from the buffer if it has been received in full.
+fn take_message(buf: &mut Vec<u8>) -> Option<Vec<u8>> {
+    if buf.len() < TTLV_HEADER_LEN {
+        return None;
+    }
+    let value_len = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]) as usize;
+    let msg_len = TTLV_HEADER_LEN + value_len;
+    if buf.len() < msg_len {
+        return None;
+    }
+    Some(buf.drain(..msg_len).collect())
+}
+
+/// Overwrite with `*` characters the values within each Credential Value of the given TTLV items, or all of their values
+/// if they are within one, so that credentials such as a password are not traced.
+///
+/// The tags, types and lengths are left as they are so that the message can still be decoded.
+fn redact(items: &mut [u8], secret: bool) {
+    let mut pos = 0;
+    while items.len().saturating_sub(pos) >= TTLV_HEADER_LEN {
+        let header = &items[pos..pos + TTLV_HEADER_LEN];
+        let tag = u32::from_be_bytes([0, header[0], header[1], header[2]]);
+        let typ = header[3];
+        let len = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        let start = pos + TTLV_HEADER_LEN;
+        let end = start.saturating_add(len).min(items.len());
+        let secret = secret || tag == TAG_CREDENTIAL_VALUE;
+        if typ == TTLV_TYPE_STRUCTURE {
+            redact(&mut items[start..end], secret);
+        } else if secret {
+            items[start..end].fill(b'*');
+        }
+        // Values are padded to a multiple of 8 bytes
+        pos = start.saturating_add(len.div_ceil(8) * 8);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encode a TTLV item with the given tag, type and value, padding the value to a multiple of 8 bytes.
+    fn item(tag: u32, typ: u8, value: &[u8]) -> Vec<u8> {
+        let mut item = tag.to_be_bytes()[1..].to_vec();
+        item.push(typ);
+        item.extend_from_slice(&(value.len() as u32).to_be_bytes());
+        item.extend_from_slice(value);
+        item.resize(TTLV_HEADER_LEN + value.len().div_ceil(8) * 8, 0);
+        item
+    }
+
+    #[test]
+    fn redact_credentials() {
+        const TEXT: u8 = 0x07;
+        const ENUMERATION: u8 = 0x05;
+        let credential_value = [
+            item(0x420099, TEXT, b"user"),
+            item(0x4200A1, TEXT, b"secret password"),
+        ]
+        .concat();
+        let credential = [
+            item(0x420024, ENUMERATION, &1u32.to_be_bytes()),
+            item(TAG_CREDENTIAL_VALUE, TTLV_TYPE_STRUCTURE, &credential_value),
+        ]
+        .concat();
+        let header = [
+            item(
+                0x420069,
+                TTLV_TYPE_STRUCTURE,
+                &item(0x42006A, 0x02, &1u32.to_be_bytes()),
+            ),
+            item(
+                0x42000C,
+                TTLV_TYPE_STRUCTURE,
+                &item(0x420023, TTLV_TYPE_STRUCTURE, &credential),
+            ),
+        ]
+        .concat();
+        let msg = item(
+            0x420078,
+            TTLV_TYPE_STRUCTURE,
+            &item(0x420077, TTLV_TYPE_STRUCTURE, &header),
+        );
+
+        let mut redacted = msg.clone();
+        redact(&mut redacted, false);
+        assert_eq!(redacted.len(), msg.len());
+        let expected = [
+            item(0x420099, TEXT, b"****"),
+            item(0x4200A1, TEXT, b"***************"),
+        ]
+        .concat();
+        let start = msg.len() - credential_value.len();
+        assert_eq!(&redacted[start..], &expected[..]);
+        // Everything else, including the Credential Type, is left as it is
+        assert_eq!(&redacted[..start], &msg[..start]);
+    }
+}
@@ -0,0 +1,68 @@
+//! List the keys held by cryptographic tokens, i.e. KMIP servers, PKCS#11 tokens and key stores supported by plugins.
+//!
+//! [connect] to a [KeyStore] using the same options as the `keyls` command line tool accepts and then list the keys
+//! that it holds:
+//!
+//! ```no_run
+//! use clap::StructOpt;
+//! use keyls::config::Opt;
+//!
+//! let opt = Opt::try_parse_from(["keyls", "kmip:kmip.example.com"])?;
+//! for key in keyls::connect(&opt)?.list_keys()? {
+//!     println!("{} {}", key.id, key.name);
+//! }
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! Or use [iter_keys] to process each key as soon as it has been retrieved, stopping whenever enough keys have been
+//! seen.
+//!
+//! Enable the `async` feature for an async variant of this API, see [async_api].
+mod algorithm;
+#[cfg(feature = "async")]
+pub mod async_api;
+mod cert;
+pub mod config;
+pub mod csr;
+pub mod error;
+mod iter;
+pub mod key;
+mod kmipclient;
+mod kmiptrace;
+mod message;
+mod pkcs11client;
+mod pkcs11raw;
+mod pkcs11trace;
+mod plugin;
+mod proxy;
+pub mod randomness;
+mod ratelimit;
+mod retry;
+mod source;
+pub mod timings;
+mod tls;
+mod util;
+
+use anyhow::Result;
+
+use crate::config::{Opt, ServerOpt};
+
+pub use crate::{
+    iter::{iter_keys, KeyIter},
+    kmipclient::KmipConnection,
+    pkcs11client::Pkcs11Connection,
+    plugin::PluginSource,
+    source::{Capabilities, KeySource, SignatureAlgorithm, WrappedKey},
+};
+
+/// A connection to a KMIP server, to one or more PKCS#11 tokens or to a plugin key store.
+pub type KeyStore<'a> = Box<dyn KeySource<'a> + 'a>;
+
+/// Connect to the key store identified by the server option.
+pub fn connect(opt: &Opt) -> Result<KeyStore<'_>> {
+    Ok(match &opt.server {
+        ServerOpt::Kmip(_) => Box::new(KmipConnection::connect(opt)?),
+        ServerOpt::Pkcs11(_) => Box::new(Pkcs11Connection::connect(opt)?),
+        ServerOpt::Plugin(_) => Box::new(PluginSource::connect(opt)?),
+    })
+}
@@ -1,41 +1,369 @@
-#[macro_use]
-extern crate prettytable;
+mod output;
+mod syslog;
 
-mod config;
-mod key;
-mod kmipclient;
-mod pkcs11client;
-mod util;
+use std::{
+    fs::OpenOptions,
+    io::{IsTerminal, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Instant,
+};
 
-use anyhow::Result;
-use prettytable::{format, row, Table};
-use clap::StructOpt;
+use anyhow::{Context, Result};
+use clap::{CommandFactory, FromArgMatches};
+use keyls::{
+    config::{Command, Opt, OutputFormat},
+    error::{explanation, ClassifiedError, ErrorClass, ErrorReport, KeyError},
+    randomness::Statistics,
+};
+use tracing_subscriber::EnvFilter;
 
-use crate::config::{Opt, ServerOpt};
+use crate::{output::KeyPrinter, syslog::SyslogMakeWriter};
 
-fn main() -> Result<()> {
-    env_logger::init();
-    
-    let opt = Opt::from_args();
+/// Exit codes, documented in the README. Argument errors use the same exit code as clap does for invalid arguments.
+const EXIT_FAILURE: i32 = 1;
+const EXIT_ARGUMENT: i32 = 2;
+const EXIT_CONNECTION: i32 = 3;
+const EXIT_AUTHENTICATION: i32 = 4;
+const EXIT_PARTIAL_FAILURE: i32 = 5;
+const EXIT_NO_KEYS: i32 = 6;
+const EXIT_EXPIRING: i32 = 7;
 
-    let keys = match &opt.server {
-        ServerOpt::Kmip(_) => kmipclient::get_keys(opt)?,
-        ServerOpt::Pkcs11(_) => pkcs11client::get_keys(opt)?,
+fn main() {
+    // Parse in two steps as --schema is valid without the server that every Opt has
+    let matches = Opt::command().get_matches();
+    if matches.is_present("schema") {
+        output::print_schema();
+        return;
+    }
+    let opt = Opt::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+
+    if let Err(err) = init_logging(&opt) {
+        report_error(&err, &opt);
+        std::process::exit(exit_code(&err));
+    }
+
+    if let Some(command) = &opt.command {
+        match run_command(command, &opt) {
+            Ok((processed, failures)) if !failures.is_empty() => {
+                report_failures(&failures, "processed", processed, &opt);
+                std::process::exit(EXIT_PARTIAL_FAILURE);
+            }
+            Ok(_) => {}
+            Err(err) => {
+                report_error(&err, &opt);
+                std::process::exit(exit_code(&err));
+            }
+        }
+        return;
+    }
+
+    let printer = KeyPrinter::new(&opt);
+    let code = match list_keys(&opt, &printer) {
+        Ok(failures) if !failures.is_empty() => {
+            let listed = printer.count();
+            printer.finish();
+            report_failures(&failures, "listed", listed, &opt);
+            EXIT_PARTIAL_FAILURE
+        }
+        Ok(_) if opt.fail_if_empty && printer.count() == 0 => {
+            printer.finish();
+            EXIT_NO_KEYS
+        }
+        Ok(_) if opt.fail_if_expiring && printer.expiring_count() > 0 => {
+            printer.finish();
+            EXIT_EXPIRING
+        }
+        Ok(_) => {
+            printer.finish();
+            return;
+        }
+        Err(err) => {
+            // Output the keys that were retrieved before the failure so that the output is not lost
+            let partial = printer.count() > 0;
+            if partial {
+                printer.finish();
+            }
+            report_error(&err, &opt);
+            if partial {
+                EXIT_PARTIAL_FAILURE
+            } else {
+                exit_code(&err)
+            }
+        }
     };
+    std::process::exit(code);
+}
 
-    if keys.is_empty() {
-        println!("No keys found");
-    } else {
-        println!("Found {} keys", keys.len());
-        let mut table = Table::new();
-        table.set_format(*format::consts::FORMAT_NO_LINESEP_WITH_TITLE);
-        table.set_titles(row!["ID", "Type", "Name", "Algorithm", "Length"]);
-        for key in keys {
-            table.add_row(row![key.id, key.typ, key.name, key.alg, key.len]);
+fn exit_code(err: &anyhow::Error) -> i32 {
+    match ErrorClass::of(err) {
+        ErrorClass::Argument | ErrorClass::NotFound => EXIT_ARGUMENT,
+        ErrorClass::Connection | ErrorClass::Tls => EXIT_CONNECTION,
+        ErrorClass::Authentication => EXIT_AUTHENTICATION,
+        ErrorClass::Server | ErrorClass::Internal => EXIT_FAILURE,
+    }
+}
+
+fn list_keys(opt: &Opt, printer: &KeyPrinter) -> Result<Vec<KeyError>> {
+    let on_key = |key| printer.print(key);
+    let start = Instant::now();
+    let mut store = keyls::connect(opt)?;
+    let connect = start.elapsed();
+    if opt.all_slots && !store.capabilities().multiple_tokens {
+        return Err(ClassifiedError::new(
+            ErrorClass::Argument,
+            "--all-slots is not supported by this kind of server",
+        )
+        .into());
+    }
+    let res = store.get_keys(&on_key);
+    if opt.timings {
+        let report = store.timings().report(connect);
+        match opt.format {
+            OutputFormat::Text => eprintln!("{}", report),
+            OutputFormat::Json => eprintln!("{}", serde_json::json!({ "timings": report })),
+        }
+    }
+    res
+}
+
+/// Run the given command, returning the number of keys that it processed and the keys that it failed to process if it
+/// was given more than one key ID, see [for_each_id].
+fn run_command(command: &Command, opt: &Opt) -> Result<(usize, Vec<KeyError>)> {
+    let mut store = keyls::connect(opt)?;
+    let res = match command {
+        Command::GenCsr { id, subject, out } => {
+            let mut csrs = String::new();
+            let res = for_each_id(id, |id| {
+                csrs.push_str(&keyls::csr::gen_csr(&mut store, id, subject)?);
+                Ok(())
+            })?;
+            if !csrs.is_empty() {
+                match out {
+                    Some(path) => std::fs::write(path, csrs)
+                        .with_context(|| format!("Cannot write '{}'", path.display()))?,
+                    None => print!("{}", csrs),
+                }
+            }
+            res
+        }
+        Command::ImportCert { id, cert } => {
+            let cert = std::fs::read(cert)
+                .with_context(|| format!("Cannot read '{}'", cert.display()))?;
+            println!("{}", keyls::csr::import_cert(&mut store, id, &cert)?);
+            (1, vec![])
+        }
+        Command::ExportWrapped {
+            id,
+            wrapping_key,
+            out,
+        } => {
+            let many = id == "-";
+            if many && !out.is_dir() {
+                return Err(ClassifiedError::new(
+                    ErrorClass::Argument,
+                    format!(
+                        "With the ID - the --out path '{}' must be an existing directory",
+                        out.display()
+                    ),
+                )
+                .into());
+            }
+            for_each_id(id, |id| {
+                let path = if many {
+                    out.join(id_file_name(id)?)
+                } else {
+                    out.clone()
+                };
+                export_wrapped(&mut store, id, wrapping_key, &path)
+            })?
+        }
+        Command::Random { bytes, out, stats } => {
+            let data = store.random(*bytes)?;
+            match out {
+                Some(path) => std::fs::write(path, &data)
+                    .with_context(|| format!("Cannot write '{}'", path.display()))?,
+                None => println!("{}", hex::encode(&data)),
+            }
+            if *stats {
+                let stats = Statistics::of(&data);
+                match opt.format {
+                    OutputFormat::Text => eprintln!("{}", stats),
+                    OutputFormat::Json => eprintln!("{}", serde_json::json!({ "stats": stats })),
+                }
+                let failures = stats.failures();
+                if !failures.is_empty() {
+                    return Err(ClassifiedError::new(
+                        ErrorClass::Server,
+                        format!(
+                            "The random data failed the sanity tests: {}",
+                            failures.join(", ")
+                        ),
+                    )
+                    .into());
+                }
+            }
+            (0, vec![])
         }
+    };
+    Ok(res)
+}
 
-        table.printstd();
+/// Perform an operation on the key with the given ID or, if the ID is `-`, on each key whose ID is given on a line of
+/// stdin, e.g. as output by `keyls --format json | jq -r '.[].id'`.
+///
+/// When the IDs are read from stdin a failed operation does not stop the remaining keys from being processed, but is
+/// returned with the number of keys that were processed.
+fn for_each_id(id: &str, mut f: impl FnMut(&str) -> Result<()>) -> Result<(usize, Vec<KeyError>)> {
+    if id != "-" {
+        f(id)?;
+        return Ok((1, vec![]));
     }
+    let ids = std::io::stdin()
+        .lines()
+        .collect::<std::io::Result<Vec<_>>>()
+        .context("Cannot read key IDs from stdin")?;
+    let ids: Vec<&str> = ids
+        .iter()
+        .map(|id| id.trim())
+        .filter(|id| !id.is_empty())
+        .collect();
+    if ids.is_empty() {
+        return Err(
+            ClassifiedError::new(ErrorClass::Argument, "No key IDs were given on stdin").into(),
+        );
+    }
+    let mut failures = Vec::new();
+    for id in &ids {
+        if let Err(err) = f(id) {
+            failures.push(KeyError::new(format!("Key '{}'", id), err));
+        }
+    }
+    Ok((ids.len() - failures.len(), failures))
+}
+
+/// The name of the file to export the key with the given ID to when exporting more than one key.
+fn id_file_name(id: &str) -> Result<&str> {
+    if id == "." || id == ".." || id.contains(['/', '\\']) {
+        return Err(ClassifiedError::new(
+            ErrorClass::Argument,
+            format!("The ID '{}' cannot be used as a file name", id),
+        )
+        .into());
+    }
+    Ok(id)
+}
+
+/// Export the key with the given ID wrapped by the given wrapping key to the given path, with its metadata written to
+/// the same path with .json appended.
+fn export_wrapped(
+    store: &mut keyls::KeyStore<'_>,
+    id: &str,
+    wrapping_key: &str,
+    out: &Path,
+) -> Result<()> {
+    let wrapped = store.export_wrapped(id, wrapping_key)?;
+    let mut metadata_path = out.to_path_buf().into_os_string();
+    metadata_path.push(".json");
+    let metadata_path = PathBuf::from(metadata_path);
+    // Never overwrite an earlier export
+    let create = |path: &Path| {
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .with_context(|| format!("Cannot create '{}'", path.display()))
+    };
+    let mut blob_file = create(out)?;
+    let mut metadata_file = create(&metadata_path).inspect_err(|_| {
+        let _ = std::fs::remove_file(out);
+    })?;
+    blob_file.write_all(&wrapped.blob)?;
+    writeln!(metadata_file, "{}", serde_json::to_string(&wrapped)?)?;
+    Ok(())
+}
 
+fn report_error(err: &anyhow::Error, opt: &Opt) {
+    match opt.format {
+        OutputFormat::Text => {
+            eprintln!("Error: {:?}", err);
+            if let Some(explanation) = explanation(err) {
+                eprintln!("\n{}", explanation);
+            }
+        }
+        OutputFormat::Json => eprintln!(
+            "{}",
+            serde_json::json!({ "error": ErrorReport::new(err, opt) })
+        ),
+    }
+}
+
+/// Summarise the keys that could not be retrieved, or processed by a command, and how many were.
+fn report_failures(failures: &[KeyError], verb: &str, done: usize, opt: &Opt) {
+    match opt.format {
+        OutputFormat::Text => {
+            let (first, rest) = verb.split_at(1);
+            eprintln!(
+                "{}{} {} keys, {} failed:",
+                first.to_uppercase(),
+                rest,
+                done,
+                failures.len()
+            );
+            for failure in failures {
+                eprintln!("  {}", failure);
+            }
+        }
+        OutputFormat::Json => {
+            let failures = failures
+                .iter()
+                .map(|failure| {
+                    serde_json::json!({
+                        "key": failure.what,
+                        "error": ErrorReport::new(&failure.error, opt),
+                    })
+                })
+                .collect::<Vec<_>>();
+            eprintln!(
+                "{}",
+                serde_json::json!({ verb: done, "failures": failures })
+            )
+        }
+    }
+}
+
+/// Log to stderr, or the log file or syslog if requested, at a level chosen by the number of -v flags given, or if none
+/// were given as configured by the RUST_LOG environment variable, defaulting to errors only.
+fn init_logging(opt: &Opt) -> Result<()> {
+    let filter = match opt.verbose {
+        0 => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("error")),
+        1 => EnvFilter::new("info"),
+        2 => EnvFilter::new("debug"),
+        _ => EnvFilter::new("trace"),
+    };
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    if let Some(path) = &opt.log_file {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Cannot open log file '{}'", path.display()))?;
+        builder
+            .with_writer(Mutex::new(file))
+            .with_ansi(false)
+            .init();
+    } else if opt.log_syslog {
+        // syslog timestamps each message itself
+        builder
+            .with_writer(SyslogMakeWriter::connect()?)
+            .with_ansi(false)
+            .without_time()
+            .init();
+    } else {
+        builder
+            .with_writer(std::io::stderr)
+            .with_ansi(std::io::stderr().is_terminal())
+            .init();
+    }
     Ok(())
 }
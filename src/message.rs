@@ -0,0 +1,1485 @@
+//! KMIP request messages which the kmip-protocol crate cannot build itself, e.g. because they use a protocol version
+//! other than the one that it always uses for the operation, or because they use the KMIP 2.x message structures.
+//!
+//! These are written to and their responses read from the underlying stream of the client directly.
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+    sync::OnceLock,
+};
+
+use anyhow::{bail, Result};
+use kmip::{
+    auth::{CredentialType, UsernameAndPasswordCredential},
+    client::{ConnectionSettings, Error},
+    tag_map::make_kmip_tag_map,
+    types::{
+        common::{
+            AttributeName, CertificateType, CryptographicParameters, LinkType,
+            LinkedObjectIdentifier, ObjectType, Operation, UniqueBatchItemID, UniqueIdentifier,
+        },
+        request::{
+            Attribute, Authentication, BatchCount, EncryptionKeyInformation, ProtocolVersion,
+            ProtocolVersionMajor, ProtocolVersionMinor, RequestHeader, RequestPayload,
+            WrappingMethod,
+        },
+        response::{self, ResponseMessage, ResponsePayload, ResultReason, ResultStatus},
+    },
+};
+use kmip_ttlv::types::TtlvTag;
+use serde::Serialize;
+
+use crate::{config::KmipVersion, key::SplitKey, tls::KmipClient};
+
+/// What to include in the header of every request message, and how to read the response.
+#[derive(Clone)]
+pub(crate) struct RequestSettings {
+    pub auth: Option<Authentication>,
+    pub max_response_bytes: Option<u32>,
+    pub version: KmipVersion,
+}
+
+impl RequestSettings {
+    pub(crate) fn new(conn_settings: &ConnectionSettings, version: KmipVersion) -> Self {
+        Self {
+            auth: authentication(conn_settings),
+            max_response_bytes: conn_settings.max_response_bytes,
+            version,
+        }
+    }
+
+    fn header(&self, batch_count: usize) -> RequestHeader {
+        RequestHeader(
+            protocol_version(self.version),
+            None,
+            self.auth.clone(),
+            BatchCount(batch_count as i32),
+        )
+    }
+}
+
+fn protocol_version(version: KmipVersion) -> ProtocolVersion {
+    ProtocolVersion(
+        ProtocolVersionMajor(version.major),
+        ProtocolVersionMinor(version.minor),
+    )
+}
+
+/// Build the authentication header to include in requests, following the same rules as the kmip-protocol crate uses
+/// for the requests that it builds.
+fn authentication(conn_settings: &ConnectionSettings) -> Option<Authentication> {
+    match (&conn_settings.username, &conn_settings.password) {
+        (Some(username), Some(password)) => {
+            Some(Authentication::build(CredentialType::UsernameAndPassword(
+                UsernameAndPasswordCredential::new(username.clone(), Some(password.clone())),
+            )))
+        }
+        _ => None,
+    }
+}
+
+/// A request payload in the form required by the protocol version in use.
+#[derive(Clone)]
+#[allow(clippy::large_enum_variant)]
+pub(crate) enum Request {
+    /// A request payload built by the kmip-protocol crate, which only knows the KMIP 1.x message structures.
+    Payload(RequestPayload),
+    Locate(LocateRequestPayload),
+    GetAttributes(GetAttributesRequestPayload),
+    Register(RegisterRequestPayload),
+    GetWrapped(GetWrappedRequestPayload),
+}
+
+impl Request {
+    fn operation(&self) -> Operation {
+        match self {
+            Request::Payload(payload) => payload.operation(),
+            Request::Locate(_) => Operation::Locate,
+            Request::GetAttributes(_) => Operation::GetAttributes,
+            Request::Register(_) => Operation::Register,
+            Request::GetWrapped(_) => Operation::Get,
+        }
+    }
+}
+
+impl From<RequestPayload> for Request {
+    fn from(payload: RequestPayload) -> Self {
+        Request::Payload(payload)
+    }
+}
+
+impl Serialize for Request {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Request::Payload(payload) => payload.serialize(serializer),
+            Request::Locate(payload) => payload.serialize(serializer),
+            Request::GetAttributes(payload) => payload.serialize(serializer),
+            Request::Register(payload) => payload.serialize(serializer),
+            Request::GetWrapped(payload) => payload.serialize(serializer),
+        }
+    }
+}
+
+/// Request the named attributes of an object, e.g. "Cryptographic Algorithm".
+pub(crate) fn get_attributes(
+    settings: &RequestSettings,
+    key_id: &UniqueIdentifier,
+    names: &[&str],
+) -> Request {
+    if settings.version.has_tagged_attributes() {
+        // Names that are neither are rejected by [can_reference_attribute] before any request is made
+        let references = names
+            .iter()
+            .filter_map(|name| attribute_tag(name))
+            .map(AttributeReference)
+            .collect();
+        let vendor_references = names
+            .iter()
+            .filter(|name| attribute_tag(name).is_none())
+            .filter_map(|name| vendor_attribute(name))
+            .map(|(vendor, name)| {
+                VendorAttributeReference(
+                    VendorIdentification(vendor.to_string()),
+                    AttributeName(name.to_string()),
+                )
+            })
+            .collect();
+        Request::GetAttributes(GetAttributesRequestPayload(
+            key_id.clone(),
+            references,
+            vendor_references,
+        ))
+    } else {
+        let names = names
+            .iter()
+            .map(|name| AttributeName(name.to_string()))
+            .collect();
+        RequestPayload::GetAttributes(Some(key_id.clone()), Some(names)).into()
+    }
+}
+
+/// Can the named attribute be requested with KMIP 2.x, which identifies attributes by tag rather than by name? Only
+/// standard attributes have a tag, but a KMIP 1.x custom attribute such as `x-Owner` can be requested as the Vendor
+/// Attribute of the vendor before the first `-` with the name after it.
+pub(crate) fn can_reference_attribute(name: &str) -> bool {
+    attribute_tag(name).is_some() || vendor_attribute(name).is_some()
+}
+
+/// The vendor identification and attribute name of the Vendor Attribute with the given KMIP 1.x custom attribute
+/// name, e.g. `x` and `Owner` for `x-Owner`.
+fn vendor_attribute(name: &str) -> Option<(&str, &str)> {
+    name.split_once('-')
+        .filter(|(vendor, name)| !vendor.is_empty() && !name.is_empty())
+}
+
+/// Locate the objects of the given type, or all objects if no type is given.
+pub(crate) fn locate(
+    client: &KmipClient,
+    settings: &RequestSettings,
+    object_type: Option<ObjectType>,
+) -> Result<Vec<UniqueIdentifier>> {
+    let payload = locate_request(settings, object_type, None, 0);
+    match do_request(client, settings, payload)? {
+        ResponsePayload::Locate(res) => Ok(res.unique_identifiers),
+        _ => bail!("Unexpected response payload"),
+    }
+}
+
+fn locate_request(
+    settings: &RequestSettings,
+    object_type: Option<ObjectType>,
+    max_items: Option<i32>,
+    offset: i32,
+) -> Request {
+    // The Attributes structure is required by KMIP 2.x even when empty
+    let (attributes, tagged_attributes) = if settings.version.has_tagged_attributes() {
+        (vec![], Some(Attributes(object_type.into_iter().collect())))
+    } else {
+        (
+            object_type.into_iter().map(Attribute::ObjectType).collect(),
+            None,
+        )
+    };
+    Request::Locate(LocateRequestPayload(
+        max_items.map(MaximumItems),
+        Some(OffsetItems(offset)).filter(|_| offset > 0),
+        attributes,
+        tagged_attributes,
+    ))
+}
+
+/// Send a single KMIP request to the server.
+pub(crate) fn do_request(
+    client: &KmipClient,
+    settings: &RequestSettings,
+    request: impl Into<Request>,
+) -> Result<ResponsePayload> {
+    let request = request.into();
+    let message = RequestMessage(
+        settings.header(1),
+        vec![BatchItem(request.operation(), None, request)],
+    );
+    let mut res = send_message(client, &message, settings)?;
+
+    match res.batch_items.pop() {
+        Some(item) if res.batch_items.is_empty() => batch_item_result(item),
+        _ => bail!("Expected one batch item in response"),
+    }
+}
+
+/// Register a DER encoded X.509 certificate linked to its public key, returning the unique identifier of the new
+/// certificate object.
+pub(crate) fn register_certificate(
+    client: &KmipClient,
+    settings: &RequestSettings,
+    cert: &[u8],
+    public_key_id: &UniqueIdentifier,
+) -> Result<UniqueIdentifier> {
+    let link_type = LinkType::PublicKeyLink;
+    let linked_id = LinkedObjectIdentifier(public_key_id.to_string());
+    let (template_attribute, attributes) = if settings.version.has_tagged_attributes() {
+        (None, Some(RegisterAttributes(Link(link_type, linked_id))))
+    } else {
+        let link = LinkAttribute(
+            AttributeName("Link".to_string()),
+            LinkValue(link_type, linked_id),
+        );
+        (Some(TemplateAttribute(vec![link])), None)
+    };
+    let payload = RegisterRequestPayload(
+        ObjectType::Certificate,
+        template_attribute,
+        attributes,
+        Certificate(CertificateType::X509, CertificateValue(cert.to_vec())),
+    );
+    match do_request(client, settings, Request::Register(payload))? {
+        ResponsePayload::Register(res) => Ok(res.unique_identifier),
+        _ => bail!("Unexpected response payload"),
+    }
+}
+
+/// The details of a managed object which the kmip-protocol crate cannot deserialize, i.e. Secret Data, an Opaque
+/// Object or a Split Key.
+#[derive(Default)]
+pub(crate) struct ObjectDetails {
+    /// The type of data held by Secret Data, e.g. a password, or by an Opaque Object.
+    pub(crate) data_type: Option<String>,
+    /// The length in bits, if known.
+    pub(crate) len: Option<i32>,
+    pub(crate) split_key: Option<SplitKey>,
+}
+
+/// Get a managed object which the kmip-protocol crate cannot deserialize, returning only its type, size and for a
+/// split key how it was split.
+pub(crate) fn get_object_details(
+    client: &KmipClient,
+    settings: &RequestSettings,
+    key_id: &UniqueIdentifier,
+) -> Result<ObjectDetails> {
+    let payload = RequestPayload::Get(Some(key_id.clone()), None, None, None);
+    get_raw(client, settings, payload, parse_object_details_payload)
+}
+
+/// A key block wrapped by another key, which the kmip-protocol crate cannot deserialize.
+pub(crate) struct WrappedKeyBlock {
+    /// The format of the key before it was wrapped, e.g. PKCS8.
+    pub(crate) format: String,
+    /// The wrapped key value.
+    pub(crate) blob: Vec<u8>,
+}
+
+/// Get a key encrypted by the wrapping key with the given cryptographic parameters.
+///
+/// Fails if the server returns the key unwrapped, so that the caller never mistakes an unwrapped key for a wrapped one.
+pub(crate) fn get_wrapped_key(
+    client: &KmipClient,
+    settings: &RequestSettings,
+    key_id: &UniqueIdentifier,
+    wrapping_key_id: &UniqueIdentifier,
+    params: CryptographicParameters,
+) -> Result<WrappedKeyBlock> {
+    let payload = GetWrappedRequestPayload(
+        key_id.clone(),
+        KeyWrappingSpecification(
+            WrappingMethod::Encrypt,
+            EncryptionKeyInformation(wrapping_key_id.clone(), Some(params)),
+        ),
+    );
+    get_raw(
+        client,
+        settings,
+        Request::GetWrapped(payload),
+        parse_wrapped_key_payload,
+    )
+}
+
+/// Send a single KMIP request whose response payload the kmip-protocol crate cannot deserialize, returning what the
+/// given function parses from the payload instead.
+fn get_raw<T>(
+    client: &KmipClient,
+    settings: &RequestSettings,
+    request: impl Into<Request>,
+    parse: fn(&[u8]) -> Result<Option<T>>,
+) -> Result<T> {
+    let request = request.into();
+    let message = RequestMessage(
+        settings.header(1),
+        vec![BatchItem(request.operation(), None, request)],
+    );
+    let res_bytes = exchange_message(client, &message, settings)?;
+
+    // The rest of the response is deserialized as usual without the payload, e.g. to report a failed request in the
+    // same way as for any other request
+    let mut stripped = Vec::with_capacity(res_bytes.len());
+    let parsed = take_payload(&res_bytes, &mut stripped, parse)?;
+    let mut res: ResponseMessage =
+        kmip_ttlv::from_slice(&stripped).map_err(|err| Error::DeserializeError(err.to_string()))?;
+    match res.batch_items.pop() {
+        Some(item) if res.batch_items.is_empty() => match (item.result_status, parsed) {
+            (ResultStatus::Success, Some(parsed)) => Ok(parsed),
+            (ResultStatus::Success, None) => bail!("Unexpected managed object in response"),
+            _ => batch_item_result(item).and_then(|_| bail!("Unexpected response payload")),
+        },
+        _ => bail!("Expected one batch item in response"),
+    }
+}
+
+/// Ask the server which of the given protocol versions it supports, returning them in its order of preference.
+pub(crate) fn discover_versions(
+    client: &KmipClient,
+    settings: &RequestSettings,
+    offered: &[KmipVersion],
+) -> Result<Vec<KmipVersion>> {
+    let payload =
+        RequestPayload::DiscoverVersions(offered.iter().copied().map(protocol_version).collect());
+    match do_request(client, settings, payload)? {
+        ResponsePayload::DiscoverVersions(res) => Ok(res
+            .supported_versions
+            .unwrap_or_default()
+            .into_iter()
+            .map(|version| KmipVersion::new(version.major, version.minor))
+            .collect()),
+        _ => bail!("Unexpected response payload"),
+    }
+}
+
+/// Send several KMIP requests to the server in a single request message with one batch item per request.
+///
+/// Returns the result of each request in the order given. A request that the server did not process, e.g. because an
+/// earlier batch item failed, results in an error.
+pub(crate) fn do_batch(
+    client: &KmipClient,
+    settings: &RequestSettings,
+    requests: Vec<Request>,
+) -> Result<Vec<Result<ResponsePayload>>> {
+    let num_items = requests.len();
+    if num_items == 0 {
+        return Ok(vec![]);
+    }
+    let batch_items = requests
+        .into_iter()
+        .enumerate()
+        .map(|(idx, request)| BatchItem(request.operation(), Some(batch_item_id(idx)), request))
+        .collect();
+    let message = RequestMessage(settings.header(num_items), batch_items);
+    let res = send_message(client, &message, settings)?;
+    // A server which does not support batching may respond with a single failed batch item for the whole message.
+    if num_items > 1
+        && res.batch_items.len() == 1
+        && res.batch_items[0].unique_batch_item_id.is_none()
+    {
+        let item = &res.batch_items[0];
+        bail!(
+            "Server rejected batched request: {}",
+            item.result_message.as_deref().unwrap_or("no reason given")
+        );
+    }
+
+    let mut results: HashMap<Vec<u8>, Result<ResponsePayload>> = HashMap::new();
+    for item in res.batch_items {
+        let id = match &item.unique_batch_item_id {
+            Some(id) => id.0.clone(),
+            None => bail!("Server response batch item lacks a unique batch item ID"),
+        };
+        results.insert(id, batch_item_result(item));
+    }
+
+    Ok((0..num_items)
+        .map(|idx| {
+            results
+                .remove(&batch_item_id(idx).0)
+                .unwrap_or_else(|| bail!("Server did not process batch item {}", idx))
+        })
+        .collect())
+}
+
+/// Locate at most `max_items` objects of the given type, or of any type if no type is given, skipping the first
+/// `offset` matches.
+///
+/// The Offset Items request field was introduced in KMIP 1.3 so this requires at least that protocol version.
+pub(crate) fn locate_page(
+    client: &KmipClient,
+    settings: &RequestSettings,
+    object_type: Option<ObjectType>,
+    max_items: i32,
+    offset: i32,
+) -> Result<Vec<UniqueIdentifier>> {
+    if settings.version < KmipVersion::V1_3 {
+        bail!(
+            "Locating keys a page at a time requires KMIP 1.3 or later but KMIP {} is in use",
+            settings.version
+        );
+    }
+    let payload = locate_request(settings, object_type, Some(max_items), offset);
+    match do_request(client, settings, payload)? {
+        ResponsePayload::Locate(res) => Ok(res.unique_identifiers),
+        _ => bail!("Unexpected response payload"),
+    }
+}
+
+/// Like the kmip-protocol crate request message but able to carry any [Request].
+#[derive(Serialize)]
+#[serde(rename = "0x420078")]
+struct RequestMessage(RequestHeader, Vec<BatchItem>);
+
+#[derive(Serialize)]
+#[serde(rename = "0x42000F")]
+struct BatchItem(
+    Operation,
+    #[serde(skip_serializing_if = "Option::is_none")] Option<UniqueBatchItemID>,
+    Request,
+);
+
+/// See KMIP 1.3 section 4.9 and KMIP 2.0 section 6.1.27 Locate.
+///
+/// KMIP 1.x identifies the objects to locate by a list of Attribute structures, KMIP 2.x by a single Attributes
+/// structure.
+#[derive(Clone, Serialize)]
+#[serde(rename = "0x420079")]
+pub(crate) struct LocateRequestPayload(
+    #[serde(skip_serializing_if = "Option::is_none")] Option<MaximumItems>,
+    #[serde(skip_serializing_if = "Option::is_none")] Option<OffsetItems>,
+    Vec<Attribute>,
+    #[serde(skip_serializing_if = "Option::is_none")] Option<Attributes>,
+);
+
+#[derive(Clone, Serialize)]
+#[serde(rename = "Transparent:0x42004F")]
+struct MaximumItems(i32);
+
+#[derive(Clone, Serialize)]
+#[serde(rename = "Transparent:0x4200D4")]
+struct OffsetItems(i32);
+
+/// See KMIP 2.0 section 2.1.2 Attributes, of which keyls only ever needs the Object Type.
+#[derive(Clone, Serialize)]
+#[serde(rename = "0x420125")]
+struct Attributes(Vec<ObjectType>);
+
+/// See KMIP 2.0 section 6.1.20 Get Attributes. The order of the attribute references does not matter, so those for
+/// vendor attributes follow those for standard attributes.
+#[derive(Clone, Serialize)]
+#[serde(rename = "0x420079")]
+pub(crate) struct GetAttributesRequestPayload(
+    UniqueIdentifier,
+    Vec<AttributeReference>,
+    Vec<VendorAttributeReference>,
+);
+
+/// See KMIP 2.0 section 2.1.3 Attribute Reference, which for a standard attribute is the tag of the attribute.
+#[derive(Clone, Serialize)]
+#[serde(rename = "Transparent:0x42013B")]
+struct AttributeReference(u32);
+
+/// See KMIP 2.0 section 2.1.3 Attribute Reference, which for a vendor attribute is the vendor identification and name
+/// of the attribute.
+#[derive(Clone, Serialize)]
+#[serde(rename = "0x42013B")]
+struct VendorAttributeReference(VendorIdentification, AttributeName);
+
+#[derive(Clone, Serialize)]
+#[serde(rename = "Transparent:0x42009D")]
+struct VendorIdentification(String);
+
+/// See KMIP 1.0 section 4.10 Get, of a key wrapped as given by a Key Wrapping Specification.
+#[derive(Clone, Serialize)]
+#[serde(rename = "0x420079")]
+pub(crate) struct GetWrappedRequestPayload(UniqueIdentifier, KeyWrappingSpecification);
+
+/// See KMIP 1.0 section 2.1.6 Key Wrapping Specification, which the kmip-protocol crate serializes with an invalid tag.
+#[derive(Clone, Serialize)]
+#[serde(rename = "0x420047")]
+struct KeyWrappingSpecification(WrappingMethod, EncryptionKeyInformation);
+
+/// See KMIP 1.0 section 4.3 and KMIP 2.0 section 6.1.40 Register, of which keyls only ever registers certificates.
+///
+/// KMIP 1.x gives the attributes of the new object by a Template-Attribute structure, KMIP 2.x by an Attributes
+/// structure.
+#[derive(Clone, Serialize)]
+#[serde(rename = "0x420079")]
+pub(crate) struct RegisterRequestPayload(
+    ObjectType,
+    #[serde(skip_serializing_if = "Option::is_none")] Option<TemplateAttribute>,
+    #[serde(skip_serializing_if = "Option::is_none")] Option<RegisterAttributes>,
+    Certificate,
+);
+
+/// See KMIP 1.0 section 2.1.8 Template-Attribute Structures, of which keyls only ever needs the Link.
+#[derive(Clone, Serialize)]
+#[serde(rename = "0x420091")]
+struct TemplateAttribute(Vec<LinkAttribute>);
+
+/// See KMIP 1.0 sections 2.1.1 Attribute and 3.29 Link.
+#[derive(Clone, Serialize)]
+#[serde(rename = "0x420008")]
+struct LinkAttribute(AttributeName, LinkValue);
+
+#[derive(Clone, Serialize)]
+#[serde(rename = "0x42000B")]
+struct LinkValue(LinkType, LinkedObjectIdentifier);
+
+/// See KMIP 2.0 section 2.1.2 Attributes, of which keyls only ever needs the Link when registering an object.
+#[derive(Clone, Serialize)]
+#[serde(rename = "0x420125")]
+struct RegisterAttributes(Link);
+
+/// See KMIP 2.0 section 4.22 Link.
+#[derive(Clone, Serialize)]
+#[serde(rename = "0x42004A")]
+struct Link(LinkType, LinkedObjectIdentifier);
+
+/// See KMIP 1.0 section 2.2.1 Certificate.
+#[derive(Clone, Serialize)]
+#[serde(rename = "0x420013")]
+struct Certificate(CertificateType, CertificateValue);
+
+/// A Byte String, which the kmip-ttlv crate only serializes as such if asked to serialize bytes rather than a Vec.
+#[derive(Clone)]
+struct CertificateValue(Vec<u8>);
+
+impl Serialize for CertificateValue {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        struct Bytes<'a>(&'a [u8]);
+        impl Serialize for Bytes<'_> {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.serialize_bytes(self.0)
+            }
+        }
+        serializer.serialize_newtype_struct("Transparent:0x42001E", &Bytes(&self.0))
+    }
+}
+
+fn send_message<T: Serialize>(
+    client: &KmipClient,
+    request: &T,
+    settings: &RequestSettings,
+) -> Result<ResponseMessage> {
+    let res_bytes = exchange_message(client, request, settings)?;
+    let res = kmip_ttlv::from_slice(&res_bytes)
+        .map_err(|err| Error::DeserializeError(err.to_string()))?;
+
+    Ok(res)
+}
+
+/// Send a request message and return the response message with its payloads normalized by [normalize_response].
+fn exchange_message<T: Serialize>(
+    client: &KmipClient,
+    request: &T,
+    settings: &RequestSettings,
+) -> Result<Vec<u8>> {
+    let req_bytes =
+        kmip_ttlv::to_vec(request).map_err(|err| Error::SerializeError(err.to_string()))?;
+
+    let stream = client.inner();
+    let mut stream = stream
+        .lock()
+        .map_err(|err| Error::InternalError(err.to_string()))?;
+    stream
+        .write_all(&req_bytes)
+        .map_err(|err| Error::RequestWriteError(err.to_string()))?;
+
+    // The length of the response is given by the header of its outermost TTLV item
+    let mut res_bytes = vec![0; 8];
+    stream
+        .read_exact(&mut res_bytes)
+        .map_err(|err| Error::ResponseReadError(err.to_string()))?;
+    let value_len = u32::from_be_bytes([res_bytes[4], res_bytes[5], res_bytes[6], res_bytes[7]]);
+    if let Some(max_bytes) = settings.max_response_bytes {
+        if value_len.saturating_add(8) > max_bytes {
+            return Err(Error::DeserializeError(format!(
+                "Response size {} bytes exceeds the limit of {} bytes",
+                value_len.saturating_add(8),
+                max_bytes
+            ))
+            .into());
+        }
+    }
+    res_bytes.resize(8 + value_len as usize, 0);
+    stream
+        .read_exact(&mut res_bytes[8..])
+        .map_err(|err| Error::ResponseReadError(err.to_string()))?;
+
+    let mut normalized = Vec::with_capacity(res_bytes.len());
+    normalize_response(&res_bytes, &mut normalized)?;
+
+    Ok(normalized)
+}
+
+/// The name that the Cryptographic Algorithm attribute has in normalized responses, see [normalize_response].
+pub(crate) const RAW_CRYPTOGRAPHIC_ALGORITHM: &str = "Cryptographic Algorithm (raw)";
+
+/// The name that the Link attribute has in normalized responses, see [normalize_response].
+pub(crate) const LINK: &str = "Linked Object Identifier";
+
+const TTLV_STRUCTURE: u8 = 0x01;
+const TTLV_INTEGER: u8 = 0x02;
+const TTLV_LONG_INTEGER: u8 = 0x03;
+const TTLV_ENUMERATION: u8 = 0x05;
+const TTLV_BYTE_STRING: u8 = 0x08;
+const TTLV_TEXT_STRING: u8 = 0x07;
+const TTLV_DATE_TIME: u8 = 0x09;
+
+const TAG_ATTRIBUTE: u32 = 0x420008;
+const TAG_ATTRIBUTE_NAME: u32 = 0x42000A;
+const TAG_ATTRIBUTE_VALUE: u32 = 0x42000B;
+const TAG_ATTRIBUTES: u32 = 0x420125;
+const TAG_BATCH_ITEM: u32 = 0x42000F;
+const TAG_CRYPTOGRAPHIC_LENGTH: u32 = 0x42002A;
+const TAG_KEY_BLOCK: u32 = 0x420040;
+const TAG_KEY_FORMAT_TYPE: u32 = 0x420042;
+const TAG_KEY_MATERIAL: u32 = 0x420043;
+const TAG_KEY_PART_IDENTIFIER: u32 = 0x420044;
+const TAG_KEY_VALUE: u32 = 0x420045;
+const TAG_KEY_WRAPPING_DATA: u32 = 0x420046;
+const TAG_LINK_TYPE: u32 = 0x42004B;
+const TAG_LOCATED_ITEMS: u32 = 0x4200D5;
+const TAG_OPAQUE_DATA_TYPE: u32 = 0x420059;
+const TAG_OPAQUE_DATA_VALUE: u32 = 0x42005A;
+const TAG_OPAQUE_OBJECT: u32 = 0x42005B;
+const TAG_PRIVATE_KEY: u32 = 0x420064;
+const TAG_PUBLIC_KEY: u32 = 0x42006D;
+const TAG_RESPONSE_MESSAGE: u32 = 0x42007B;
+const TAG_RESPONSE_PAYLOAD: u32 = 0x42007C;
+const TAG_SECRET_DATA: u32 = 0x420085;
+const TAG_SECRET_DATA_TYPE: u32 = 0x420086;
+const TAG_SPLIT_KEY: u32 = 0x420089;
+const TAG_SPLIT_KEY_METHOD: u32 = 0x42008A;
+const TAG_SPLIT_KEY_PARTS: u32 = 0x42008B;
+const TAG_SPLIT_KEY_THRESHOLD: u32 = 0x42008C;
+const TAG_SYMMETRIC_KEY: u32 = 0x42008F;
+const TAG_VENDOR_IDENTIFICATION: u32 = 0x42009D;
+
+/// Copy the given TTLV items, rewriting the parts of each response payload that the kmip-protocol crate cannot
+/// deserialize:
+///
+/// - The Located Items field that KMIP 1.3 added to the Locate response is dropped, as the kmip-protocol crate expects
+///   the response to start with the unique identifiers of the located objects.
+/// - The KMIP 2.x Attributes structure is replaced by one KMIP 1.x Attribute structure per attribute, named after the
+///   tag of the attribute. A Vendor Attribute is named as the KMIP 1.x custom attribute that it replaces, e.g.
+///   `x-Owner` for the attribute `Owner` of vendor `x`, unless its value is a structure. Other attributes whose tag
+///   has no name are dropped.
+/// - The Cryptographic Algorithm attribute is renamed to [RAW_CRYPTOGRAPHIC_ALGORITHM] and its value retyped as an
+///   Integer, so that it is deserialized as a plain number as the kmip-protocol crate only knows the first four
+///   algorithms (and the kmip-ttlv crate cannot deserialize the kmip-protocol crate's Enumeration value).
+/// - Date-Time attribute values, e.g. the Deactivation Date, are retyped as Long Integers holding the same number of
+///   seconds since the epoch, as the kmip-ttlv crate cannot deserialize the kmip-protocol crate's Date-Time value.
+/// - The Link attribute is renamed to [LINK], the name that the kmip-protocol crate mistakenly expects it to have, and
+///   dropped if it is of a Link Type that the kmip-protocol crate does not know.
+fn normalize_response(bytes: &[u8], out: &mut Vec<u8>) -> Result<()> {
+    for (tag, typ, value) in ttlv_items(bytes)? {
+        match tag {
+            TAG_RESPONSE_MESSAGE | TAG_BATCH_ITEM | TAG_RESPONSE_PAYLOAD => {
+                let mut items = Vec::with_capacity(value.len());
+                normalize_response(value, &mut items)?;
+                write_ttlv(out, tag, typ, &items);
+            }
+            TAG_LOCATED_ITEMS => {}
+            TAG_ATTRIBUTES => {
+                for (tag, typ, value) in ttlv_items(value)? {
+                    if let Some((name, typ, value)) = parse_vendor_attribute(tag, typ, value)? {
+                        let mut attribute = Vec::new();
+                        write_ttlv(
+                            &mut attribute,
+                            TAG_ATTRIBUTE_NAME,
+                            TTLV_TEXT_STRING,
+                            name.as_bytes(),
+                        );
+                        write_ttlv(
+                            &mut attribute,
+                            TAG_ATTRIBUTE_VALUE,
+                            attribute_value_type(&name, typ),
+                            value,
+                        );
+                        write_ttlv(out, TAG_ATTRIBUTE, TTLV_STRUCTURE, &attribute);
+                    } else if let Some(name) = attribute_name(tag)
+                        .map(normalized_attribute_name)
+                        .filter(|name| is_known_attribute_value(name, typ, value))
+                    {
+                        let mut attribute = Vec::new();
+                        write_ttlv(
+                            &mut attribute,
+                            TAG_ATTRIBUTE_NAME,
+                            TTLV_TEXT_STRING,
+                            name.as_bytes(),
+                        );
+                        write_ttlv(
+                            &mut attribute,
+                            TAG_ATTRIBUTE_VALUE,
+                            attribute_value_type(name, typ),
+                            value,
+                        );
+                        write_ttlv(out, TAG_ATTRIBUTE, TTLV_STRUCTURE, &attribute);
+                    }
+                }
+            }
+            TAG_ATTRIBUTE => {
+                let mut attribute = Vec::with_capacity(value.len());
+                // The Attribute Name precedes the Attribute Value
+                let mut name = "";
+                let mut known = true;
+                for (tag, typ, value) in ttlv_items(value)? {
+                    match tag {
+                        TAG_ATTRIBUTE_NAME => {
+                            name = normalized_attribute_name(std::str::from_utf8(value)?);
+                            write_ttlv(&mut attribute, tag, typ, name.as_bytes());
+                        }
+                        TAG_ATTRIBUTE_VALUE => {
+                            known = is_known_attribute_value(name, typ, value);
+                            write_ttlv(&mut attribute, tag, attribute_value_type(name, typ), value)
+                        }
+                        _ => write_ttlv(&mut attribute, tag, typ, value),
+                    }
+                }
+                if known {
+                    write_ttlv(out, tag, typ, &attribute);
+                }
+            }
+            _ => write_ttlv(out, tag, typ, value),
+        }
+    }
+    Ok(())
+}
+
+/// The KMIP 1.x custom attribute name, type and value of a KMIP 2.x Vendor Attribute, i.e. an item of an Attributes
+/// structure whose tag has no name which holds a Vendor Identification, an Attribute Name and an Attribute Value that
+/// is not a structure, or None if the item is not such a Vendor Attribute.
+fn parse_vendor_attribute(tag: u32, typ: u8, value: &[u8]) -> Result<Option<(String, u8, &[u8])>> {
+    if typ != TTLV_STRUCTURE || attribute_name(tag).is_some() {
+        return Ok(None);
+    }
+    let (mut vendor, mut name, mut attr_value) = (None, None, None);
+    for (tag, typ, value) in ttlv_items(value)? {
+        match (tag, typ) {
+            (TAG_VENDOR_IDENTIFICATION, TTLV_TEXT_STRING) => {
+                vendor = Some(std::str::from_utf8(value)?)
+            }
+            (TAG_ATTRIBUTE_NAME, TTLV_TEXT_STRING) => name = Some(std::str::from_utf8(value)?),
+            (TAG_ATTRIBUTE_VALUE, typ) if typ != TTLV_STRUCTURE => attr_value = Some((typ, value)),
+            _ => {}
+        }
+    }
+    match (vendor, name, attr_value) {
+        (Some(vendor), Some(name), Some((typ, value))) => {
+            Ok(Some((format!("{}-{}", vendor, name), typ, value)))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// The name to deserialize an attribute of the given name as, see [normalize_response].
+pub(crate) fn normalized_attribute_name(name: &str) -> &str {
+    match name {
+        "Cryptographic Algorithm" => RAW_CRYPTOGRAPHIC_ALGORITHM,
+        "Link" => LINK,
+        name => name,
+    }
+}
+
+/// Can the kmip-protocol crate deserialize the given value of the named (normalized) attribute? Only the Link Types
+/// of KMIP 1.0 are known to it.
+fn is_known_attribute_value(name: &str, typ: u8, value: &[u8]) -> bool {
+    match (name, typ) {
+        (LINK, TTLV_STRUCTURE) => ttlv_items(value).is_ok_and(|items| {
+            items.iter().any(|(tag, typ, value)| {
+                *tag == TAG_LINK_TYPE
+                    && *typ == TTLV_ENUMERATION
+                    && ttlv_u32(value).is_ok_and(|link_type| (0x101..=0x107).contains(&link_type))
+            })
+        }),
+        _ => true,
+    }
+}
+
+/// The TTLV type to deserialize a value of the given type of the named (normalized) attribute as, see
+/// [normalize_response].
+fn attribute_value_type(name: &str, typ: u8) -> u8 {
+    match (name, typ) {
+        (RAW_CRYPTOGRAPHIC_ALGORITHM, TTLV_ENUMERATION) => TTLV_INTEGER,
+        (_, TTLV_DATE_TIME) => TTLV_LONG_INTEGER,
+        (_, typ) => typ,
+    }
+}
+
+/// Copy the given TTLV items, leaving out any response payload and returning what the given function parses from it.
+fn take_payload<T>(
+    bytes: &[u8],
+    out: &mut Vec<u8>,
+    parse: fn(&[u8]) -> Result<Option<T>>,
+) -> Result<Option<T>> {
+    let mut parsed = None;
+    for (tag, typ, value) in ttlv_items(bytes)? {
+        match tag {
+            TAG_RESPONSE_MESSAGE | TAG_BATCH_ITEM => {
+                let mut items = Vec::with_capacity(value.len());
+                if let Some(found) = take_payload(value, &mut items, parse)? {
+                    parsed = Some(found);
+                }
+                write_ttlv(out, tag, typ, &items);
+            }
+            TAG_RESPONSE_PAYLOAD => parsed = parse(value)?,
+            _ => write_ttlv(out, tag, typ, value),
+        }
+    }
+    Ok(parsed)
+}
+
+/// The details of the managed object held by a Get response payload, if it is of a type described by
+/// [ObjectDetails].
+fn parse_object_details_payload(bytes: &[u8]) -> Result<Option<ObjectDetails>> {
+    let mut details = None;
+    for (tag, typ, value) in ttlv_items(bytes)? {
+        if matches!(tag, TAG_SECRET_DATA | TAG_OPAQUE_OBJECT | TAG_SPLIT_KEY)
+            && typ == TTLV_STRUCTURE
+        {
+            details = Some(parse_object_details(tag, value)?);
+        }
+    }
+    Ok(details)
+}
+
+/// The wrapped key block of the key held by a Get response payload, see KMIP 1.0 sections 2.1.3 Key Block and 2.1.5
+/// Key Wrapping Data.
+fn parse_wrapped_key_payload(bytes: &[u8]) -> Result<Option<WrappedKeyBlock>> {
+    let key_block = ttlv_items(bytes)?
+        .into_iter()
+        .filter(|(tag, typ, _)| {
+            matches!(
+                *tag,
+                TAG_PRIVATE_KEY
+                    | TAG_PUBLIC_KEY
+                    | TAG_SECRET_DATA
+                    | TAG_SPLIT_KEY
+                    | TAG_SYMMETRIC_KEY
+            ) && *typ == TTLV_STRUCTURE
+        })
+        .map(|(_, _, value)| ttlv_items(value))
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .flatten()
+        .find(|(tag, typ, _)| *tag == TAG_KEY_BLOCK && *typ == TTLV_STRUCTURE);
+    let (_, _, key_block) = match key_block {
+        Some(key_block) => key_block,
+        None => return Ok(None),
+    };
+
+    let (mut format, mut blob, mut wrapped) = (None, None, false);
+    for (tag, typ, value) in ttlv_items(key_block)? {
+        match (tag, typ) {
+            (TAG_KEY_FORMAT_TYPE, TTLV_ENUMERATION) => {
+                format = Some(match ttlv_u32(value)? {
+                    1 => "Raw".to_string(),
+                    2 => "Opaque".to_string(),
+                    3 => "PKCS1".to_string(),
+                    4 => "PKCS8".to_string(),
+                    5 => "X509".to_string(),
+                    6 => "ECPrivateKey".to_string(),
+                    other => format!("{:#010X}", other),
+                })
+            }
+            // The whole Key Value is wrapped, unless only its Key Material is as the Attribute encoding option was used
+            (TAG_KEY_VALUE, TTLV_BYTE_STRING) => blob = Some(value.to_vec()),
+            (TAG_KEY_VALUE, TTLV_STRUCTURE) => {
+                blob = ttlv_items(value)?
+                    .into_iter()
+                    .find(|(tag, typ, _)| *tag == TAG_KEY_MATERIAL && *typ == TTLV_BYTE_STRING)
+                    .map(|(_, _, material)| material.to_vec())
+            }
+            (TAG_KEY_WRAPPING_DATA, TTLV_STRUCTURE) => wrapped = true,
+            _ => {}
+        }
+    }
+    match (format, blob, wrapped) {
+        (Some(format), Some(blob), true) => Ok(Some(WrappedKeyBlock { format, blob })),
+        (_, _, false) => bail!("The server returned the key without wrapping it"),
+        _ => bail!("Invalid wrapped key block"),
+    }
+}
+
+/// See KMIP 1.0 sections 2.2.5 Split Key, 2.2.7 Secret Data and 2.2.8 Opaque Object.
+///
+/// The length of Secret Data or a Split Key is that given by its key block if any, otherwise that of its key material
+/// unless it is wrapped.
+fn parse_object_details(tag: u32, bytes: &[u8]) -> Result<ObjectDetails> {
+    let mut details = ObjectDetails::default();
+    let (mut part, mut parts, mut threshold, mut method) = (None, None, None, None);
+    for (tag, typ, value) in ttlv_items(bytes)? {
+        match (tag, typ) {
+            (TAG_SECRET_DATA_TYPE, TTLV_ENUMERATION) => {
+                details.data_type = Some(match ttlv_u32(value)? {
+                    1 => "Password".to_string(),
+                    2 => "Seed".to_string(),
+                    other => format!("{:#010X}", other),
+                })
+            }
+            (TAG_OPAQUE_DATA_TYPE, TTLV_ENUMERATION) => {
+                // KMIP only defines vendor specific opaque data types
+                details.data_type = Some(format!("{:#010X}", ttlv_u32(value)?))
+            }
+            (TAG_OPAQUE_DATA_VALUE, TTLV_BYTE_STRING) => details.len = Some(value.len() as i32 * 8),
+            (TAG_KEY_PART_IDENTIFIER, TTLV_INTEGER) => part = Some(ttlv_u32(value)? as i32),
+            (TAG_SPLIT_KEY_PARTS, TTLV_INTEGER) => parts = Some(ttlv_u32(value)? as i32),
+            (TAG_SPLIT_KEY_THRESHOLD, TTLV_INTEGER) => threshold = Some(ttlv_u32(value)? as i32),
+            (TAG_SPLIT_KEY_METHOD, TTLV_ENUMERATION) => {
+                method = Some(match ttlv_u32(value)? {
+                    1 => "XOR".to_string(),
+                    2 => "Polynomial Sharing GF(2^16)".to_string(),
+                    3 => "Polynomial Sharing Prime Field".to_string(),
+                    4 => "Polynomial Sharing GF(2^8)".to_string(),
+                    other => format!("{:#010X}", other),
+                })
+            }
+            (TAG_KEY_BLOCK, TTLV_STRUCTURE) => {
+                let items = ttlv_items(value)?;
+                let wrapped = items.iter().any(|(tag, ..)| *tag == TAG_KEY_WRAPPING_DATA);
+                for (tag, typ, value) in items {
+                    match (tag, typ) {
+                        (TAG_CRYPTOGRAPHIC_LENGTH, TTLV_INTEGER) => {
+                            details.len = Some(ttlv_u32(value)? as i32)
+                        }
+                        (TAG_KEY_VALUE, TTLV_STRUCTURE) if !wrapped && details.len.is_none() => {
+                            details.len = ttlv_items(value)?
+                                .iter()
+                                .find(|(tag, typ, _)| {
+                                    *tag == TAG_KEY_MATERIAL && *typ == TTLV_BYTE_STRING
+                                })
+                                .map(|(_, _, material)| material.len() as i32 * 8);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if tag == TAG_SPLIT_KEY {
+        match (part, parts, threshold, method) {
+            (Some(part), Some(parts), Some(threshold), Some(method)) => {
+                details.split_key = Some(SplitKey {
+                    part,
+                    parts,
+                    threshold,
+                    method,
+                })
+            }
+            _ => bail!("Split Key object lacks how the key was split"),
+        }
+    }
+    Ok(details)
+}
+
+fn ttlv_u32(value: &[u8]) -> Result<u32> {
+    match <[u8; 4]>::try_from(value) {
+        Ok(value) => Ok(u32::from_be_bytes(value)),
+        Err(_) => bail!("Invalid TTLV integer in response"),
+    }
+}
+
+/// Split a sequence of TTLV items into the tag, type and value of each item.
+fn ttlv_items(mut bytes: &[u8]) -> Result<Vec<(u32, u8, &[u8])>> {
+    let mut items = Vec::new();
+    while !bytes.is_empty() {
+        if bytes.len() < 8 {
+            bail!("Truncated TTLV item in response");
+        }
+        let tag = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        let len = u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]) as usize;
+        // Values are padded to a multiple of eight bytes
+        let padded_len = len.div_ceil(8) * 8;
+        if bytes.len() < 8 + padded_len {
+            bail!("Truncated TTLV item in response");
+        }
+        items.push((tag, bytes[3], &bytes[8..8 + len]));
+        bytes = &bytes[8 + padded_len..];
+    }
+    Ok(items)
+}
+
+fn write_ttlv(out: &mut Vec<u8>, tag: u32, typ: u8, value: &[u8]) {
+    out.extend_from_slice(&tag.to_be_bytes()[1..]);
+    out.push(typ);
+    out.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    out.extend_from_slice(value);
+    out.resize(out.len() + value.len().div_ceil(8) * 8 - value.len(), 0);
+}
+
+/// The names of the KMIP tags, which for an attribute is also its KMIP 1.x attribute name.
+fn tag_names() -> &'static HashMap<TtlvTag, &'static str> {
+    static TAG_NAMES: OnceLock<HashMap<TtlvTag, &'static str>> = OnceLock::new();
+    TAG_NAMES.get_or_init(make_kmip_tag_map)
+}
+
+fn attribute_name(tag: u32) -> Option<&'static str> {
+    let tag = TtlvTag::from(<[u8; 3]>::try_from(&tag.to_be_bytes()[1..]).ok()?);
+    tag_names().get(&tag).copied()
+}
+
+fn attribute_tag(name: &str) -> Option<u32> {
+    tag_names()
+        .iter()
+        .find(|(_, tag_name)| **tag_name == name)
+        .map(|(tag, _)| **tag)
+}
+
+/// Convert a response batch item into the same errors that the kmip-protocol crate uses for failed requests.
+fn batch_item_result(item: response::BatchItem) -> Result<ResponsePayload> {
+    match (item.result_status, item.payload) {
+        (ResultStatus::Success, Some(payload)) => Ok(payload),
+        (ResultStatus::Success, None) => Err(Error::InternalError(
+            "Unable to process response payload due to wrong deserialized type!".into(),
+        )
+        .into()),
+        (ResultStatus::OperationFailed, _) => {
+            let msg = match item.result_reason {
+                Some(reason) => format!(
+                    "Operation {:?} failed ({:?}): {}",
+                    item.operation,
+                    reason,
+                    item.result_message.unwrap_or_default()
+                ),
+                None => format!(
+                    "Operation {:?} failed: {}",
+                    item.operation,
+                    item.result_message.unwrap_or_default()
+                ),
+            };
+            if matches!(item.result_reason, Some(ResultReason::ItemNotFound)) {
+                Err(Error::ItemNotFound(msg).into())
+            } else {
+                Err(Error::ServerError(msg).into())
+            }
+        }
+        (status, _) => {
+            Err(Error::InternalError(format!("Result status {:?} is not supported", status)).into())
+        }
+    }
+}
+
+/// Extract the result reason from the message of an error returned by [batch_item_result], if it has one.
+pub(crate) fn result_reason(msg: &str) -> Option<String> {
+    let (_, rest) = msg.split_once(" failed (")?;
+    let (reason, _) = rest.split_once("): ")?;
+    Some(reason.to_string())
+}
+
+fn batch_item_id(idx: usize) -> UniqueBatchItemID {
+    UniqueBatchItemID((idx as u32).to_be_bytes().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The tag used by the TTLV encoding examples of KMIP 1.0 section 9.1.2, that of the Compromise Date.
+    const TAG_EXAMPLE: u32 = 0x420020;
+    const TAG_CRYPTOGRAPHIC_ALGORITHM: u32 = 0x420028;
+    const TAG_DEACTIVATION_DATE: u32 = 0x42002F;
+    const TAG_LINK: u32 = 0x42004A;
+    const TAG_LINKED_OBJECT_IDENTIFIER: u32 = 0x42004C;
+    const TAG_UNIQUE_IDENTIFIER: u32 = 0x420094;
+    /// A tag in the range of those that KMIP 2.x assigns to attributes but that has no name, as used by servers for
+    /// a Vendor Attribute.
+    const TAG_UNNAMED: u32 = 0x420150;
+
+    fn bytes(hex: &str) -> Vec<u8> {
+        let hex: String = hex.chars().filter(char::is_ascii_hexdigit).collect();
+        hex::decode(hex).unwrap()
+    }
+
+    fn item(tag: u32, typ: u8, value: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_ttlv(&mut out, tag, typ, value);
+        out
+    }
+
+    fn structure(tag: u32, items: &[Vec<u8>]) -> Vec<u8> {
+        item(tag, TTLV_STRUCTURE, &items.concat())
+    }
+
+    fn attribute(name: &str, typ: u8, value: &[u8]) -> Vec<u8> {
+        structure(
+            TAG_ATTRIBUTE,
+            &[
+                item(TAG_ATTRIBUTE_NAME, TTLV_TEXT_STRING, name.as_bytes()),
+                item(TAG_ATTRIBUTE_VALUE, typ, value),
+            ],
+        )
+    }
+
+    fn normalize(bytes: &[u8]) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        normalize_response(bytes, &mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn spec_encoding_examples() {
+        // KMIP 1.0 section 9.1.2 TTLV Encoding Examples
+        let examples: [(&str, u8, &[u8]); 7] = [
+            (
+                "420020 02 00000004 00000008 00000000",
+                TTLV_INTEGER,
+                &[0, 0, 0, 8],
+            ),
+            (
+                "420020 03 00000008 01B69B4BA5749200",
+                TTLV_LONG_INTEGER,
+                &123456789000000000u64.to_be_bytes(),
+            ),
+            (
+                "420020 05 00000004 000000FF 00000000",
+                TTLV_ENUMERATION,
+                &[0, 0, 0, 255],
+            ),
+            (
+                "420020 06 00000008 0000000000000001",
+                0x06,
+                &[0, 0, 0, 0, 0, 0, 0, 1],
+            ),
+            (
+                "420020 07 0000000B 48656C6C6F20576F726C64 0000000000",
+                TTLV_TEXT_STRING,
+                b"Hello World",
+            ),
+            (
+                "420020 08 00000003 010203 0000000000",
+                TTLV_BYTE_STRING,
+                &[1, 2, 3],
+            ),
+            (
+                "420020 09 00000008 0000000047DA67F8",
+                TTLV_DATE_TIME,
+                &[0, 0, 0, 0, 0x47, 0xDA, 0x67, 0xF8],
+            ),
+        ];
+        for (hex, typ, value) in examples {
+            let encoded = bytes(hex);
+            assert_eq!(
+                ttlv_items(&encoded).unwrap(),
+                vec![(TAG_EXAMPLE, typ, value)]
+            );
+            assert_eq!(item(TAG_EXAMPLE, typ, value), encoded);
+        }
+    }
+
+    #[test]
+    fn spec_structure_example() {
+        // KMIP 1.0 section 9.1.2: a Structure containing an Enumeration of 254 followed by an Integer of 255
+        let encoded = bytes(
+            "420020 01 00000020 \
+             420004 05 00000004 000000FE 00000000 \
+             420005 02 00000004 000000FF 00000000",
+        );
+        let items = ttlv_items(&encoded).unwrap();
+        assert_eq!(items.len(), 1);
+        let (tag, typ, value) = items[0];
+        assert_eq!((tag, typ), (TAG_EXAMPLE, TTLV_STRUCTURE));
+        assert_eq!(
+            ttlv_items(value).unwrap(),
+            vec![
+                (0x420004, TTLV_ENUMERATION, &[0, 0, 0, 254][..]),
+                (0x420005, TTLV_INTEGER, &[0, 0, 0, 255][..]),
+            ]
+        );
+        assert_eq!(item(TAG_EXAMPLE, TTLV_STRUCTURE, value), encoded);
+        // Items that need no rewriting are copied as is
+        assert_eq!(normalize(&encoded).unwrap(), encoded);
+    }
+
+    #[test]
+    fn truncated_and_oversized_lengths() {
+        let encoded = bytes("420020 08 00000003 010203 0000000000");
+        // A truncated header, a truncated value and missing padding
+        for len in [1, 7, 10, 11, 15] {
+            assert!(ttlv_items(&encoded[..len]).is_err(), "{} bytes", len);
+        }
+        // A length beyond the end of the message, including one that would overflow if padded carelessly
+        for len in ["00000010", "7FFFFFFF", "FFFFFFF9", "FFFFFFFF"] {
+            let oversized = bytes(&format!("420020 08 {} 010203 0000000000", len));
+            assert!(ttlv_items(&oversized).is_err(), "length {}", len);
+        }
+        // Also in a nested structure of a response
+        let nested = structure(TAG_RESPONSE_PAYLOAD, &[encoded[..12].to_vec()]);
+        assert!(normalize(&nested).is_err());
+        assert!(ttlv_items(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn normalize_v1_attributes() {
+        let deactivation_date = 0x47DA67F8u64.to_be_bytes();
+        let payload = structure(
+            TAG_RESPONSE_PAYLOAD,
+            &[
+                item(TAG_UNIQUE_IDENTIFIER, TTLV_TEXT_STRING, b"1"),
+                attribute(
+                    "Cryptographic Algorithm",
+                    TTLV_ENUMERATION,
+                    &[0, 0, 0, 0x0B],
+                ),
+                attribute("Deactivation Date", TTLV_DATE_TIME, &deactivation_date),
+                attribute("x-Owner", TTLV_TEXT_STRING, b"alice"),
+                // A Public Key Link, known to KMIP 1.0, and a Derived Key Link, added by KMIP 1.2
+                attribute(
+                    "Link",
+                    TTLV_STRUCTURE,
+                    &[
+                        item(TAG_LINK_TYPE, TTLV_ENUMERATION, &[0, 0, 1, 3]),
+                        item(TAG_LINKED_OBJECT_IDENTIFIER, TTLV_TEXT_STRING, b"2"),
+                    ]
+                    .concat(),
+                ),
+                attribute(
+                    "Link",
+                    TTLV_STRUCTURE,
+                    &[
+                        item(TAG_LINK_TYPE, TTLV_ENUMERATION, &[0, 0, 1, 9]),
+                        item(TAG_LINKED_OBJECT_IDENTIFIER, TTLV_TEXT_STRING, b"3"),
+                    ]
+                    .concat(),
+                ),
+            ],
+        );
+        let expected = structure(
+            TAG_RESPONSE_PAYLOAD,
+            &[
+                item(TAG_UNIQUE_IDENTIFIER, TTLV_TEXT_STRING, b"1"),
+                attribute(RAW_CRYPTOGRAPHIC_ALGORITHM, TTLV_INTEGER, &[0, 0, 0, 0x0B]),
+                attribute("Deactivation Date", TTLV_LONG_INTEGER, &deactivation_date),
+                attribute("x-Owner", TTLV_TEXT_STRING, b"alice"),
+                attribute(
+                    LINK,
+                    TTLV_STRUCTURE,
+                    &[
+                        item(TAG_LINK_TYPE, TTLV_ENUMERATION, &[0, 0, 1, 3]),
+                        item(TAG_LINKED_OBJECT_IDENTIFIER, TTLV_TEXT_STRING, b"2"),
+                    ]
+                    .concat(),
+                ),
+            ],
+        );
+        assert_eq!(normalize(&payload).unwrap(), expected);
+    }
+
+    #[test]
+    fn normalize_v2_attributes() {
+        assert_eq!(attribute_name(TAG_UNNAMED), None);
+        let deactivation_date = 0x47DA67F8u64.to_be_bytes();
+        let payload = structure(
+            TAG_RESPONSE_PAYLOAD,
+            &[
+                item(TAG_UNIQUE_IDENTIFIER, TTLV_TEXT_STRING, b"1"),
+                structure(
+                    TAG_ATTRIBUTES,
+                    &[
+                        item(TAG_CRYPTOGRAPHIC_ALGORITHM, TTLV_ENUMERATION, &[0, 0, 0, 3]),
+                        item(TAG_CRYPTOGRAPHIC_LENGTH, TTLV_INTEGER, &[0, 0, 1, 0]),
+                        item(TAG_DEACTIVATION_DATE, TTLV_DATE_TIME, &deactivation_date),
+                        structure(
+                            TAG_LINK,
+                            &[
+                                item(TAG_LINK_TYPE, TTLV_ENUMERATION, &[0, 0, 1, 0x0A]),
+                                item(TAG_LINKED_OBJECT_IDENTIFIER, TTLV_TEXT_STRING, b"2"),
+                            ],
+                        ),
+                        structure(
+                            TAG_UNNAMED,
+                            &[
+                                item(TAG_VENDOR_IDENTIFICATION, TTLV_TEXT_STRING, b"x"),
+                                item(TAG_ATTRIBUTE_NAME, TTLV_TEXT_STRING, b"Owner"),
+                                item(TAG_ATTRIBUTE_VALUE, TTLV_TEXT_STRING, b"alice"),
+                            ],
+                        ),
+                        // Neither a known attribute nor a Vendor Attribute
+                        item(TAG_UNNAMED, TTLV_INTEGER, &[0, 0, 0, 1]),
+                    ],
+                ),
+            ],
+        );
+        let expected = structure(
+            TAG_RESPONSE_PAYLOAD,
+            &[
+                item(TAG_UNIQUE_IDENTIFIER, TTLV_TEXT_STRING, b"1"),
+                attribute(RAW_CRYPTOGRAPHIC_ALGORITHM, TTLV_INTEGER, &[0, 0, 0, 3]),
+                attribute("Cryptographic Length", TTLV_INTEGER, &[0, 0, 1, 0]),
+                attribute("Deactivation Date", TTLV_LONG_INTEGER, &deactivation_date),
+                attribute("x-Owner", TTLV_TEXT_STRING, b"alice"),
+            ],
+        );
+        assert_eq!(normalize(&payload).unwrap(), expected);
+    }
+
+    #[test]
+    fn locate_page_request_and_response() {
+        for version in [KmipVersion::V1_3, KmipVersion::V2_0] {
+            let settings = RequestSettings {
+                auth: None,
+                max_response_bytes: None,
+                version,
+            };
+            let request = locate_request(&settings, Some(ObjectType::SymmetricKey), Some(10), 20);
+            let encoded = kmip_ttlv::to_vec(&request).unwrap();
+            let (tag, _, payload) = ttlv_items(&encoded).unwrap()[0];
+            assert_eq!(tag, 0x420079);
+            let items = ttlv_items(payload).unwrap();
+            let tags: Vec<u32> = items.iter().map(|(tag, ..)| *tag).collect();
+            let object_type = item(0x420057, TTLV_ENUMERATION, &[0, 0, 0, 2]);
+            if version.has_tagged_attributes() {
+                assert_eq!(tags, [0x42004F, 0x4200D4, TAG_ATTRIBUTES]);
+                assert_eq!(items[2].2, object_type);
+            } else {
+                assert_eq!(tags, [0x42004F, 0x4200D4, TAG_ATTRIBUTE]);
+            }
+            assert_eq!(items[0].2, [0, 0, 0, 10]);
+            assert_eq!(items[1].2, [0, 0, 0, 20]);
+        }
+
+        // KMIP 1.3 section 4.9 Locate: the Located Items precede the unique identifiers
+        let response = structure(
+            TAG_RESPONSE_MESSAGE,
+            &[
+                structure(
+                    0x42007A,
+                    &[
+                        structure(
+                            0x420069,
+                            &[
+                                item(0x42006A, TTLV_INTEGER, &[0, 0, 0, 1]),
+                                item(0x42006B, TTLV_INTEGER, &[0, 0, 0, 3]),
+                            ],
+                        ),
+                        item(0x420092, TTLV_DATE_TIME, &0x47DA67F8u64.to_be_bytes()),
+                        item(0x42000D, TTLV_INTEGER, &[0, 0, 0, 1]),
+                    ],
+                ),
+                structure(
+                    TAG_BATCH_ITEM,
+                    &[
+                        item(0x42005C, TTLV_ENUMERATION, &[0, 0, 0, 8]),
+                        item(0x42007F, TTLV_ENUMERATION, &[0, 0, 0, 0]),
+                        structure(
+                            TAG_RESPONSE_PAYLOAD,
+                            &[
+                                item(TAG_LOCATED_ITEMS, TTLV_INTEGER, &[0, 0, 0, 42]),
+                                item(TAG_UNIQUE_IDENTIFIER, TTLV_TEXT_STRING, b"21"),
+                                item(TAG_UNIQUE_IDENTIFIER, TTLV_TEXT_STRING, b"22"),
+                            ],
+                        ),
+                    ],
+                ),
+            ],
+        );
+        let normalized = normalize(&response).unwrap();
+        let mut res: ResponseMessage = kmip_ttlv::from_slice(&normalized).unwrap();
+        match batch_item_result(res.batch_items.pop().unwrap()).unwrap() {
+            ResponsePayload::Locate(res) => {
+                let ids: Vec<&str> = res
+                    .unique_identifiers
+                    .iter()
+                    .map(|id| id.0.as_str())
+                    .collect();
+                assert_eq!(ids, ["21", "22"]);
+            }
+            _ => panic!("Expected a Locate response payload"),
+        }
+    }
+
+    #[test]
+    fn object_details() {
+        let key_block = |items: &[Vec<u8>]| structure(TAG_KEY_BLOCK, items);
+        let key_value = structure(
+            TAG_KEY_VALUE,
+            &[item(TAG_KEY_MATERIAL, TTLV_BYTE_STRING, &[0; 32])],
+        );
+
+        // Secret Data without a Cryptographic Length has the length of its key material
+        let secret_data = [
+            item(TAG_SECRET_DATA_TYPE, TTLV_ENUMERATION, &[0, 0, 0, 1]),
+            key_block(&[
+                item(TAG_KEY_FORMAT_TYPE, TTLV_ENUMERATION, &[0, 0, 0, 2]),
+                key_value.clone(),
+            ]),
+        ]
+        .concat();
+        let details = parse_object_details(TAG_SECRET_DATA, &secret_data).unwrap();
+        assert_eq!(details.data_type.as_deref(), Some("Password"));
+        assert_eq!(details.len, Some(256));
+
+        // Unless it is wrapped
+        let wrapped = [
+            item(TAG_SECRET_DATA_TYPE, TTLV_ENUMERATION, &[0, 0, 0, 2]),
+            key_block(&[
+                item(TAG_KEY_FORMAT_TYPE, TTLV_ENUMERATION, &[0, 0, 0, 2]),
+                key_value.clone(),
+                structure(TAG_KEY_WRAPPING_DATA, &[]),
+            ]),
+        ]
+        .concat();
+        let details = parse_object_details(TAG_SECRET_DATA, &wrapped).unwrap();
+        assert_eq!(details.data_type.as_deref(), Some("Seed"));
+        assert_eq!(details.len, None);
+
+        let opaque = [
+            item(TAG_OPAQUE_DATA_TYPE, TTLV_ENUMERATION, &[0x80, 0, 0, 1]),
+            item(TAG_OPAQUE_DATA_VALUE, TTLV_BYTE_STRING, &[0; 3]),
+        ]
+        .concat();
+        let details = parse_object_details(TAG_OPAQUE_OBJECT, &opaque).unwrap();
+        assert_eq!(details.data_type.as_deref(), Some("0x80000001"));
+        assert_eq!(details.len, Some(24));
+
+        let split_key = [
+            item(TAG_SPLIT_KEY_PARTS, TTLV_INTEGER, &[0, 0, 0, 3]),
+            item(TAG_KEY_PART_IDENTIFIER, TTLV_INTEGER, &[0, 0, 0, 1]),
+            item(TAG_SPLIT_KEY_THRESHOLD, TTLV_INTEGER, &[0, 0, 0, 2]),
+            item(TAG_SPLIT_KEY_METHOD, TTLV_ENUMERATION, &[0, 0, 0, 1]),
+            key_block(&[
+                item(TAG_KEY_FORMAT_TYPE, TTLV_ENUMERATION, &[0, 0, 0, 1]),
+                key_value,
+                item(TAG_CRYPTOGRAPHIC_LENGTH, TTLV_INTEGER, &[0, 0, 0, 128]),
+            ]),
+        ]
+        .concat();
+        let details = parse_object_details(TAG_SPLIT_KEY, &split_key).unwrap();
+        assert_eq!(details.len, Some(128));
+        let split = details.split_key.unwrap();
+        assert_eq!(
+            (
+                split.part,
+                split.parts,
+                split.threshold,
+                split.method.as_str()
+            ),
+            (1, 3, 2, "XOR")
+        );
+        assert!(parse_object_details(TAG_SPLIT_KEY, &split_key[16..]).is_err());
+
+        // Through a whole Get response payload
+        let payload = [
+            item(TAG_UNIQUE_IDENTIFIER, TTLV_TEXT_STRING, b"1"),
+            structure(TAG_SECRET_DATA, &[secret_data]),
+        ]
+        .concat();
+        let details = parse_object_details_payload(&payload).unwrap().unwrap();
+        assert_eq!(details.len, Some(256));
+    }
+}
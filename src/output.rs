@@ -0,0 +1,529 @@
+use std::{collections::BTreeMap, ops::ControlFlow, sync::Mutex};
+
+use prettytable::{format, Row, Table};
+use serde::Serialize;
+
+use keyls::{
+    config::{ColumnWidth, KeyColumn, Opt, OutputFormat},
+    key::{CertInfo, Key},
+};
+
+/// The maximum width of a text output column unless set with --column-width.
+const DEFAULT_MAX_WIDTH: usize = 40;
+
+struct Column {
+    key_column: KeyColumn,
+    title: &'static str,
+    /// Minimum width used when streaming, chosen to fit typical values (e.g. a UUID key ID) so that most rows line up
+    /// even though the widest value in each column is not known in advance.
+    streaming_width: usize,
+    value: fn(&Key) -> String,
+}
+
+const TOKEN_COLUMN: Column = Column {
+    key_column: KeyColumn::Token,
+    title: "Token",
+    streaming_width: 24,
+    value: |key| key.token.clone().unwrap_or_default(),
+};
+
+const KEY_COLUMNS: [Column; 5] = [
+    Column {
+        key_column: KeyColumn::Id,
+        title: "ID",
+        streaming_width: 36,
+        value: |key| key.id.clone(),
+    },
+    Column {
+        key_column: KeyColumn::Type,
+        title: "Type",
+        streaming_width: 13,
+        value: |key| key.typ.to_string(),
+    },
+    Column {
+        key_column: KeyColumn::Name,
+        title: "Name",
+        streaming_width: 24,
+        value: |key| key.name.clone(),
+    },
+    Column {
+        key_column: KeyColumn::Algorithm,
+        title: "Algorithm",
+        streaming_width: 9,
+        value: |key| key.alg.clone(),
+    },
+    Column {
+        key_column: KeyColumn::Length,
+        title: "Length",
+        streaming_width: 6,
+        value: |key| key.len.clone(),
+    },
+];
+
+/// Columns only output with --long, or if selected explicitly, of which those for kinds of object not listed by
+/// default are only output if those objects are being listed.
+const LONG_COLUMNS: [Column; 10] = [
+    Column {
+        key_column: KeyColumn::Group,
+        title: "Group",
+        streaming_width: 16,
+        value: |key| key.groups.join(", "),
+    },
+    Column {
+        key_column: KeyColumn::AppInfo,
+        title: "App Info",
+        streaming_width: 24,
+        value: |key| {
+            key.app_info
+                .iter()
+                .map(|app_info| app_info.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        },
+    },
+    Column {
+        key_column: KeyColumn::AlwaysAuth,
+        title: "Always Auth",
+        streaming_width: 11,
+        value: |key| match key.always_authenticate {
+            Some(true) => "yes".to_string(),
+            Some(false) => "no".to_string(),
+            None => String::new(),
+        },
+    },
+    Column {
+        key_column: KeyColumn::Expires,
+        title: "Expires",
+        streaming_width: 31,
+        value: |key| key.expires.clone().unwrap_or_default(),
+    },
+    Column {
+        key_column: KeyColumn::Subject,
+        title: "Subject",
+        streaming_width: 32,
+        value: |key| cert_value(key, |cert| &cert.subject),
+    },
+    Column {
+        key_column: KeyColumn::Issuer,
+        title: "Issuer",
+        streaming_width: 32,
+        value: |key| cert_value(key, |cert| &cert.issuer),
+    },
+    Column {
+        key_column: KeyColumn::Serial,
+        title: "Serial",
+        streaming_width: 16,
+        value: |key| cert_value(key, |cert| &cert.serial),
+    },
+    Column {
+        key_column: KeyColumn::NotBefore,
+        title: "Not Before",
+        streaming_width: 20,
+        value: |key| cert_value(key, |cert| &cert.not_before),
+    },
+    Column {
+        key_column: KeyColumn::NotAfter,
+        title: "Not After",
+        streaming_width: 20,
+        value: |key| cert_value(key, |cert| &cert.not_after),
+    },
+    Column {
+        key_column: KeyColumn::SplitKey,
+        title: "Split Key",
+        streaming_width: 24,
+        value: |key| {
+            key.split_key
+                .as_ref()
+                .map(|split_key| split_key.to_string())
+                .unwrap_or_default()
+        },
+    },
+];
+
+/// A column of text output, either one of the fixed columns or one for an attribute requested with --attr.
+enum OutputColumn {
+    Fixed(&'static Column),
+    Attr(String),
+}
+
+/// The streaming width of columns for attributes requested with --attr, whose values are not known in advance.
+const ATTR_STREAMING_WIDTH: usize = 16;
+
+impl OutputColumn {
+    fn title(&self) -> &str {
+        match self {
+            OutputColumn::Fixed(column) => column.title,
+            OutputColumn::Attr(name) => name,
+        }
+    }
+
+    fn key_column(&self) -> Option<KeyColumn> {
+        match self {
+            OutputColumn::Fixed(column) => Some(column.key_column),
+            OutputColumn::Attr(_) => None,
+        }
+    }
+
+    fn streaming_width(&self) -> usize {
+        match self {
+            OutputColumn::Fixed(column) => column.streaming_width,
+            OutputColumn::Attr(name) => ATTR_STREAMING_WIDTH.max(name.chars().count()),
+        }
+    }
+
+    fn value(&self, key: &Key) -> String {
+        match self {
+            OutputColumn::Fixed(column) => (column.value)(key),
+            OutputColumn::Attr(name) => key.attrs.get(name).cloned().unwrap_or_default(),
+        }
+    }
+}
+
+fn cert_value(key: &Key, field: fn(&CertInfo) -> &Option<String>) -> String {
+    key.cert
+        .as_ref()
+        .and_then(|cert| field(cert).clone())
+        .unwrap_or_default()
+}
+
+/// Print the JSON Schema of the keys output with --format json.
+pub(crate) fn print_schema() {
+    let schema = schemars::schema_for!(Vec<Key>);
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&schema).expect("schema is serializable")
+    );
+}
+
+/// A node of the tree of key names printed with --tree, holding the keys whose names are the path to the node.
+#[derive(Default, Serialize)]
+struct TreeNode<'a> {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    keys: Vec<&'a Key>,
+    /// The nodes for the next component of the names of keys, by that component.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    children: BTreeMap<&'a str, TreeNode<'a>>,
+}
+
+impl<'a> TreeNode<'a> {
+    /// Build the tree of the names of the keys split into components by the separator, ignoring empty components so
+    /// that e.g. `/app1//signing` is the same as `app1/signing`, with keys without a name held by the root node.
+    fn new(keys: &'a [Key], separator: &str) -> Self {
+        let mut root = TreeNode::default();
+        for key in keys {
+            let node = key
+                .name
+                .split(separator)
+                .filter(|component| !component.is_empty())
+                .fold(&mut root, |node, component| {
+                    node.children.entry(component).or_default()
+                });
+            node.keys.push(key);
+        }
+        root
+    }
+}
+
+/// Prints keys as they are retrieved or, if sorting, a tree or JSON output was requested, collects them and prints
+/// them once all have been retrieved.
+pub(crate) struct KeyPrinter {
+    sort: bool,
+    /// The separator between the components of key names if printing them as a tree.
+    tree: Option<String>,
+    format: OutputFormat,
+    limit: Option<usize>,
+    warn_expiring: Option<u32>,
+    columns: Vec<OutputColumn>,
+    column_widths: Vec<ColumnWidth>,
+    wrap: bool,
+    full: bool,
+    state: Mutex<PrinterState>,
+}
+
+#[derive(Default)]
+struct PrinterState {
+    count: usize,
+    collected_keys: Vec<Key>,
+    /// The IDs of the keys that have expired or expire within the --warn-expiring window.
+    expiring: Vec<String>,
+}
+
+impl KeyPrinter {
+    /// Create a printer for the selected columns, in the order given, or if none were selected for all of the key
+    /// columns plus the token that each key was found in if listing keys from all slots, and the long columns that
+    /// apply if requested, followed by a column for each attribute requested with --attr.
+    ///
+    /// Once the limit of keys have been printed any further keys are ignored.
+    pub(crate) fn new(opt: &Opt) -> Self {
+        let selected = &opt.columns;
+        let mut columns: Vec<&'static Column> = Vec::new();
+        if selected.is_empty() {
+            if opt.all_slots {
+                columns.push(&TOKEN_COLUMN);
+            }
+            columns.extend(KEY_COLUMNS.iter());
+            columns.extend(
+                LONG_COLUMNS
+                    .iter()
+                    .filter(|column| opt.wants_column(column.key_column)),
+            );
+        } else {
+            for key_column in selected {
+                let column = std::iter::once(&TOKEN_COLUMN)
+                    .chain(KEY_COLUMNS.iter())
+                    .chain(LONG_COLUMNS.iter())
+                    .find(|column| column.key_column == *key_column)
+                    .expect("every key column has an output column");
+                columns.push(column);
+            }
+        }
+        let columns = columns
+            .into_iter()
+            .map(OutputColumn::Fixed)
+            .chain(opt.attrs.iter().cloned().map(OutputColumn::Attr))
+            .collect();
+
+        Self {
+            sort: opt.sort,
+            tree: opt.tree.then(|| opt.separator.clone()),
+            format: opt.format,
+            limit: opt.limit,
+            warn_expiring: opt.warn_expiring,
+            columns,
+            column_widths: opt.column_widths.clone(),
+            wrap: opt.wrap,
+            full: opt.full,
+            state: Default::default(),
+        }
+    }
+
+    /// Print the key, or save it for printing once all have been retrieved, and say whether more keys are wanted.
+    pub(crate) fn print(&self, key: Key) -> ControlFlow<()> {
+        let mut state = self.state.lock().unwrap();
+        if self.limit.is_some_and(|limit| state.count >= limit) {
+            return ControlFlow::Break(());
+        }
+        state.count += 1;
+        if self.is_expiring(&key) {
+            state.expiring.push(key.id.clone());
+        }
+        if self.sort || self.tree.is_some() || self.format == OutputFormat::Json {
+            state.collected_keys.push(key);
+        } else {
+            if state.count == 1 {
+                self.print_streaming_row(|column| column.title().to_string());
+            }
+            self.print_streaming_row(|column| self.value(column, &key));
+        }
+
+        if self.limit == Some(state.count) {
+            ControlFlow::Break(())
+        } else {
+            ControlFlow::Continue(())
+        }
+    }
+
+    /// The number of keys printed or collected for printing so far.
+    pub(crate) fn count(&self) -> usize {
+        self.state.lock().unwrap().count
+    }
+
+    /// The number of keys printed or collected so far that have expired or expire within the --warn-expiring window.
+    pub(crate) fn expiring_count(&self) -> usize {
+        self.state.lock().unwrap().expiring.len()
+    }
+
+    /// Print the keys if collected, and then warn on stderr about keys that have expired or expire soon.
+    pub(crate) fn finish(mut self) {
+        let mut state = std::mem::take(self.state.get_mut().unwrap());
+        self.print_keys(&mut state);
+        if let Some(days) = self.warn_expiring {
+            self.warn_expiring(&state.expiring, days);
+        }
+    }
+
+    fn print_keys(&self, state: &mut PrinterState) {
+        if self.sort {
+            state
+                .collected_keys
+                .sort_by(|a, b| (&a.token, &a.id).cmp(&(&b.token, &b.id)));
+        }
+
+        if self.format == OutputFormat::Json {
+            let json = match &self.tree {
+                Some(separator) => {
+                    serde_json::to_string_pretty(&TreeNode::new(&state.collected_keys, separator))
+                }
+                None => serde_json::to_string_pretty(&state.collected_keys),
+            };
+            println!("{}", json.expect("keys are serializable"));
+            return;
+        }
+
+        if state.count == 0 {
+            println!("No keys found");
+            return;
+        }
+
+        println!("Found {} keys", state.count);
+        if let Some(separator) = &self.tree {
+            self.print_tree(&TreeNode::new(&state.collected_keys, separator));
+        } else if self.sort {
+            let mut table = Table::new();
+            table.set_format(*format::consts::FORMAT_NO_LINESEP_WITH_TITLE);
+            table.set_titles(Row::from(self.columns.iter().map(|column| column.title())));
+            for key in &state.collected_keys {
+                table.add_row(Row::from(
+                    self.columns.iter().map(|column| self.value(column, key)),
+                ));
+            }
+
+            table.printstd();
+        }
+    }
+
+    /// Print the tree as a table whose first column is the name of each key indented by its depth in the tree, with
+    /// a row of its own for each component of the names that is not the full name of a key, and which otherwise has
+    /// the other columns.
+    fn print_tree(&self, root: &TreeNode) {
+        let columns = self
+            .columns
+            .iter()
+            .filter(|column| column.key_column() != Some(KeyColumn::Name))
+            .collect::<Vec<_>>();
+        let mut table = Table::new();
+        table.set_format(*format::consts::FORMAT_NO_LINESEP_WITH_TITLE);
+        table.set_titles(Row::from(
+            std::iter::once("Name").chain(columns.iter().map(|column| column.title())),
+        ));
+        self.add_tree_rows(&mut table, &columns, "", root, 0);
+        table.printstd();
+    }
+
+    fn add_tree_rows(
+        &self,
+        table: &mut Table,
+        columns: &[&OutputColumn],
+        label: &str,
+        node: &TreeNode,
+        depth: usize,
+    ) {
+        for key in &node.keys {
+            table.add_row(Row::from(
+                std::iter::once(label.to_string())
+                    .chain(columns.iter().map(|column| self.value(column, key))),
+            ));
+        }
+        for (component, child) in &node.children {
+            let label = format!("{}{}", "  ".repeat(depth), component);
+            if child.keys.is_empty() {
+                table.add_row(Row::from(
+                    std::iter::once(label.clone()).chain(columns.iter().map(|_| String::new())),
+                ));
+            }
+            self.add_tree_rows(table, columns, &label, child, depth + 1);
+        }
+    }
+
+    fn warn_expiring(&self, expiring: &[String], days: u32) {
+        if expiring.is_empty() {
+            return;
+        }
+        match self.format {
+            OutputFormat::Text => eprintln!(
+                "Warning: {} keys have expired or expire within {} days: {}",
+                expiring.len(),
+                days,
+                expiring.join(", ")
+            ),
+            OutputFormat::Json => eprintln!(
+                "{}",
+                serde_json::json!({ "expiring": { "days": days, "keys": expiring } })
+            ),
+        }
+    }
+
+    fn is_expiring(&self, key: &Key) -> bool {
+        self.warn_expiring
+            .is_some_and(|days| key.expires_within(days))
+    }
+
+    /// The value of the column for the key, marking when the key expires if it is soon, and fitted to the width of
+    /// the column.
+    fn value(&self, column: &OutputColumn, key: &Key) -> String {
+        let value = column.value(key);
+        let value = if column.key_column() != Some(KeyColumn::Expires) || !self.is_expiring(key) {
+            value
+        } else if key.expires_within(0) {
+            format!("{} (EXPIRED)", value)
+        } else {
+            format!("{} (EXPIRING)", value)
+        };
+        self.fit(column, value)
+    }
+
+    /// The maximum width of the column, if values are not to be output in full.
+    fn max_width(&self, column: &OutputColumn) -> Option<usize> {
+        if self.full {
+            return None;
+        }
+        let width = self
+            .column_widths
+            .iter()
+            .rev()
+            .find(|width| Some(width.column) == column.key_column())
+            .map_or(DEFAULT_MAX_WIDTH, |width| width.width);
+        Some(width)
+    }
+
+    /// Truncate a value that is too long for the column, ending it with ... to show that it was truncated, or with
+    /// --wrap split it into lines that fit the column.
+    fn fit(&self, column: &OutputColumn, value: String) -> String {
+        let max_width = match self.max_width(column) {
+            Some(max_width) if value.chars().count() > max_width => max_width,
+            _ => return value,
+        };
+        let chars = value.chars().collect::<Vec<_>>();
+        if self.wrap {
+            chars
+                .chunks(max_width)
+                .map(|line| line.iter().collect::<String>())
+                .collect::<Vec<_>>()
+                .join("\n")
+        } else {
+            let mut truncated = chars[..max_width - 3].iter().collect::<String>();
+            truncated.push_str("...");
+            truncated
+        }
+    }
+
+    /// Print a row padding each value to the streaming width of its column, on as many lines as the value that was
+    /// wrapped onto the most lines.
+    fn print_streaming_row<F: Fn(&OutputColumn) -> String>(&self, value: F) {
+        let values = self.columns.iter().map(value).collect::<Vec<_>>();
+        let line_count = values
+            .iter()
+            .map(|value| value.lines().count())
+            .max()
+            .unwrap_or_default()
+            .max(1);
+        for line_idx in 0..line_count {
+            let line = self
+                .columns
+                .iter()
+                .zip(&values)
+                .map(|(column, value)| {
+                    let width = self
+                        .max_width(column)
+                        .map_or(column.streaming_width(), |max_width| {
+                            max_width.min(column.streaming_width())
+                        });
+                    let value = value.lines().nth(line_idx).unwrap_or_default();
+                    format!("{:<width$}", value, width = width)
+                })
+                .collect::<Vec<_>>()
+                .join("  ");
+            println!("{}", line.trim_end());
+        }
+    }
+}
@@ -1,74 +1,965 @@
-use anyhow::{bail, Result};
+use std::{
+    collections::{BTreeMap, HashSet},
+    ops::{ControlFlow, Deref},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use anyhow::{anyhow, bail, Result};
 use cryptoki::{
     context::{CInitializeArgs, Pkcs11},
-    object::{Attribute, AttributeType, ObjectClass, ObjectHandle},
+    error::RvError,
+    mechanism::Mechanism,
+    object::{Attribute, AttributeInfo, AttributeType, CertificateType, ObjectClass},
     session::{Session, SessionFlags, UserType},
-    slot::Slot,
+    slot::{Slot, TokenInfo},
 };
+use cryptoki_sys::{CKM_AES_KEY_WRAP_PAD, CK_OBJECT_HANDLE};
+use tracing::{debug, info, warn};
 
 use crate::{
-    config::{Opt, Pkcs11ServerOpt, ServerOpt},
+    algorithm::{self, pkcs11_key_type_name},
+    cert::{cert_names, decode_name, decode_serial, parse_cert},
+    config::{Include, KeyColumn, Opt, Pkcs11ServerOpt, ServerOpt},
+    csr,
+    error::{ClassifiedError, ErrorClass, KeyError},
     key::{Key, KeyType},
+    pkcs11raw::{FunctionList, RawSession, FIND_BATCH_SIZE},
+    pkcs11trace::Pkcs11Tracer,
+    ratelimit::RateLimiter,
+    retry,
+    source::{Capabilities, KeySource, SignatureAlgorithm, WrappedKey},
+    timings::Timings,
+    util::glob_match,
 };
 
-pub(crate) fn get_keys(opt: Opt) -> Result<Vec<Key>> {
-    if let ServerOpt::Pkcs11(server_opt) = &opt.server {
-        let pkcs11 = Pkcs11::new(&server_opt.lib_path)?;
-        pkcs11.initialize(CInitializeArgs::OsThreads)?;
-
-        let slot = get_slot(&pkcs11, server_opt)?;
-        println!("Using PKCS#11 slot id {} ({:#x})", slot.id(), slot.id());
-
-        let mut flags = SessionFlags::new();
-        flags.set_serial_session(true).set_rw_session(true);
-        let session = pkcs11.open_session_no_callback(slot, flags)?;
-        session.login(UserType::User, server_opt.user_pin.as_deref())?;
-
-        let mut keys = Vec::new();
-        for key_handle in session.find_objects(&[Attribute::Class(ObjectClass::PRIVATE_KEY)])? {
-            match get_key(&session, key_handle) {
-                Ok(key) => keys.push(key),
-                Err(err) => eprintln!(
-                    "Error retrieving attributes for private key {:?}: {}",
-                    key_handle, err
+/// Logged in sessions with one or more PKCS#11 tokens which are reused by every operation performed against the
+/// tokens.
+///
+/// The sessions are logged out and closed, and the PKCS#11 library finalized, when this is dropped.
+pub struct Pkcs11Connection {
+    jobs: u16,
+    settings: RequestSettings,
+    sessions: Vec<SlotSession>,
+    /// The slots skipped with --all-slots as a session could not be opened with them, reported as failures by
+    /// [Pkcs11Connection::get_keys] so that the listing is known to be partial.
+    skipped_slots: Vec<KeyError>,
+}
+
+/// How to perform requests, shared by the sessions with every token.
+struct RequestSettings {
+    /// Used to reopen sessions that are lost, e.g. when a network HSM fails over.
+    pkcs11: Pkcs11,
+    /// Used to open the [RawSession] with each token.
+    functions: Arc<FunctionList>,
+    user_pin: Option<String>,
+    retries: u32,
+    /// The classes of object to list, each with a description and the attributes to retrieve, only including those
+    /// needed for the columns being output.
+    classes: Vec<(ObjectClass, &'static str, Vec<AttributeType>)>,
+    /// Whether to retrieve the CKA_END_DATE of keys, see [get_end_date].
+    end_date: bool,
+    /// Only list keys whose algorithm matches this --alg pattern.
+    alg_filter: Option<String>,
+    /// The attributes requested with --attr, by the name they were requested by, see [get_extra_attributes].
+    extra_attrs: Vec<(String, AttributeType)>,
+    limiter: RateLimiter,
+    tracer: Arc<Pkcs11Tracer>,
+    timings: Timings,
+}
+
+/// A logged in session with the token in a single PKCS#11 slot.
+struct SlotSession {
+    /// Identifies the slot and token that keys were found in, if keys are being listed from more than one slot.
+    token: Option<String>,
+    slot: Slot,
+    session: LoggedInSession,
+}
+
+/// A session which is logged in to a token, and which logs out when dropped before the session itself is closed.
+///
+/// The cryptoki crate closes sessions and, once the last session is closed, finalizes the library when they are
+/// dropped. Together this makes sure that however listing ends the token is left logged out, as some PKCS#11 libraries
+/// otherwise leave the token locked.
+///
+/// Objects are found and used via the raw session opened with it, as the cryptoki crate (as of v0.3) does not expose
+/// the values of object handles. It is declared first so that it is closed before the library can be finalized.
+struct LoggedInSession {
+    raw: RawSession,
+    session: Session,
+    slot: Slot,
+    tracer: Arc<Pkcs11Tracer>,
+    /// Cleared if the session was lost, as logging out would then fail and the library has already forgotten it.
+    logged_in: bool,
+}
+
+impl Deref for LoggedInSession {
+    type Target = Session;
+
+    fn deref(&self) -> &Session {
+        &self.session
+    }
+}
+
+impl Drop for LoggedInSession {
+    fn drop(&mut self) {
+        if self.logged_in {
+            let _ = self
+                .tracer
+                .call("C_Logout", format_args!("slot={}", self.slot.id()), || {
+                    self.session.logout()
+                });
+        }
+    }
+}
+
+impl<'a> KeySource<'a> for Pkcs11Connection {
+    fn connect(opt: &'a Opt) -> Result<Self> {
+        if let ServerOpt::Pkcs11(server_opt) = &opt.server {
+            let extra_attrs = opt
+                .attrs
+                .iter()
+                .map(|name| Ok((name.clone(), attribute_type(name)?)))
+                .collect::<Result<Vec<_>>>()?;
+            let tracer = Arc::new(Pkcs11Tracer::new(opt.trace_pkcs11.as_ref())?);
+            let pkcs11 = Pkcs11::new(&server_opt.lib_path)?;
+            tracer.call("C_Initialize", "CKF_OS_LOCKING_OK", || {
+                pkcs11.initialize(CInitializeArgs::OsThreads)
+            })?;
+            let functions = Arc::new(FunctionList::load(&server_opt.lib_path)?);
+            if let Ok(info) = tracer.call("C_GetInfo", "", || pkcs11.get_library_info()) {
+                info!(
+                    path = %server_opt.lib_path.display(),
+                    manufacturer = %info.manufacturer_id().trim_end(),
+                    description = %info.library_description().trim_end(),
+                    version = %info.library_version(),
+                    cryptoki_version = %info.cryptoki_version(),
+                    "Loaded PKCS#11 library"
+                );
+            }
+
+            let user_pin = server_opt.user_pin.as_deref();
+            let mut sessions = Vec::new();
+            let mut skipped_slots = Vec::new();
+            if opt.all_slots {
+                if server_opt.slot_id.is_some()
+                    || server_opt.slot_label.is_some()
+                    || server_opt.token_serial.is_some()
+                {
+                    bail!("Cannot specify a slot id, slot label or token serial number with --all-slots");
+                }
+                let slots = tracer.call("C_GetSlotList", "tokenPresent=CK_TRUE", || {
+                    pkcs11.get_slots_with_initialized_token()
+                })?;
+                for slot in slots {
+                    let info = get_token_info(&pkcs11, slot, &tracer).ok();
+                    if !token_matches(info.as_ref(), opt) {
+                        debug!(
+                            slot = slot.id(),
+                            "Skipping PKCS#11 token of another manufacturer or model"
+                        );
+                        continue;
+                    }
+                    let label = info
+                        .map(|info| info.label().trim_end().to_string())
+                        .unwrap_or_default();
+                    match open_session(&pkcs11, &functions, slot, user_pin, opt.retries, &tracer) {
+                        Ok(session) => sessions.push(SlotSession {
+                            token: Some(format!("{} ({})", slot.id(), label)),
+                            slot,
+                            session,
+                        }),
+                        Err(err) => {
+                            warn!(
+                                slot = slot.id(),
+                                "Cannot open a session with PKCS#11 slot, skipping it: {:#}", err
+                            );
+                            skipped_slots.push(KeyError::new(
+                                format!("PKCS#11 slot id {} ({:#x})", slot.id(), slot.id()),
+                                err,
+                            ));
+                        }
+                    }
+                }
+                if sessions.is_empty() {
+                    bail!("Cannot open a session with any PKCS#11 slot with a matching initialized token");
+                }
+            } else {
+                let slot = get_slot(&pkcs11, opt, server_opt, &tracer)?;
+                sessions.push(SlotSession {
+                    token: None,
+                    slot,
+                    session: open_session(
+                        &pkcs11,
+                        &functions,
+                        slot,
+                        user_pin,
+                        opt.retries,
+                        &tracer,
+                    )?,
+                });
+            }
+
+            let request_attrs = |columns: &[(KeyColumn, AttributeType)]| {
+                let mut attrs = vec![AttributeType::Class, AttributeType::Id];
+                for (column, attr) in columns {
+                    if opt.needs_column(*column) && !attrs.contains(attr) {
+                        attrs.push(*attr);
+                    }
+                }
+                attrs
+            };
+            let key_attrs = request_attrs(&[
+                (KeyColumn::Length, AttributeType::ModulusBits),
+                (KeyColumn::Algorithm, AttributeType::KeyType),
+                // The named curve of an EC key, which gives its size and for e.g. SM2 its algorithm
+                (KeyColumn::Algorithm, AttributeType::EcParams),
+                (KeyColumn::Length, AttributeType::EcParams),
+                // The size of the prime of a DSA or DH key
+                (KeyColumn::Length, AttributeType::Prime),
+                (KeyColumn::Name, AttributeType::Label),
+            ]);
+            let mut private_key_attrs = key_attrs.clone();
+            if opt.wants_column(KeyColumn::AlwaysAuth) {
+                private_key_attrs.push(AttributeType::AlwaysAuthenticate);
+            }
+            let mut classes = vec![
+                (ObjectClass::PRIVATE_KEY, "private key", private_key_attrs),
+                (ObjectClass::PUBLIC_KEY, "public key", key_attrs),
+            ];
+            if opt.includes(Include::Certs) {
+                // The key algorithm and size and the validity period are only known by parsing the certificate
+                let cert_attrs = request_attrs(&[
+                    (KeyColumn::Name, AttributeType::Label),
+                    (KeyColumn::Subject, AttributeType::Subject),
+                    (KeyColumn::Issuer, AttributeType::Issuer),
+                    (KeyColumn::Serial, AttributeType::SerialNumber),
+                    (KeyColumn::Algorithm, AttributeType::Value),
+                    (KeyColumn::Length, AttributeType::Value),
+                    (KeyColumn::NotBefore, AttributeType::Value),
+                    (KeyColumn::NotAfter, AttributeType::Value),
+                ]);
+                classes.push((ObjectClass::CERTIFICATE, "certificate", cert_attrs));
+            }
+
+            Ok(Self {
+                jobs: opt.jobs,
+                settings: RequestSettings {
+                    pkcs11,
+                    functions,
+                    user_pin: server_opt.user_pin.clone(),
+                    retries: opt.retries,
+                    classes,
+                    end_date: opt.needs_column(KeyColumn::Expires),
+                    alg_filter: opt.alg.clone(),
+                    extra_attrs,
+                    limiter: RateLimiter::new(opt.max_rps),
+                    tracer,
+                    timings: Timings::default(),
+                },
+                sessions,
+                skipped_slots,
+            })
+        } else {
+            bail!("Expected PKCS#11 settings")
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            parallel: true,
+            batching: false,
+            multiple_tokens: true,
+        }
+    }
+
+    fn timings(&self) -> &Timings {
+        &self.settings.timings
+    }
+
+    /// Tokens are processed in parallel, one session each, if more than one job was requested.
+    fn get_keys(
+        &mut self,
+        on_key: &(dyn Fn(Key) -> ControlFlow<()> + Sync),
+    ) -> Result<Vec<KeyError>> {
+        let settings = &self.settings;
+        let jobs = usize::from(self.jobs).clamp(1, self.sessions.len().max(1));
+        let sessions_per_job = self.sessions.len().div_ceil(jobs);
+        std::thread::scope(|scope| {
+            let workers: Vec<_> = self
+                .sessions
+                .chunks_mut(sessions_per_job.max(1))
+                .map(|sessions| {
+                    scope.spawn(move || -> Result<Vec<KeyError>> {
+                        let mut failures = Vec::new();
+                        for session in sessions.iter_mut() {
+                            if session
+                                .find_keys(settings, None, on_key, &mut failures)?
+                                .is_break()
+                            {
+                                break;
+                            }
+                        }
+                        Ok(failures)
+                    })
+                })
+                .collect();
+
+            let mut failures = std::mem::take(&mut self.skipped_slots);
+            for worker in workers {
+                failures.extend(worker.join().expect("PKCS#11 worker thread panicked")?);
+            }
+            Ok(failures)
+        })
+    }
+
+    fn get_keys_by_id(&mut self, id: &str) -> Result<Vec<Key>> {
+        let id =
+            hex::decode(id).map_err(|err| anyhow!("Invalid PKCS#11 key ID '{}': {}", id, err))?;
+        let keys = Mutex::new(Vec::new());
+        let mut failures = Vec::new();
+        for session in &mut self.sessions {
+            let on_key = |key| {
+                keys.lock().unwrap().push(key);
+                ControlFlow::Continue(())
+            };
+            let _ = session.find_keys(&self.settings, Some(&id), &on_key, &mut failures)?;
+        }
+        if let Some(failure) = failures.into_iter().next() {
+            return Err(failure.into());
+        }
+        Ok(keys.into_inner().unwrap())
+    }
+
+    /// Only RSA and EC public keys are supported.
+    fn public_key(&mut self, id: &str) -> Result<Vec<u8>> {
+        let (idx, key_handle) = self.find_object(ObjectClass::PUBLIC_KEY, "public key", id)?;
+        let settings = &self.settings;
+        let session = &mut self.sessions[idx];
+        let slot = session.slot;
+        session.do_op(settings, "Get public key", |session| {
+            let get_attributes = |types: &[AttributeType]| {
+                settings.tracer.call(
+                    "C_GetAttributeValue",
+                    format_args!(
+                        "slot={}, object={}, types={:?}",
+                        slot.id(),
+                        key_handle,
+                        types
+                    ),
+                    || session.raw.get_attributes(key_handle, types),
+                )
+            };
+            // The other attributes depend on the type of key, and the cryptoki crate (as of v0.3) fails to retrieve
+            // any attributes if one of them is not available
+            let key_type = match get_attributes(&[AttributeType::KeyType])?.pop() {
+                Some(Attribute::KeyType(key_type)) => key_type,
+                _ => bail!("Cannot retrieve the type of the public key"),
+            };
+            let types = match key_type {
+                cryptoki::object::KeyType::RSA => {
+                    [AttributeType::Modulus, AttributeType::PublicExponent]
+                }
+                cryptoki::object::KeyType::EC => [AttributeType::EcParams, AttributeType::EcPoint],
+                key_type => bail!(
+                    "Unsupported public key type {}, only RSA and EC public keys are supported",
+                    key_type
                 ),
+            };
+            let (mut first, mut second) = (None, None);
+            for attr in get_attributes(&types)? {
+                match attr {
+                    Attribute::Modulus(value) | Attribute::EcParams(value) => first = Some(value),
+                    Attribute::PublicExponent(value) | Attribute::EcPoint(value) => {
+                        second = Some(value)
+                    }
+                    _ => {}
+                }
             }
+            match (key_type, first, second) {
+                (cryptoki::object::KeyType::RSA, Some(modulus), Some(exponent)) => {
+                    csr::rsa_public_key(&modulus, &exponent)
+                }
+                (_, Some(params), Some(point)) => csr::ec_public_key(&params, &point),
+                _ => bail!("Cannot retrieve the public key"),
+            }
+        })
+    }
+
+    /// A key that requires the PIN to be entered again before each use (CKA_ALWAYS_AUTHENTICATE) is given the user PIN
+    /// again, see [login_context_specific].
+    fn sign(&mut self, id: &str, alg: SignatureAlgorithm, data: &[u8]) -> Result<Vec<u8>> {
+        let (idx, key_handle) = self.find_object(ObjectClass::PRIVATE_KEY, "private key", id)?;
+        let settings = &self.settings;
+        let session = &mut self.sessions[idx];
+        let slot = session.slot;
+        session.do_op(settings, "Sign", |session| {
+            let types = [AttributeType::AlwaysAuthenticate];
+            let always_authenticate = settings
+                .tracer
+                .call(
+                    "C_GetAttributeValue",
+                    format_args!(
+                        "slot={}, object={}, types={:?}",
+                        slot.id(),
+                        key_handle,
+                        types
+                    ),
+                    || session.raw.get_attributes(key_handle, &types),
+                )
+                .is_ok_and(|attrs| {
+                    attrs
+                        .iter()
+                        .any(|attr| matches!(attr, Attribute::AlwaysAuthenticate(true)))
+                });
+
+            // Hash the data for ECDSA as CKM_ECDSA is more widely supported than the mechanisms that also hash it
+            let (mechanism, data) = match alg {
+                SignatureAlgorithm::RsaPkcs1Sha256 => (Mechanism::Sha256RsaPkcs, data.to_vec()),
+                alg => (Mechanism::Ecdsa, csr::ecdsa_digest(alg, data)?),
+            };
+            settings.tracer.call(
+                "C_SignInit",
+                format_args!(
+                    "slot={}, mechanism={:?}, key={}",
+                    slot.id(),
+                    mechanism.mechanism_type(),
+                    key_handle
+                ),
+                || session.raw.sign_init(&mechanism, key_handle),
+            )?;
+            if always_authenticate {
+                login_context_specific(settings, slot, &session.raw)?;
+            }
+            let signature =
+                settings
+                    .tracer
+                    .call("C_Sign", format_args!("slot={}", slot.id()), || {
+                        session.raw.sign(&data)
+                    })?;
+            match alg {
+                SignatureAlgorithm::RsaPkcs1Sha256 => Ok(signature),
+                _ => csr::ecdsa_signature(&signature),
+            }
+        })
+    }
+
+    /// The certificate is stored on the token that holds the private key, with the same CKA_ID and CKA_LABEL.
+    fn import_cert(&mut self, id: &str, cert: &[u8]) -> Result<String> {
+        let (idx, key_handle) = self.find_object(ObjectClass::PRIVATE_KEY, "private key", id)?;
+        let key_id = hex::decode(id)?;
+        let names = cert_names(cert)?;
+        let settings = &self.settings;
+        let session = &mut self.sessions[idx];
+        let slot = session.slot;
+        session.do_op(settings, "Import certificate", |session| {
+            let types = [AttributeType::Label];
+            let label = settings
+                .tracer
+                .call(
+                    "C_GetAttributeValue",
+                    format_args!(
+                        "slot={}, object={}, types={:?}",
+                        slot.id(),
+                        key_handle,
+                        types
+                    ),
+                    || session.raw.get_attributes(key_handle, &types),
+                )?
+                .into_iter()
+                .find_map(|attr| match attr {
+                    Attribute::Label(label) => Some(label),
+                    _ => None,
+                });
+
+            let mut template = vec![
+                Attribute::Class(ObjectClass::CERTIFICATE),
+                Attribute::CertificateType(CertificateType::X_509),
+                Attribute::Token(true),
+                Attribute::Private(false),
+                Attribute::Id(key_id.clone()),
+                Attribute::Subject(names.subject.clone()),
+                Attribute::Issuer(names.issuer.clone()),
+                Attribute::SerialNumber(names.serial.clone()),
+                Attribute::Value(cert.to_vec()),
+            ];
+            template.extend(label.map(Attribute::Label));
+            settings.tracer.call(
+                "C_CreateObject",
+                format_args!("slot={}, class=CKO_CERTIFICATE, id={}", slot.id(), id),
+                || session.create_object(&template),
+            )?;
+            Ok(())
+        })?;
+        Ok(hex::encode_upper(key_id))
+    }
+
+    /// The wrapping key must be an AES secret key on the same token as the private key, which wraps it with
+    /// CKM_AES_KEY_WRAP_PAD, i.e. AES Key Wrap with Padding as specified by RFC 5649, as a private key is not
+    /// generally a multiple of 8 bytes long. The cryptoki crate (as of v0.3) does not support this mechanism, so the
+    /// key is wrapped via the raw session.
+    fn export_wrapped(&mut self, id: &str, wrapping_key_id: &str) -> Result<WrappedKey> {
+        let (idx, key_handle) = self.find_object(ObjectClass::PRIVATE_KEY, "private key", id)?;
+        let (wrapping_idx, wrapping_key_handle) =
+            self.find_object(ObjectClass::SECRET_KEY, "secret key", wrapping_key_id)?;
+        if wrapping_idx != idx {
+            return Err(ClassifiedError::new(
+                ErrorClass::Argument,
+                "The wrapping key must be on the same token as the private key",
+            )
+            .into());
         }
-        for key_handle in session.find_objects(&[Attribute::Class(ObjectClass::PUBLIC_KEY)])? {
-            match get_key(&session, key_handle) {
-                Ok(key) => keys.push(key),
-                Err(err) => eprintln!(
-                    "Error retrieving attributes for public key {:?}: {}",
-                    key_handle, err
+        let key = self
+            .get_keys_by_id(id)?
+            .into_iter()
+            .find(|key| key.typ == KeyType::Private)
+            .ok_or_else(|| anyhow!("Cannot retrieve the private key with ID '{}'", id))?;
+
+        let settings = &self.settings;
+        let session = &mut self.sessions[idx];
+        let slot = session.slot;
+        let blob = session.do_op(settings, "Wrap key", |session| {
+            let types = [AttributeType::KeyType];
+            let wrapping_key_type = settings
+                .tracer
+                .call(
+                    "C_GetAttributeValue",
+                    format_args!(
+                        "slot={}, object={}, types={:?}",
+                        slot.id(),
+                        wrapping_key_handle,
+                        types
+                    ),
+                    || session.raw.get_attributes(wrapping_key_handle, &types),
+                )?
+                .into_iter()
+                .find_map(|attr| match attr {
+                    Attribute::KeyType(key_type) => Some(key_type),
+                    _ => None,
+                });
+            match wrapping_key_type {
+                Some(cryptoki::object::KeyType::AES) => {}
+                Some(key_type) => {
+                    return Err(ClassifiedError::new(
+                        ErrorClass::Argument,
+                        format!(
+                            "Unsupported wrapping key type {}, only AES wrapping keys are supported for PKCS#11",
+                            key_type
+                        ),
+                    )
+                    .into())
+                }
+                None => bail!("Cannot retrieve the type of the wrapping key"),
+            }
+            Ok(settings.tracer.call(
+                "C_WrapKey",
+                format_args!(
+                    "slot={}, mechanism=CKM_AES_KEY_WRAP_PAD, wrapping_key={}, key={}",
+                    slot.id(),
+                    wrapping_key_handle,
+                    key_handle
                 ),
+                || {
+                    session
+                        .raw
+                        .wrap_key(CKM_AES_KEY_WRAP_PAD, wrapping_key_handle, key_handle)
+                },
+            )?)
+        })?;
+
+        Ok(WrappedKey {
+            blob,
+            key,
+            wrapping_key: wrapping_key_id.to_string(),
+            mechanism: "CKM_AES_KEY_WRAP_PAD (RFC 5649)".to_string(),
+            // PKCS#11 wraps a private key as a PKCS#8 PrivateKeyInfo
+            format: "PKCS8".to_string(),
+        })
+    }
+
+    /// With --all-slots the random data is generated by the token in the first slot with a token.
+    fn random(&mut self, len: u32) -> Result<Vec<u8>> {
+        let settings = &self.settings;
+        let session = self
+            .sessions
+            .first_mut()
+            .ok_or_else(|| anyhow!("No PKCS#11 token to generate random data with"))?;
+        let slot = session.slot;
+        session.do_op(settings, "Generate random", |session| {
+            Ok(settings.tracer.call(
+                "C_GenerateRandom",
+                format_args!("slot={}, len={}", slot.id(), len),
+                || session.generate_random_vec(len),
+            )?)
+        })
+    }
+}
+
+impl Pkcs11Connection {
+    /// Find the object of the given class with the given key ID, returning the index of the session with the token
+    /// that holds it and its handle.
+    ///
+    /// Exactly one such object must be held by the tokens, so that an operation is never performed with the wrong key.
+    fn find_object(
+        &mut self,
+        class: ObjectClass,
+        desc: &str,
+        id: &str,
+    ) -> Result<(usize, CK_OBJECT_HANDLE)> {
+        let key_id =
+            hex::decode(id).map_err(|err| anyhow!("Invalid PKCS#11 key ID '{}': {}", id, err))?;
+        let template = [Attribute::Class(class), Attribute::Id(key_id)];
+        let settings = &self.settings;
+        let mut found = Vec::new();
+        for (idx, session) in self.sessions.iter_mut().enumerate() {
+            let slot = session.slot;
+            let key_handles = session.do_op(settings, "Find key", |session| {
+                Ok(settings.tracer.call(
+                    "C_FindObjectsInit/C_FindObjects/C_FindObjectsFinal",
+                    format_args!("slot={}, template={:?}", slot.id(), template),
+                    || session.raw.find_objects(&template),
+                )?)
+            })?;
+            found.extend(key_handles.into_iter().map(|key_handle| (idx, key_handle)));
+        }
+        match found[..] {
+            [found] => Ok(found),
+            [] => Err(ClassifiedError::new(
+                ErrorClass::NotFound,
+                format!("No PKCS#11 {} with ID '{}' was found", desc, id),
+            )
+            .into()),
+            _ => Err(ClassifiedError::new(
+                ErrorClass::Argument,
+                format!("More than one PKCS#11 {} has ID '{}'", desc, id),
+            )
+            .into()),
+        }
+    }
+}
+
+impl SlotSession {
+    /// Find the keys, and certificates if included, held by the token, optionally only those with the given ID,
+    /// passing each to `on_key` until it asks to stop and adding those that cannot be retrieved to `failures`.
+    fn find_keys(
+        &mut self,
+        settings: &RequestSettings,
+        id: Option<&[u8]>,
+        on_key: &(dyn Fn(Key) -> ControlFlow<()> + Sync),
+        failures: &mut Vec<KeyError>,
+    ) -> Result<ControlFlow<()>> {
+        for class in &settings.classes {
+            let mut template = vec![Attribute::Class(class.0)];
+            if let Some(id) = id {
+                template.push(Attribute::Id(id.to_vec()));
+            }
+            let res = self.find_class_keys(settings, &template, class, on_key, failures);
+            // The search must be ended however it ended, so that the session can search again
+            let raw = &self.session.raw;
+            if raw.searching() {
+                let _ = settings.tracer.call(
+                    "C_FindObjectsFinal",
+                    format_args!("slot={}", self.slot.id()),
+                    || raw.find_objects_final(),
+                );
+            }
+            if res?.is_break() {
+                return Ok(ControlFlow::Break(()));
             }
         }
 
-        keys.sort_by_key(|v| v.id.clone());
+        Ok(ControlFlow::Continue(()))
+    }
+
+    /// Find the objects of one of the classes being listed that match the template, see [SlotSession::find_keys].
+    ///
+    /// The attributes of each batch of objects found are retrieved before the next batch is found, so that memory use
+    /// and the time until the first key is output do not grow with the number of objects on the token.
+    fn find_class_keys(
+        &mut self,
+        settings: &RequestSettings,
+        template: &[Attribute],
+        (class, desc, request_attrs): &(ObjectClass, &str, Vec<AttributeType>),
+        on_key: &(dyn Fn(Key) -> ControlFlow<()> + Sync),
+        failures: &mut Vec<KeyError>,
+    ) -> Result<ControlFlow<()>> {
+        let slot = self.slot;
+        // The handles already listed, as the search is lost with the session if that has to be reopened and the search
+        // started again then finds them again
+        let mut listed = HashSet::new();
+        loop {
+            let start = Instant::now();
+            let key_handles = self.do_op(settings, "Find keys", |session| {
+                if !session.raw.searching() {
+                    settings.tracer.call(
+                        "C_FindObjectsInit",
+                        format_args!("slot={}, template={:?}", slot.id(), template),
+                        || session.raw.find_objects_init(template),
+                    )?;
+                }
+                Ok(settings.tracer.call(
+                    "C_FindObjects",
+                    format_args!("slot={}, max={}", slot.id(), FIND_BATCH_SIZE),
+                    || session.raw.find_objects_batch(),
+                )?)
+            })?;
+            settings.timings.record_enumeration(start.elapsed());
+            if key_handles.is_empty() {
+                break;
+            }
+            for key_handle in key_handles {
+                if !listed.insert(key_handle) {
+                    continue;
+                }
+                let start = Instant::now();
+                let res = self.do_op(settings, "Get attributes", |session| {
+                    let get_attributes = |types: &[AttributeType]| {
+                        settings.tracer.call(
+                            "C_GetAttributeValue",
+                            format_args!(
+                                "slot={}, object={}, types={:?}",
+                                slot.id(),
+                                key_handle,
+                                types
+                            ),
+                            || session.raw.get_attributes(key_handle, types),
+                        )
+                    };
+                    let mut vendor_key_type = false;
+                    let attrs = match get_attributes(request_attrs) {
+                        // The cryptoki crate (as of v0.3) fails to retrieve any attributes if the CKA_KEY_TYPE is one
+                        // that it does not know, e.g. a vendor defined key type as some tokens use for SM2 keys
+                        Err(cryptoki::error::Error::NotSupported)
+                            if request_attrs.contains(&AttributeType::KeyType) =>
+                        {
+                            vendor_key_type = true;
+                            let types = request_attrs
+                                .iter()
+                                .filter(|typ| **typ != AttributeType::KeyType)
+                                .copied()
+                                .collect::<Vec<_>>();
+                            get_attributes(&types)?
+                        }
+                        res => res?,
+                    };
+                    let mut key = key_from_attributes(attrs)?;
+                    if vendor_key_type && key.alg.is_empty() {
+                        key.alg = "Vendor defined".to_string();
+                    }
+                    if settings.end_date && *class != ObjectClass::CERTIFICATE {
+                        key.expires =
+                            get_end_date(&session.raw, slot, key_handle, &settings.tracer);
+                    }
+                    if !settings.extra_attrs.is_empty() {
+                        key.attrs = get_extra_attributes(
+                            &session.raw,
+                            slot,
+                            key_handle,
+                            &settings.extra_attrs,
+                            &settings.tracer,
+                        );
+                    }
+                    Ok(key)
+                });
+                settings.timings.record_keys(start.elapsed(), 1);
+                match res {
+                    Ok(key)
+                        if !settings
+                            .alg_filter
+                            .as_ref()
+                            .is_none_or(|pattern| key.alg_matches(pattern)) => {}
+                    Ok(mut key) => {
+                        key.token = self.token.clone();
+                        if on_key(key).is_break() {
+                            return Ok(ControlFlow::Break(()));
+                        }
+                    }
+                    Err(err) => {
+                        let what = match &self.token {
+                            Some(token) => {
+                                format!("{} handle {} in token {}", desc, key_handle, token)
+                            }
+                            None => format!("{} handle {}", desc, key_handle),
+                        };
+                        failures.push(KeyError::new(
+                            what,
+                            err.context("Cannot retrieve attributes"),
+                        ))
+                    }
+                }
+            }
+        }
+        debug!(token = ?self.token, "Found {} {}s", listed.len(), desc);
+
+        Ok(ControlFlow::Continue(()))
+    }
+
+    /// Perform a PKCS#11 operation using the session, retrying on transient failure and reopening the session once
+    /// if it was lost.
+    ///
+    /// Object handles remain valid in the new session, as token object handles belong to the application rather than
+    /// to the session that found them, so the listing can carry on from where it was.
+    fn do_op<T, F>(&mut self, settings: &RequestSettings, what: &str, f: F) -> Result<T>
+    where
+        F: Fn(&LoggedInSession) -> Result<T>,
+    {
+        let attempt = |session: &LoggedInSession| {
+            retry::with_retries(settings.retries, what, is_transient_error, || {
+                settings.limiter.wait();
+                f(session)
+            })
+        };
+        match attempt(&self.session) {
+            Err(err) if is_session_lost_error(&err) => {
+                self.session.logged_in = false;
+                warn!(
+                    "{} failed, reopening the session with PKCS#11 slot id {}: {}",
+                    what,
+                    self.slot.id(),
+                    err
+                );
+                self.session = open_session(
+                    &settings.pkcs11,
+                    &settings.functions,
+                    self.slot,
+                    settings.user_pin.as_deref(),
+                    settings.retries,
+                    &settings.tracer,
+                )?;
+                attempt(&self.session)
+            }
+            res => res,
+        }
+    }
+}
+
+/// Give the user PIN again for a signing operation just initialized with a private key that requires the PIN to be
+/// entered again before each use (CKA_ALWAYS_AUTHENTICATE), by logging in with CKU_CONTEXT_SPECIFIC as PKCS#11
+/// requires, which the cryptoki crate (as of v0.3) does not allow.
+fn login_context_specific(settings: &RequestSettings, slot: Slot, raw: &RawSession) -> Result<()> {
+    let pin = settings.user_pin.as_deref().ok_or_else(|| {
+        ClassifiedError::new(
+            ErrorClass::Argument,
+            "The private key requires the PIN to be entered again before each use (CKA_ALWAYS_AUTHENTICATE) but no user PIN was given",
+        )
+    })?;
+    settings.tracer.call(
+        "C_Login",
+        format_args!(
+            "slot={}, userType=CKU_CONTEXT_SPECIFIC, pin=<redacted>",
+            slot.id()
+        ),
+        || raw.login_context_specific(pin),
+    )?;
+    Ok(())
+}
+
+fn open_session(
+    pkcs11: &Pkcs11,
+    functions: &Arc<FunctionList>,
+    slot: Slot,
+    user_pin: Option<&str>,
+    retries: u32,
+    tracer: &Arc<Pkcs11Tracer>,
+) -> Result<LoggedInSession> {
+    info!("Using PKCS#11 slot id {} ({:#x})", slot.id(), slot.id());
 
-        Ok(keys)
-    } else {
-        bail!("Expected PKCS#11 settings")
+    let mut flags = SessionFlags::new();
+    flags.set_serial_session(true).set_rw_session(true);
+    let session = retry::with_retries(
+        retries,
+        "Opening PKCS#11 session",
+        is_transient_error,
+        || {
+            Ok(tracer.call(
+                "C_OpenSession",
+                format_args!(
+                    "slot={}, flags=CKF_SERIAL_SESSION|CKF_RW_SESSION",
+                    slot.id()
+                ),
+                || pkcs11.open_session_no_callback(slot, flags),
+            )?)
+        },
+    )?;
+    let pin = match user_pin {
+        Some(_) => "<redacted>",
+        None => "NULL_PTR",
+    };
+    match tracer.call(
+        "C_Login",
+        format_args!("userType=CKU_USER, pin={}", pin),
+        || session.login(UserType::User, user_pin),
+    ) {
+        // Login state is shared by all sessions with the token, so when a lost session is reopened the token may
+        // still be logged in via the sessions with other slots
+        Ok(()) | Err(cryptoki::error::Error::Pkcs11(RvError::UserAlreadyLoggedIn)) => {}
+        Err(err) => return Err(err.into()),
     }
+    info!(slot = slot.id(), "Logged in to PKCS#11 token");
+    let raw = tracer.call(
+        "C_OpenSession",
+        format_args!("slot={}, flags=CKF_SERIAL_SESSION", slot.id()),
+        || RawSession::open(functions, slot),
+    )?;
+
+    Ok(LoggedInSession {
+        raw,
+        session,
+        slot,
+        tracer: tracer.clone(),
+        logged_in: true,
+    })
 }
 
-fn get_key(session: &Session, key_handle: ObjectHandle) -> Result<Key> {
+fn is_transient_error(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<cryptoki::error::Error>(),
+        Some(cryptoki::error::Error::Pkcs11(
+            RvError::FunctionFailed | RvError::DeviceError | RvError::DeviceMemory
+        ))
+    )
+}
+
+/// Is this an error which means that the session can no longer be used, but a new session may work?
+fn is_session_lost_error(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<cryptoki::error::Error>(),
+        Some(cryptoki::error::Error::Pkcs11(
+            RvError::SessionHandleInvalid
+                | RvError::SessionClosed
+                | RvError::DeviceRemoved
+                | RvError::UserNotLoggedIn
+        ))
+    )
+}
+
+fn get_token_info(
+    pkcs11: &Pkcs11,
+    slot: Slot,
+    tracer: &Pkcs11Tracer,
+) -> cryptoki::error::Result<TokenInfo> {
+    tracer.call("C_GetTokenInfo", format_args!("slot={}", slot.id()), || {
+        pkcs11.get_token_info(slot)
+    })
+}
+
+fn key_from_attributes(attrs: Vec<Attribute>) -> Result<Key> {
     let mut key = Key {
         id: Default::default(),
         typ: KeyType::Private,
         name: Default::default(),
         alg: Default::default(),
         len: Default::default(),
+        token: None,
+        groups: vec![],
+        app_info: vec![],
+        always_authenticate: None,
+        expires: None,
+        cert: None,
+        split_key: None,
+        attrs: Default::default(),
     };
-
-    let request_attrs = [
-        AttributeType::Class,
-        AttributeType::Id,
-        AttributeType::ModulusBits,
-        AttributeType::KeyType,
-        AttributeType::Label,
-    ];
-    let attrs = session.get_attributes(key_handle, &request_attrs)?;
+    let mut curve = None;
 
     for attr in attrs {
         match attr {
@@ -77,6 +968,8 @@ fn get_key(session: &Session, key_handle: ObjectHandle) -> Result<Key> {
                     key.typ = KeyType::Private;
                 } else if class == ObjectClass::PUBLIC_KEY {
                     key.typ = KeyType::Public;
+                } else if class == ObjectClass::CERTIFICATE {
+                    key.typ = KeyType::Certificate;
                 } else {
                     bail!("Unsupported object class");
                 }
@@ -85,11 +978,10 @@ fn get_key(session: &Session, key_handle: ObjectHandle) -> Result<Key> {
                 key.id = hex::encode_upper(&id);
             }
             Attribute::KeyType(typ) => {
-                if typ == cryptoki::object::KeyType::RSA {
-                    key.alg = "RSA".to_string();
-                } else {
-                    key.alg = "Non-RSA".to_string();
-                }
+                key.alg = pkcs11_key_type_name(typ);
+            }
+            Attribute::EcParams(params) => {
+                curve = algorithm::curve(&params);
             }
             Attribute::Label(label) => {
                 key.name = String::from_utf8_lossy(&label).to_string();
@@ -97,46 +989,417 @@ fn get_key(session: &Session, key_handle: ObjectHandle) -> Result<Key> {
             Attribute::ModulusBits(bits) => {
                 key.len = bits.to_string();
             }
+            Attribute::Prime(prime) => {
+                key.len = bit_length(&prime).to_string();
+            }
+            Attribute::AlwaysAuthenticate(always) => {
+                key.always_authenticate = Some(always);
+            }
+            Attribute::Subject(der) => {
+                key.cert.get_or_insert_with(Default::default).subject =
+                    Some(decode(der, decode_name));
+            }
+            Attribute::Issuer(der) => {
+                key.cert.get_or_insert_with(Default::default).issuer =
+                    Some(decode(der, decode_name));
+            }
+            Attribute::SerialNumber(der) => {
+                key.cert.get_or_insert_with(Default::default).serial =
+                    Some(decode(der, decode_serial));
+            }
+            Attribute::Value(der) => match parse_cert(&der) {
+                Ok(parsed) => {
+                    let cert = key.cert.get_or_insert_with(Default::default);
+                    cert.not_before = Some(parsed.not_before);
+                    cert.not_after = Some(parsed.not_after.clone());
+                    key.expires = Some(parsed.not_after);
+                    key.alg = parsed.alg;
+                    key.len = parsed.len;
+                }
+                Err(err) => debug!("Cannot parse certificate: {}", err),
+            },
             _ => {
                 // ignore unexpected attributes
             }
         }
     }
 
+    if let Some(curve) = curve {
+        if let Some(alg) = curve.alg {
+            key.alg = alg.to_string();
+        } else if let Some(name) = curve.name.filter(|_| !key.alg.is_empty()) {
+            key.alg = format!("{} {}", key.alg, name);
+        }
+        key.len = curve.bits.to_string();
+    }
+
     Ok(key)
 }
 
-fn get_slot(pkcs11: &Pkcs11, server_opt: &Pkcs11ServerOpt) -> Result<Slot> {
-    fn has_token_label(pkcs11: &Pkcs11, slot: Slot, slot_label: &str) -> bool {
-        pkcs11
-            .get_token_info(slot)
-            .map(|info| info.label().trim_end() == slot_label)
-            .unwrap_or(false)
+/// Get the CKA_END_DATE of a key, if it has a valid one, in RFC 3339 format.
+///
+/// This is not retrieved together with the other attributes of the key as the cryptoki crate (as of v0.3) then fails
+/// to retrieve any of them if the date is blank, as it commonly is, and misreads a date that is empty.
+fn get_end_date(
+    session: &RawSession,
+    slot: Slot,
+    key_handle: CK_OBJECT_HANDLE,
+    tracer: &Pkcs11Tracer,
+) -> Option<String> {
+    let types = [AttributeType::EndDate];
+    let info = tracer
+        .call(
+            "C_GetAttributeValue",
+            format_args!(
+                "slot={}, object={}, types={:?}",
+                slot.id(),
+                key_handle,
+                types
+            ),
+            || session.get_attribute_info(key_handle, &types),
+        )
+        .ok()?;
+    // A CK_DATE holds the year, month and day as 8 characters
+    if !matches!(info[..], [AttributeInfo::Available(8)]) {
+        return None;
     }
+    let attrs = tracer
+        .call(
+            "C_GetAttributeValue",
+            format_args!(
+                "slot={}, object={}, types={:?}",
+                slot.id(),
+                key_handle,
+                types
+            ),
+            || session.get_attributes(key_handle, &types),
+        )
+        .ok()?;
+    let date = match attrs.first() {
+        Some(Attribute::EndDate(date)) => date,
+        _ => return None,
+    };
 
-    let slot = match (&server_opt.slot_id, &server_opt.slot_label) {
-        (Some(id), None) => {
-            match pkcs11
-                .get_all_slots()?
-                .into_iter()
-                .find(|&slot| slot.id() == *id)
-            {
-                Some(slot) => slot,
-                None => bail!("Cannot find slot wiht id {}", id),
+    let digits = |chars: &[u8]| {
+        Some(String::from_utf8_lossy(chars).to_string())
+            .filter(|_| chars.iter().all(u8::is_ascii_digit))
+    };
+    let date = format!(
+        "{}-{}-{}T00:00:00Z",
+        digits(&date.year)?,
+        digits(&date.month)?,
+        digits(&date.day)?
+    );
+    humantime::parse_rfc3339(&date).ok().map(|_| date)
+}
+
+/// Every attribute type known to the cryptoki crate (as of v0.3), for looking up those requested with --attr by name.
+const ATTRIBUTE_TYPES: &[AttributeType] = &[
+    AttributeType::AcIssuer,
+    AttributeType::AllowedMechanisms,
+    AttributeType::AlwaysAuthenticate,
+    AttributeType::AlwaysSensitive,
+    AttributeType::Application,
+    AttributeType::AttrTypes,
+    AttributeType::Base,
+    AttributeType::CertificateType,
+    AttributeType::CheckValue,
+    AttributeType::Class,
+    AttributeType::Coefficient,
+    AttributeType::Copyable,
+    AttributeType::Decrypt,
+    AttributeType::Derive,
+    AttributeType::Destroyable,
+    AttributeType::EcParams,
+    AttributeType::EcPoint,
+    AttributeType::Encrypt,
+    AttributeType::EndDate,
+    AttributeType::Exponent1,
+    AttributeType::Exponent2,
+    AttributeType::Extractable,
+    AttributeType::HashOfIssuerPublicKey,
+    AttributeType::HashOfSubjectPublicKey,
+    AttributeType::Id,
+    AttributeType::Issuer,
+    AttributeType::KeyGenMechanism,
+    AttributeType::KeyType,
+    AttributeType::Label,
+    AttributeType::Local,
+    AttributeType::Modifiable,
+    AttributeType::Modulus,
+    AttributeType::ModulusBits,
+    AttributeType::NeverExtractable,
+    AttributeType::ObjectId,
+    AttributeType::Owner,
+    AttributeType::Prime,
+    AttributeType::Prime1,
+    AttributeType::Prime2,
+    AttributeType::Private,
+    AttributeType::PrivateExponent,
+    AttributeType::PublicExponent,
+    AttributeType::PublicKeyInfo,
+    AttributeType::Sensitive,
+    AttributeType::SerialNumber,
+    AttributeType::Sign,
+    AttributeType::SignRecover,
+    AttributeType::StartDate,
+    AttributeType::Subject,
+    AttributeType::Token,
+    AttributeType::Trusted,
+    AttributeType::Unwrap,
+    AttributeType::Url,
+    AttributeType::Value,
+    AttributeType::ValueLen,
+    AttributeType::Verify,
+    AttributeType::VerifyRecover,
+    AttributeType::Wrap,
+    AttributeType::WrapWithTrusted,
+];
+
+/// The attribute type with the given name, e.g. `CKA_SENSITIVE`, in any case and with or without the `CKA_` prefix.
+fn attribute_type(name: &str) -> Result<AttributeType> {
+    ATTRIBUTE_TYPES
+        .iter()
+        .find(|typ| {
+            let typ_name = typ.to_string();
+            typ_name.eq_ignore_ascii_case(name)
+                || typ_name
+                    .trim_start_matches("CKA_")
+                    .eq_ignore_ascii_case(name)
+        })
+        .copied()
+        .ok_or_else(|| {
+            ClassifiedError::new(
+                ErrorClass::Argument,
+                format!("Unknown PKCS#11 attribute '{}' given with --attr", name),
+            )
+            .into()
+        })
+}
+
+/// Get the attributes of a key requested with --attr, formatted as text, leaving out those that the key does not have
+/// or that cannot be retrieved.
+///
+/// Each is retrieved separately as the cryptoki crate (as of v0.3) fails to retrieve any attributes if one of them is
+/// not available, and misreads dates that are empty (see [get_end_date]).
+fn get_extra_attributes(
+    session: &RawSession,
+    slot: Slot,
+    key_handle: CK_OBJECT_HANDLE,
+    extra_attrs: &[(String, AttributeType)],
+    tracer: &Pkcs11Tracer,
+) -> BTreeMap<String, String> {
+    let mut values = BTreeMap::new();
+    for (name, typ) in extra_attrs {
+        let types = [*typ];
+        let available = tracer.call(
+            "C_GetAttributeValue",
+            format_args!(
+                "slot={}, object={}, types={:?}",
+                slot.id(),
+                key_handle,
+                types
+            ),
+            || session.get_attribute_info(key_handle, &types),
+        );
+        match available.as_deref() {
+            Ok([AttributeInfo::Available(8)]) => {}
+            Ok([AttributeInfo::Available(_)])
+                if !matches!(typ, AttributeType::StartDate | AttributeType::EndDate) => {}
+            _ => continue,
+        }
+        let attrs = tracer.call(
+            "C_GetAttributeValue",
+            format_args!(
+                "slot={}, object={}, types={:?}",
+                slot.id(),
+                key_handle,
+                types
+            ),
+            || session.get_attributes(key_handle, &types),
+        );
+        if let Some(value) = attrs.ok().and_then(|attrs| attrs.into_iter().next()) {
+            if let Some(value) = format_attribute(value) {
+                values.insert(name.clone(), value);
             }
         }
-        (None, Some(label)) => {
-            match pkcs11
-                .get_slots_with_initialized_token()?
+    }
+    values
+}
+
+/// Format the value of an attribute requested with --attr as text.
+fn format_attribute(attr: Attribute) -> Option<String> {
+    let text = |bytes: Vec<u8>| String::from_utf8_lossy(&bytes).to_string();
+    let value = match attr {
+        Attribute::Label(bytes) | Attribute::Application(bytes) | Attribute::Url(bytes) => {
+            text(bytes)
+        }
+        Attribute::AllowedMechanisms(mechanisms) => mechanisms
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(", "),
+        Attribute::AlwaysAuthenticate(value)
+        | Attribute::AlwaysSensitive(value)
+        | Attribute::Copyable(value)
+        | Attribute::Decrypt(value)
+        | Attribute::Derive(value)
+        | Attribute::Destroyable(value)
+        | Attribute::Encrypt(value)
+        | Attribute::Extractable(value)
+        | Attribute::Local(value)
+        | Attribute::Modifiable(value)
+        | Attribute::NeverExtractable(value)
+        | Attribute::Private(value)
+        | Attribute::Sensitive(value)
+        | Attribute::Sign(value)
+        | Attribute::SignRecover(value)
+        | Attribute::Token(value)
+        | Attribute::Trusted(value)
+        | Attribute::Unwrap(value)
+        | Attribute::Verify(value)
+        | Attribute::VerifyRecover(value)
+        | Attribute::Wrap(value)
+        | Attribute::WrapWithTrusted(value) => value.to_string(),
+        Attribute::CertificateType(typ) => typ.to_string(),
+        Attribute::Class(class) => class.to_string(),
+        Attribute::KeyGenMechanism(mechanism) => mechanism.to_string(),
+        Attribute::KeyType(typ) => typ.to_string(),
+        Attribute::ModulusBits(value) | Attribute::ValueLen(value) => value.to_string(),
+        Attribute::StartDate(date) | Attribute::EndDate(date) => {
+            let digits = |chars: &[u8]| {
+                Some(String::from_utf8_lossy(chars).to_string())
+                    .filter(|_| chars.iter().all(u8::is_ascii_digit))
+            };
+            format!(
+                "{}-{}-{}",
+                digits(&date.year)?,
+                digits(&date.month)?,
+                digits(&date.day)?
+            )
+        }
+        Attribute::AcIssuer(bytes)
+        | Attribute::AttrTypes(bytes)
+        | Attribute::Base(bytes)
+        | Attribute::CheckValue(bytes)
+        | Attribute::Coefficient(bytes)
+        | Attribute::EcParams(bytes)
+        | Attribute::EcPoint(bytes)
+        | Attribute::Exponent1(bytes)
+        | Attribute::Exponent2(bytes)
+        | Attribute::HashOfIssuerPublicKey(bytes)
+        | Attribute::HashOfSubjectPublicKey(bytes)
+        | Attribute::Id(bytes)
+        | Attribute::Issuer(bytes)
+        | Attribute::Modulus(bytes)
+        | Attribute::ObjectId(bytes)
+        | Attribute::Owner(bytes)
+        | Attribute::Prime(bytes)
+        | Attribute::Prime1(bytes)
+        | Attribute::Prime2(bytes)
+        | Attribute::PrivateExponent(bytes)
+        | Attribute::PublicExponent(bytes)
+        | Attribute::PublicKeyInfo(bytes)
+        | Attribute::SerialNumber(bytes)
+        | Attribute::Subject(bytes)
+        | Attribute::Value(bytes) => hex::encode_upper(bytes),
+        attr => format!("{:?}", attr),
+    };
+    Some(value)
+}
+
+/// The number of bits in a big-endian unsigned integer, e.g. the CKA_PRIME of a DSA or DH key.
+fn bit_length(value: &[u8]) -> usize {
+    match value.iter().position(|byte| *byte != 0) {
+        Some(first) => (value.len() - first) * 8 - value[first].leading_zeros() as usize,
+        None => 0,
+    }
+}
+
+/// Decode a DER encoded attribute value, falling back to hex if it cannot be decoded (e.g. if a token does not store
+/// the value DER encoded as required).
+fn decode(der: Vec<u8>, decoder: fn(&[u8]) -> Result<String>) -> String {
+    decoder(&der).unwrap_or_else(|err| {
+        debug!("Cannot decode DER attribute value: {}", err);
+        hex::encode_upper(&der)
+    })
+}
+
+/// Does the token match the manufacturer and model patterns given, if any?
+fn token_matches(info: Option<&TokenInfo>, opt: &Opt) -> bool {
+    let matches = |pattern: &Option<String>, value: fn(&TokenInfo) -> String| match pattern {
+        Some(pattern) => info.is_some_and(|info| glob_match(pattern, value(info).trim_end())),
+        None => true,
+    };
+    matches(&opt.token_manufacturer, TokenInfo::manufacturer_id)
+        && matches(&opt.token_model, TokenInfo::model)
+}
+
+/// Find the slot identified by its id, the label of its token or the serial number of its token, whose token also
+/// matches the manufacturer and model patterns if given, e.g. to choose between tokens with the same label.
+fn get_slot(
+    pkcs11: &Pkcs11,
+    opt: &Opt,
+    server_opt: &Pkcs11ServerOpt,
+    tracer: &Pkcs11Tracer,
+) -> Result<Slot> {
+    let find_token = |matches: &dyn Fn(&TokenInfo) -> bool, what: String| -> Result<Slot> {
+        tracer
+            .call("C_GetSlotList", "tokenPresent=CK_TRUE", || {
+                pkcs11.get_slots_with_initialized_token()
+            })?
+            .into_iter()
+            .find(|&slot| {
+                get_token_info(pkcs11, slot, tracer)
+                    .map(|info| matches(&info) && token_matches(Some(&info), opt))
+                    .unwrap_or(false)
+            })
+            .ok_or_else(|| {
+                ClassifiedError::new(
+                    ErrorClass::NotFound,
+                    format!("Cannot find slot with {}", what),
+                )
+                .into()
+            })
+    };
+
+    let slot = match (
+        &server_opt.slot_id,
+        &server_opt.slot_label,
+        &server_opt.token_serial,
+    ) {
+        (Some(id), None, None) => {
+            match tracer
+                .call("C_GetSlotList", "tokenPresent=CK_FALSE", || {
+                    pkcs11.get_all_slots()
+                })?
                 .into_iter()
-                .find(|&slot| has_token_label(pkcs11, slot, label))
-            {
+                .find(|&slot| slot.id() == *id)
+                .filter(|&slot| {
+                    token_matches(get_token_info(pkcs11, slot, tracer).ok().as_ref(), opt)
+                }) {
                 Some(slot) => slot,
-                None => bail!("Cannot find slot with label '{}'", label),
+                None => {
+                    return Err(ClassifiedError::new(
+                        ErrorClass::NotFound,
+                        format!("Cannot find slot wiht id {}", id),
+                    )
+                    .into())
+                }
             }
         }
-        (Some(_), Some(_)) => bail!("Cannot specify both slot id and slot label"),
-        (None, None) => bail!("Must specify at least one of slot id or slot label"),
+        (None, Some(label), None) => find_token(
+            &|info| info.label().trim_end() == label,
+            format!("label '{}'", label),
+        )?,
+        (None, None, Some(serial)) => find_token(
+            &|info| info.serial_number().trim_end() == serial,
+            format!("token serial number '{}'", serial),
+        )?,
+        (None, None, None) => {
+            bail!("Must specify one of slot id, slot label or token serial number, or use --all-slots")
+        }
+        _ => bail!("Specify only one of slot id, slot label or token serial number"),
     };
 
     Ok(slot)
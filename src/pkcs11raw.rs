@@ -0,0 +1,362 @@
+//! Cryptoki calls made directly via the function list of the PKCS#11 library, for operations that the cryptoki crate
+//! (as of v0.3) does not support and because it does not expose the values of the object handles that it finds.
+use std::{
+    cell::Cell,
+    convert::TryFrom,
+    path::Path,
+    ptr,
+    sync::{
+        atomic::{compiler_fence, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::bail;
+use cryptoki::{
+    error::{Error, Result, Rv, RvError},
+    mechanism::Mechanism,
+    object::{Attribute, AttributeInfo, AttributeType},
+    slot::Slot,
+};
+use cryptoki_sys::{
+    CKF_SERIAL_SESSION, CKU_CONTEXT_SPECIFIC, CK_ATTRIBUTE, CK_FUNCTION_LIST, CK_MECHANISM,
+    CK_MECHANISM_TYPE, CK_OBJECT_HANDLE, CK_SESSION_HANDLE, CK_ULONG,
+};
+
+/// The number of object handles to ask C_FindObjects for at a time.
+pub(crate) const FIND_BATCH_SIZE: usize = 16;
+
+/// The function list of the PKCS#11 library, loaded once per connection.
+///
+/// Loading a library that is already loaded gives the same instance, so this is the library that the cryptoki crate
+/// has already loaded and initialized. It stays loaded for as long as this is kept.
+pub(crate) struct FunctionList {
+    list: CK_FUNCTION_LIST,
+    _lib: cryptoki_sys::Pkcs11,
+}
+
+impl FunctionList {
+    pub(crate) fn load(lib_path: &Path) -> anyhow::Result<Self> {
+        let lib = unsafe { cryptoki_sys::Pkcs11::new(lib_path) }?;
+        let mut list = ptr::null_mut();
+        Rv::from(unsafe { lib.C_GetFunctionList(&mut list) }).into_result()?;
+        if list.is_null() {
+            bail!("The PKCS#11 library returned no function list");
+        }
+        Ok(Self {
+            list: unsafe { *list },
+            _lib: lib,
+        })
+    }
+}
+
+/// A session with a token opened via the function list, alongside the session opened via the cryptoki crate, and
+/// closed when dropped.
+///
+/// Sessions share the login state of the token and token object handles belong to the application rather than to a
+/// session, so objects found via this session can be used via it without logging in to it.
+pub(crate) struct RawSession {
+    functions: Arc<FunctionList>,
+    handle: CK_SESSION_HANDLE,
+    /// Whether a search started by [RawSession::find_objects_init] has not yet been ended.
+    searching: Cell<bool>,
+}
+
+impl RawSession {
+    pub(crate) fn open(functions: &Arc<FunctionList>, slot: Slot) -> Result<Self> {
+        let open = functions
+            .list
+            .C_OpenSession
+            .ok_or(Error::NullFunctionPointer)?;
+        let mut handle = 0;
+        Rv::from(unsafe {
+            open(
+                slot.into(),
+                CKF_SERIAL_SESSION,
+                ptr::null_mut(),
+                None,
+                &mut handle,
+            )
+        })
+        .into_result()?;
+        Ok(Self {
+            functions: functions.clone(),
+            handle,
+            searching: Cell::new(false),
+        })
+    }
+
+    /// The handles of all of the objects that match the template.
+    pub(crate) fn find_objects(&self, template: &[Attribute]) -> Result<Vec<CK_OBJECT_HANDLE>> {
+        self.find_objects_init(template)?;
+        let mut handles = Vec::new();
+        let res = loop {
+            match self.find_objects_batch() {
+                Ok(batch) if batch.is_empty() => break Ok(handles),
+                Ok(batch) => handles.extend(batch),
+                Err(err) => break Err(err),
+            }
+        };
+        let _ = self.find_objects_final();
+        res
+    }
+
+    /// Start a search for the objects that match the template, whose handles are then retrieved a batch at a time with
+    /// [RawSession::find_objects_batch] until the search is ended with [RawSession::find_objects_final].
+    ///
+    /// Other functions, e.g. C_GetAttributeValue, can be called with the session while the search is going on.
+    pub(crate) fn find_objects_init(&self, template: &[Attribute]) -> Result<()> {
+        let init = self
+            .functions
+            .list
+            .C_FindObjectsInit
+            .ok_or(Error::NullFunctionPointer)?;
+        let mut template: Vec<CK_ATTRIBUTE> = template.iter().map(CK_ATTRIBUTE::from).collect();
+        Rv::from(unsafe {
+            init(
+                self.handle,
+                template.as_mut_ptr(),
+                CK_ULONG::try_from(template.len())?,
+            )
+        })
+        .into_result()?;
+        self.searching.set(true);
+        Ok(())
+    }
+
+    /// Whether a search has been started and not yet ended, which it is not in a session that has just been opened.
+    pub(crate) fn searching(&self) -> bool {
+        self.searching.get()
+    }
+
+    /// The handles of up to [FIND_BATCH_SIZE] more of the objects found by the search, none once all have been.
+    pub(crate) fn find_objects_batch(&self) -> Result<Vec<CK_OBJECT_HANDLE>> {
+        let find = self
+            .functions
+            .list
+            .C_FindObjects
+            .ok_or(Error::NullFunctionPointer)?;
+        let mut batch = vec![0; FIND_BATCH_SIZE];
+        let mut count = 0;
+        Rv::from(unsafe {
+            find(
+                self.handle,
+                batch.as_mut_ptr(),
+                CK_ULONG::try_from(batch.len())?,
+                &mut count,
+            )
+        })
+        .into_result()?;
+        batch.truncate(usize::try_from(count)?);
+        Ok(batch)
+    }
+
+    pub(crate) fn find_objects_final(&self) -> Result<()> {
+        let fin = self
+            .functions
+            .list
+            .C_FindObjectsFinal
+            .ok_or(Error::NullFunctionPointer)?;
+        self.searching.set(false);
+        Rv::from(unsafe { fin(self.handle) }).into_result()
+    }
+
+    /// Whether each of the attributes of the object is available, and if so its length.
+    pub(crate) fn get_attribute_info(
+        &self,
+        object: CK_OBJECT_HANDLE,
+        types: &[AttributeType],
+    ) -> Result<Vec<AttributeInfo>> {
+        let get = self
+            .functions
+            .list
+            .C_GetAttributeValue
+            .ok_or(Error::NullFunctionPointer)?;
+        // Each is requested separately so that it is known which are sensitive or invalid
+        types
+            .iter()
+            .map(|typ| {
+                let mut template = CK_ATTRIBUTE {
+                    type_: (*typ).into(),
+                    pValue: ptr::null_mut(),
+                    ulValueLen: 0,
+                };
+                match Rv::from(unsafe { get(self.handle, object, &mut template, 1) }) {
+                    Rv::Ok => Ok(AttributeInfo::Available(usize::try_from(
+                        template.ulValueLen,
+                    )?)),
+                    Rv::Error(RvError::AttributeSensitive) => Ok(AttributeInfo::Sensitive),
+                    Rv::Error(RvError::AttributeTypeInvalid) => Ok(AttributeInfo::TypeInvalid),
+                    rv => Err(rv.into_result().unwrap_err()),
+                }
+            })
+            .collect()
+    }
+
+    /// The values of those of the attributes of the object that are available.
+    pub(crate) fn get_attributes(
+        &self,
+        object: CK_OBJECT_HANDLE,
+        types: &[AttributeType],
+    ) -> Result<Vec<Attribute>> {
+        let get = self
+            .functions
+            .list
+            .C_GetAttributeValue
+            .ok_or(Error::NullFunctionPointer)?;
+        let mut values: Vec<(AttributeType, Vec<u8>)> = types
+            .iter()
+            .zip(self.get_attribute_info(object, types)?)
+            .filter_map(|(typ, info)| match info {
+                AttributeInfo::Available(len) => Some((*typ, vec![0; len])),
+                _ => None,
+            })
+            .collect();
+        let mut template = values
+            .iter_mut()
+            .map(|(typ, value)| {
+                Ok(CK_ATTRIBUTE {
+                    type_: (*typ).into(),
+                    pValue: value.as_mut_ptr().cast(),
+                    ulValueLen: CK_ULONG::try_from(value.len())?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Rv::from(unsafe {
+            get(
+                self.handle,
+                object,
+                template.as_mut_ptr(),
+                CK_ULONG::try_from(template.len())?,
+            )
+        })
+        .into_result()?;
+        template.into_iter().map(Attribute::try_from).collect()
+    }
+
+    pub(crate) fn sign_init(&self, mechanism: &Mechanism, key: CK_OBJECT_HANDLE) -> Result<()> {
+        let sign_init = self
+            .functions
+            .list
+            .C_SignInit
+            .ok_or(Error::NullFunctionPointer)?;
+        let mut mechanism = CK_MECHANISM::from(mechanism);
+        Rv::from(unsafe { sign_init(self.handle, &mut mechanism, key) }).into_result()
+    }
+
+    /// Give the PIN again for the operation just initialized, for a key that requires it before each use
+    /// (CKA_ALWAYS_AUTHENTICATE).
+    ///
+    /// C_Login takes a mutable pointer to the PIN, so it is given a copy which is cleared afterwards.
+    pub(crate) fn login_context_specific(&self, pin: &str) -> Result<()> {
+        let login = self
+            .functions
+            .list
+            .C_Login
+            .ok_or(Error::NullFunctionPointer)?;
+        let mut pin = pin.as_bytes().to_vec();
+        let len = CK_ULONG::try_from(pin.len())?;
+        let rv =
+            Rv::from(unsafe { login(self.handle, CKU_CONTEXT_SPECIFIC, pin.as_mut_ptr(), len) });
+        zeroize(&mut pin);
+        rv.into_result()
+    }
+
+    /// Sign the data with the operation initialized by [RawSession::sign_init].
+    pub(crate) fn sign(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let sign = self
+            .functions
+            .list
+            .C_Sign
+            .ok_or(Error::NullFunctionPointer)?;
+        let mut data = data.to_vec();
+        let data_len = CK_ULONG::try_from(data.len())?;
+        // The first call only gets the length of the signature
+        let mut len = 0;
+        Rv::from(unsafe {
+            sign(
+                self.handle,
+                data.as_mut_ptr(),
+                data_len,
+                ptr::null_mut(),
+                &mut len,
+            )
+        })
+        .into_result()?;
+        let mut signature = vec![0; usize::try_from(len)?];
+        Rv::from(unsafe {
+            sign(
+                self.handle,
+                data.as_mut_ptr(),
+                data_len,
+                signature.as_mut_ptr(),
+                &mut len,
+            )
+        })
+        .into_result()?;
+        signature.truncate(usize::try_from(len)?);
+        Ok(signature)
+    }
+
+    /// Wrap the key with the wrapping key, using a mechanism that takes no parameter.
+    pub(crate) fn wrap_key(
+        &self,
+        mechanism: CK_MECHANISM_TYPE,
+        wrapping_key: CK_OBJECT_HANDLE,
+        key: CK_OBJECT_HANDLE,
+    ) -> Result<Vec<u8>> {
+        let wrap = self
+            .functions
+            .list
+            .C_WrapKey
+            .ok_or(Error::NullFunctionPointer)?;
+        let mut mechanism = CK_MECHANISM {
+            mechanism,
+            pParameter: ptr::null_mut(),
+            ulParameterLen: 0,
+        };
+        // The first call only gets the length of the wrapped key
+        let mut len = 0;
+        Rv::from(unsafe {
+            wrap(
+                self.handle,
+                &mut mechanism,
+                wrapping_key,
+                key,
+                ptr::null_mut(),
+                &mut len,
+            )
+        })
+        .into_result()?;
+        let mut wrapped = vec![0; usize::try_from(len)?];
+        Rv::from(unsafe {
+            wrap(
+                self.handle,
+                &mut mechanism,
+                wrapping_key,
+                key,
+                wrapped.as_mut_ptr(),
+                &mut len,
+            )
+        })
+        .into_result()?;
+        wrapped.truncate(usize::try_from(len)?);
+        Ok(wrapped)
+    }
+}
+
+impl Drop for RawSession {
+    fn drop(&mut self) {
+        if let Some(close) = self.functions.list.C_CloseSession {
+            let _ = unsafe { close(self.handle) };
+        }
+    }
+}
+
+/// Overwrite a buffer that held a secret in a way that the compiler cannot optimize away as a dead store.
+fn zeroize(buf: &mut [u8]) {
+    for byte in buf.iter_mut() {
+        unsafe { ptr::write_volatile(byte, 0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
@@ -0,0 +1,171 @@
+//! Tracing of the Cryptoki calls made to the PKCS#11 library, enabled by `--trace-pkcs11`.
+use std::{fmt::Display, io::Write, path::PathBuf, sync::Mutex, time::Instant};
+
+use anyhow::Result;
+use cryptoki::{
+    context::Info,
+    object::{Attribute, AttributeInfo, ObjectHandle},
+    session::Session,
+    slot::{Slot, TokenInfo},
+};
+use cryptoki_sys::CK_OBJECT_HANDLE;
+
+use crate::{error::ckr_name, pkcs11raw::RawSession, util::open_trace_output};
+
+/// The maximum number of object handles to include in the trace of a call that returns them.
+const MAX_TRACED_HANDLES: usize = 16;
+
+/// Writes a line per Cryptoki call, similar to the PKCS#11 spy module, if tracing is enabled.
+pub(crate) struct Pkcs11Tracer {
+    out: Option<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl Pkcs11Tracer {
+    /// Trace to stderr if `trace` is `Some(None)` or append to the given file if it is `Some(Some(path))`.
+    pub(crate) fn new(trace: Option<&Option<PathBuf>>) -> Result<Self> {
+        let out = match trace {
+            Some(path) => Some(Mutex::new(open_trace_output(path.as_deref())?)),
+            None => None,
+        };
+        Ok(Self { out })
+    }
+
+    /// Make a Cryptoki call via `f`, tracing the function name, the given description of its arguments, the return
+    /// code, how long the call took and a summary of what it returned.
+    pub(crate) fn call<T, A, F>(&self, function: &str, args: A, f: F) -> cryptoki::error::Result<T>
+    where
+        T: TraceSummary,
+        A: Display,
+        F: FnOnce() -> cryptoki::error::Result<T>,
+    {
+        let out = match &self.out {
+            Some(out) => out,
+            None => return f(),
+        };
+
+        let start = Instant::now();
+        let res = f();
+        let elapsed = start.elapsed();
+        let line = match &res {
+            Ok(value) => {
+                let summary = value.trace_summary();
+                if summary.is_empty() {
+                    format!("{}({}) = CKR_OK [{:?}]", function, args, elapsed)
+                } else {
+                    format!(
+                        "{}({}) = CKR_OK [{:?}] -> {}",
+                        function, args, elapsed, summary
+                    )
+                }
+            }
+            Err(cryptoki::error::Error::Pkcs11(rv)) => {
+                format!("{}({}) = {} [{:?}]", function, args, ckr_name(*rv), elapsed)
+            }
+            Err(err) => format!("{}({}) failed: {} [{:?}]", function, args, err, elapsed),
+        };
+
+        // Tracing is a diagnostic aid so a failure to write the trace must not fail the call
+        if let Ok(mut out) = out.lock() {
+            let _ = writeln!(out, "PKCS#11 {}", line);
+            let _ = out.flush();
+        }
+
+        res
+    }
+}
+
+/// A short description of the value returned by a Cryptoki call, for inclusion in the trace.
+pub(crate) trait TraceSummary {
+    fn trace_summary(&self) -> String;
+}
+
+impl TraceSummary for () {
+    fn trace_summary(&self) -> String {
+        String::new()
+    }
+}
+
+impl TraceSummary for Session {
+    fn trace_summary(&self) -> String {
+        String::new()
+    }
+}
+
+impl TraceSummary for RawSession {
+    fn trace_summary(&self) -> String {
+        String::new()
+    }
+}
+
+impl TraceSummary for Info {
+    fn trace_summary(&self) -> String {
+        format!(
+            "manufacturer '{}', library '{}' version {}, Cryptoki version {}",
+            self.manufacturer_id().trim_end(),
+            self.library_description().trim_end(),
+            self.library_version(),
+            self.cryptoki_version()
+        )
+    }
+}
+
+impl TraceSummary for TokenInfo {
+    fn trace_summary(&self) -> String {
+        format!(
+            "label '{}', manufacturer '{}', model '{}', serial '{}'",
+            self.label().trim_end(),
+            self.manufacturer_id().trim_end(),
+            self.model().trim_end(),
+            self.serial_number().trim_end()
+        )
+    }
+}
+
+impl TraceSummary for Vec<Slot> {
+    fn trace_summary(&self) -> String {
+        let ids = self.iter().map(|slot| slot.id().to_string());
+        format!("slots [{}]", ids.collect::<Vec<_>>().join(", "))
+    }
+}
+
+impl TraceSummary for Vec<CK_OBJECT_HANDLE> {
+    fn trace_summary(&self) -> String {
+        let handles = self.iter().take(MAX_TRACED_HANDLES).map(|h| h.to_string());
+        let more = if self.len() > MAX_TRACED_HANDLES {
+            ", ..."
+        } else {
+            ""
+        };
+        format!(
+            "{} objects [{}{}]",
+            self.len(),
+            handles.collect::<Vec<_>>().join(", "),
+            more
+        )
+    }
+}
+
+impl TraceSummary for ObjectHandle {
+    fn trace_summary(&self) -> String {
+        format!("object {}", self)
+    }
+}
+
+impl TraceSummary for Vec<Attribute> {
+    fn trace_summary(&self) -> String {
+        format!("{:?}", self)
+    }
+}
+
+impl TraceSummary for Vec<AttributeInfo> {
+    fn trace_summary(&self) -> String {
+        format!("{:?}", self)
+    }
+}
+
+/// Data such as a signature, whose value is not traced.
+impl TraceSummary for Vec<u8> {
+    fn trace_summary(&self) -> String {
+        format!("{} bytes", self.len())
+    }
+}
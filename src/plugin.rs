@@ -0,0 +1,244 @@
+//! Key sources implemented by external plugin programs.
+//!
+//! A plugin named `name` is an executable called `keyls-plugin-name` found via the `PATH` environment variable. For
+//! each operation keyls runs the plugin, writes a single JSON request object to its standard input and then reads one
+//! JSON object per line from its standard output. See the README for a description of the protocol.
+use std::{
+    io::{BufRead, BufReader, Write},
+    ops::ControlFlow,
+    process::{Command, Stdio},
+    time::Instant,
+};
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    algorithm::{canonical_name, with_key_size},
+    config::{Opt, PluginServerOpt, ServerOpt},
+    error::{ClassifiedError, ErrorClass, KeyError},
+    key::{Key, KeyType},
+    source::{Capabilities, KeySource},
+    timings::Timings,
+};
+
+/// The version of the plugin protocol implemented by this version of keyls.
+const PROTOCOL_VERSION: u32 = 1;
+
+const PLUGIN_PROGRAM_PREFIX: &str = "keyls-plugin-";
+
+/// A key source implemented by an external plugin program.
+pub struct PluginSource {
+    program: String,
+    settings: String,
+    /// Only list keys whose algorithm matches this --alg pattern.
+    alg_filter: Option<String>,
+    timings: Timings,
+}
+
+#[derive(Serialize)]
+struct PluginRequest<'a> {
+    version: u32,
+    operation: &'a str,
+    settings: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PluginResponse {
+    Key {
+        id: String,
+        #[serde(rename = "type")]
+        typ: PluginKeyType,
+        #[serde(default)]
+        name: Option<String>,
+        #[serde(default)]
+        algorithm: Option<String>,
+        #[serde(default)]
+        length: Option<u32>,
+        #[serde(default)]
+        token: Option<String>,
+    },
+    Error {
+        error: String,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum PluginKeyType {
+    Public,
+    Private,
+}
+
+impl<'a> KeySource<'a> for PluginSource {
+    fn connect(opt: &'a Opt) -> Result<Self> {
+        if let ServerOpt::Plugin(PluginServerOpt { name, settings }) = &opt.server {
+            if name.is_empty() || name.contains(std::path::is_separator) {
+                bail!("Invalid plugin name '{}'", name);
+            }
+            Ok(Self {
+                program: format!("{}{}", PLUGIN_PROGRAM_PREFIX, name),
+                settings: settings.clone(),
+                alg_filter: opt.alg.clone(),
+                timings: Timings::default(),
+            })
+        } else {
+            bail!("Expected plugin settings")
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::default()
+    }
+
+    /// The plugin does not say how it spends its time so the whole of each run counts as enumeration.
+    fn timings(&self) -> &Timings {
+        &self.timings
+    }
+
+    fn get_keys(
+        &mut self,
+        on_key: &(dyn Fn(Key) -> ControlFlow<()> + Sync),
+    ) -> Result<Vec<KeyError>> {
+        self.run("list", None, on_key)
+    }
+
+    fn get_keys_by_id(&mut self, id: &str) -> Result<Vec<Key>> {
+        let keys = std::sync::Mutex::new(Vec::new());
+        let failures = self.run("get", Some(id), &|key| {
+            keys.lock().unwrap().push(key);
+            ControlFlow::Continue(())
+        })?;
+        if let Some(failure) = failures.into_iter().next() {
+            return Err(failure.into());
+        }
+        Ok(keys.into_inner().unwrap())
+    }
+}
+
+impl PluginSource {
+    /// Run the plugin to perform the given operation, passing each key that it outputs to `on_key` until it asks to
+    /// stop, at which point the plugin is killed, and returning the errors that it output for individual keys.
+    #[tracing::instrument(skip(self, on_key), fields(program = %self.program))]
+    fn run(
+        &self,
+        operation: &str,
+        id: Option<&str>,
+        on_key: &(dyn Fn(Key) -> ControlFlow<()> + Sync),
+    ) -> Result<Vec<KeyError>> {
+        let start = Instant::now();
+        let res = self.run_plugin(operation, id, on_key);
+        self.timings.record_enumeration(start.elapsed());
+        res
+    }
+
+    fn run_plugin(
+        &self,
+        operation: &str,
+        id: Option<&str>,
+        on_key: &(dyn Fn(Key) -> ControlFlow<()> + Sync),
+    ) -> Result<Vec<KeyError>> {
+        let mut child = Command::new(&self.program)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|err| {
+                ClassifiedError::new(
+                    ErrorClass::Connection,
+                    format!("Failed to run plugin '{}': {}", self.program, err),
+                )
+            })?;
+
+        let request = PluginRequest {
+            version: PROTOCOL_VERSION,
+            operation,
+            settings: &self.settings,
+            id,
+        };
+        {
+            // Close standard input once the request is written so that the plugin sees end of file.
+            let mut stdin = child.stdin.take().expect("plugin stdin is piped");
+            serde_json::to_writer(&mut stdin, &request)?;
+            writeln!(stdin)?;
+        }
+
+        let mut failures = Vec::new();
+        let stdout = child.stdout.take().expect("plugin stdout is piped");
+        for line in BufReader::new(stdout).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str(&line) {
+                Ok(PluginResponse::Key {
+                    id,
+                    typ,
+                    name,
+                    algorithm,
+                    length,
+                    token,
+                }) => {
+                    let len = length
+                        .map(|v| v.to_string())
+                        .unwrap_or_else(|| "unknown".to_string());
+                    let alg = match algorithm {
+                        Some(alg) => with_key_size(&canonical_name(&alg), &len),
+                        None => "unknown".to_string(),
+                    };
+                    let key = Key {
+                        id,
+                        typ: match typ {
+                            PluginKeyType::Public => KeyType::Public,
+                            PluginKeyType::Private => KeyType::Private,
+                        },
+                        name: name.unwrap_or_else(|| "None".to_string()),
+                        alg,
+                        len,
+                        token,
+                        groups: vec![],
+                        app_info: vec![],
+                        always_authenticate: None,
+                        expires: None,
+                        cert: None,
+                        split_key: None,
+                        attrs: Default::default(),
+                    };
+                    if let Some(pattern) = &self.alg_filter {
+                        if !key.alg_matches(pattern) {
+                            continue;
+                        }
+                    }
+                    if on_key(key).is_break() {
+                        // Any further output is not wanted, and the plugin may be blocked writing it
+                        let _ = child.kill();
+                        let _ = child.wait();
+                        return Ok(failures);
+                    }
+                }
+                Ok(PluginResponse::Error { error }) => failures.push(KeyError::new(
+                    format!("Plugin '{}' key", self.program),
+                    ClassifiedError::new(ErrorClass::Server, error).into(),
+                )),
+                Err(err) => failures.push(KeyError::new(
+                    format!("Plugin '{}' output '{}'", self.program, line),
+                    anyhow!("Invalid key: {}", err),
+                )),
+            }
+        }
+
+        let status = child.wait()?;
+        tracing::debug!(%status, "Plugin finished");
+        if !status.success() {
+            return Err(ClassifiedError::new(
+                ErrorClass::Server,
+                format!("Plugin '{}' failed: {}", self.program, status),
+            )
+            .into());
+        }
+
+        Ok(failures)
+    }
+}
@@ -0,0 +1,174 @@
+use std::{
+    io::{Read, Write},
+    net::{Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use tracing::debug;
+use socks::Socks5Stream;
+
+use crate::config::{parse_proxy, Opt, ProxyOpt};
+
+const HTTPS_PROXY_ENV_VAR_NAMES: [&str; 2] = ["HTTPS_PROXY", "https_proxy"];
+const NO_PROXY_ENV_VAR_NAMES: [&str; 2] = ["NO_PROXY", "no_proxy"];
+
+/// Determine which proxy, if any, to use to reach the given host.
+///
+/// An explicit --proxy option takes precedence, otherwise the HTTPS_PROXY environment variable is honoured unless the
+/// host is excluded by the NO_PROXY environment variable.
+pub(crate) fn proxy_for(opt: &Opt, host: &str) -> Result<Option<ProxyOpt>> {
+    if let Some(proxy) = &opt.proxy {
+        return Ok(Some(proxy.clone()));
+    }
+
+    let proxy_url = match first_env_var(&HTTPS_PROXY_ENV_VAR_NAMES) {
+        Some(url) if !url.is_empty() => url,
+        _ => return Ok(None),
+    };
+
+    if let Some(no_proxy) = first_env_var(&NO_PROXY_ENV_VAR_NAMES) {
+        if is_excluded(&no_proxy, host) {
+            debug!(
+                "Not using HTTPS_PROXY for '{}' as it matches NO_PROXY",
+                host
+            );
+            return Ok(None);
+        }
+    }
+
+    parse_proxy(&proxy_url)
+        .map(Some)
+        .with_context(|| format!("Invalid HTTPS_PROXY value '{}'", proxy_url))
+}
+
+/// Establish a TCP connection to the given host and port via the given proxy.
+///
+/// If `resolved` is given it is the address the proxy is asked to connect to instead of the host.
+pub(crate) fn connect(
+    proxy: &ProxyOpt,
+    host: &str,
+    port: u16,
+    resolved: Option<SocketAddr>,
+    connect_timeout: Option<Duration>,
+) -> Result<TcpStream> {
+    match proxy {
+        ProxyOpt::Socks5 {
+            addr,
+            port: proxy_port,
+            user,
+            pass,
+            remote_dns,
+        } => {
+            debug!(
+                "Connecting to {}:{} via SOCKS5 proxy {}:{}",
+                host, port, addr, proxy_port
+            );
+            let proxy_addr = (addr.as_str(), *proxy_port);
+            let stream = match (*remote_dns && resolved.is_none(), user) {
+                (true, None) => Socks5Stream::connect(proxy_addr, (host, port)),
+                (true, Some(user)) => Socks5Stream::connect_with_password(
+                    proxy_addr,
+                    (host, port),
+                    user,
+                    pass.as_deref().unwrap_or_default(),
+                ),
+                (false, None) => {
+                    Socks5Stream::connect(proxy_addr, resolve_target(host, port, resolved)?)
+                }
+                (false, Some(user)) => Socks5Stream::connect_with_password(
+                    proxy_addr,
+                    resolve_target(host, port, resolved)?,
+                    user,
+                    pass.as_deref().unwrap_or_default(),
+                ),
+            }
+            .map_err(|err| anyhow!("SOCKS5 proxy connection failed: {}", err))?;
+            Ok(stream.into_inner())
+        }
+        ProxyOpt::Http {
+            addr,
+            port: proxy_port,
+        } => {
+            debug!(
+                "Connecting to {}:{} via HTTP proxy {}:{}",
+                host, port, addr, proxy_port
+            );
+            let proxy_addr = resolve(addr, *proxy_port)?;
+            let mut stream = match connect_timeout {
+                Some(timeout) => TcpStream::connect_timeout(&proxy_addr, timeout)?,
+                None => TcpStream::connect(proxy_addr)?,
+            };
+            let authority = match resolved {
+                Some(addr) => SocketAddr::new(addr.ip(), port).to_string(),
+                None => authority(host, port),
+            };
+            http_connect(&mut stream, &authority)?;
+            Ok(stream)
+        }
+    }
+}
+
+/// The host and port in the form used by the CONNECT method, with an IPv6 address enclosed in brackets, e.g.
+/// `[::1]:5696`.
+fn authority(host: &str, port: u16) -> String {
+    match host.parse::<Ipv6Addr>() {
+        Ok(addr) => SocketAddr::new(addr.into(), port).to_string(),
+        Err(_) => format!("{}:{}", host, port),
+    }
+}
+
+/// Ask an HTTP proxy to tunnel the stream to the given host and port, see [authority], using the CONNECT method.
+fn http_connect(stream: &mut TcpStream, authority: &str) -> Result<()> {
+    write!(
+        stream,
+        "CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n\r\n",
+        authority = authority
+    )?;
+
+    // Read the response one byte at a time so as not to consume any bytes that belong to the tunnelled connection.
+    let mut response = Vec::new();
+    let mut byte = [0u8; 1];
+    while !response.ends_with(b"\r\n\r\n") {
+        if stream.read(&mut byte)? == 0 {
+            bail!("HTTP proxy closed the connection during CONNECT");
+        }
+        response.push(byte[0]);
+        if response.len() > 8192 {
+            bail!("HTTP proxy CONNECT response is too large");
+        }
+    }
+
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or_default();
+    match status_line.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => bail!("HTTP proxy CONNECT failed: {}", status_line),
+    }
+}
+
+fn resolve(host: &str, port: u16) -> Result<SocketAddr> {
+    (host, port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| anyhow!("Failed to resolve '{}:{}'", host, port))
+}
+
+fn resolve_target(host: &str, port: u16, resolved: Option<SocketAddr>) -> Result<SocketAddr> {
+    match resolved {
+        Some(addr) => Ok(addr),
+        None => resolve(host, port),
+    }
+}
+
+fn first_env_var(names: &[&str]) -> Option<String> {
+    names.iter().find_map(|name| std::env::var(name).ok())
+}
+
+fn is_excluded(no_proxy: &str, host: &str) -> bool {
+    no_proxy
+        .split(',')
+        .map(|entry| entry.trim().trim_start_matches('.'))
+        .filter(|entry| !entry.is_empty())
+        .any(|entry| entry == "*" || host == entry || host.ends_with(&format!(".{}", entry)))
+}
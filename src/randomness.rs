@@ -0,0 +1,223 @@
+//! Quick sanity statistics of random data, as commonly checked when commissioning the random number generator of a
+//! token.
+//!
+//! These only detect a grossly broken generator, e.g. one that is stuck or heavily biased, and are no substitute for a
+//! full statistical test suite such as NIST SP 800-22.
+use std::fmt::Display;
+
+use serde::Serialize;
+
+/// How many standard deviations from its expected value a statistic may be before the test fails, which a good
+/// generator does by chance about once in 16,000 tests.
+const MAX_DEVIATION: f64 = 4.0;
+
+/// The fewest bytes for which the bit tests are meaningful, as NIST SP 800-22 recommends at least 100 bits.
+const MIN_BIT_TEST_BYTES: usize = 13;
+
+/// The fewest bytes for which the byte frequency test is meaningful, i.e. an expected count of 5 for every byte value.
+const MIN_BYTE_TEST_BYTES: usize = 256 * 5;
+
+/// The statistics of some random data.
+#[derive(Debug, Serialize)]
+pub struct Statistics {
+    /// The number of bytes tested.
+    pub bytes: usize,
+    /// The Shannon entropy of the byte values in bits per byte, at most 8.
+    pub entropy: f64,
+    /// The tests that there was enough data to run, leaving out the runs test if the monobit test failed.
+    pub tests: Vec<TestResult>,
+}
+
+/// The result of a single statistical test.
+#[derive(Debug, Serialize)]
+pub struct TestResult {
+    /// The name of the test, e.g. `monobit`.
+    pub name: &'static str,
+    /// The value of the test statistic, e.g. the proportion of one bits for the monobit test.
+    pub statistic: f64,
+    /// How many standard deviations the statistic is from its expected value.
+    pub deviation: f64,
+    pub passed: bool,
+}
+
+impl Statistics {
+    /// Calculate the statistics of the data, running each test that there is enough data for.
+    pub fn of(data: &[u8]) -> Self {
+        let mut tests = Vec::new();
+        if data.len() >= MIN_BIT_TEST_BYTES {
+            let monobit = monobit(data);
+            // As in NIST SP 800-22 the runs test is only meaningful if the proportion of one bits is close to a half
+            let runs = monobit.passed.then(|| runs(data));
+            tests.push(monobit);
+            tests.extend(runs);
+        }
+        if data.len() >= MIN_BYTE_TEST_BYTES {
+            tests.push(byte_frequency(data));
+        }
+        Statistics {
+            bytes: data.len(),
+            entropy: entropy(data),
+            tests,
+        }
+    }
+
+    /// The names of the tests that failed.
+    pub fn failures(&self) -> Vec<&'static str> {
+        self.tests
+            .iter()
+            .filter(|test| !test.passed)
+            .map(|test| test.name)
+            .collect()
+    }
+}
+
+impl Display for Statistics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Statistics of {} random bytes:\n  entropy: {:.4} bits per byte",
+            self.bytes, self.entropy
+        )?;
+        for test in &self.tests {
+            write!(
+                f,
+                "\n  {}: {:.4} ({:+.2} standard deviations), {}",
+                test.name,
+                test.statistic,
+                test.deviation,
+                if test.passed { "passed" } else { "FAILED" }
+            )?;
+        }
+        if self.bytes < MIN_BYTE_TEST_BYTES {
+            write!(
+                f,
+                "\n  Too few bytes to run all tests, at least {} are needed",
+                MIN_BYTE_TEST_BYTES
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl TestResult {
+    fn new(name: &'static str, statistic: f64, expected: f64, std_dev: f64) -> Self {
+        let deviation = (statistic - expected) / std_dev;
+        TestResult {
+            name,
+            statistic,
+            deviation,
+            passed: deviation.abs() <= MAX_DEVIATION,
+        }
+    }
+}
+
+/// The proportion of one bits, which should be close to a half.
+fn monobit(data: &[u8]) -> TestResult {
+    let bits = (data.len() * 8) as f64;
+    let ones: u32 = data.iter().map(|b| b.count_ones()).sum();
+    TestResult::new("monobit", ones as f64 / bits, 0.5, 0.5 / bits.sqrt())
+}
+
+/// The number of runs of identical bits, which detects bits that oscillate too quickly or too slowly, as given by the
+/// NIST SP 800-22 runs test.
+fn runs(data: &[u8]) -> TestResult {
+    let bits = data.len() * 8;
+    let bit = |i: usize| data[i / 8] >> (7 - i % 8) & 1;
+    let runs = 1 + (1..bits).filter(|&i| bit(i) != bit(i - 1)).count();
+    let n = bits as f64;
+    let ones: u32 = data.iter().map(|b| b.count_ones()).sum();
+    let pi = ones as f64 / n;
+    let expected = 2.0 * n * pi * (1.0 - pi);
+    let std_dev = 2.0 * (2.0 * n).sqrt() * pi * (1.0 - pi);
+    TestResult::new("runs", runs as f64, expected, std_dev)
+}
+
+/// The chi-squared statistic of the frequencies of the byte values, which should all be equally common.
+fn byte_frequency(data: &[u8]) -> TestResult {
+    let mut counts = [0usize; 256];
+    for b in data {
+        counts[*b as usize] += 1;
+    }
+    let expected = data.len() as f64 / 256.0;
+    let chi_squared: f64 = counts
+        .iter()
+        .map(|&count| (count as f64 - expected).powi(2) / expected)
+        .sum();
+    // The chi-squared distribution with 255 degrees of freedom has a mean of 255 and a variance of 510
+    TestResult::new("byte frequency", chi_squared, 255.0, 510f64.sqrt())
+}
+
+/// The Shannon entropy of the byte values in bits per byte, which is 0 rather than -0 if all bytes are the same.
+fn entropy(data: &[u8]) -> f64 {
+    let mut counts = [0usize; 256];
+    for b in data {
+        counts[*b as usize] += 1;
+    }
+    let len = data.len() as f64;
+    counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / len;
+            -p * p.log2()
+        })
+        .sum::<f64>()
+        .abs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test<'a>(stats: &'a Statistics, name: &str) -> &'a TestResult {
+        stats
+            .tests
+            .iter()
+            .find(|test| test.name == name)
+            .unwrap_or_else(|| panic!("The {} test was not run", name))
+    }
+
+    #[test]
+    fn stuck_generator_fails() {
+        let stats = Statistics::of(&[0; 4096]);
+        assert_eq!(stats.entropy, 0.0);
+        assert!(!test(&stats, "monobit").passed);
+        assert!(!test(&stats, "byte frequency").passed);
+        // The runs test is not meaningful when the monobit test fails
+        assert_eq!(stats.failures(), ["monobit", "byte frequency"]);
+    }
+
+    #[test]
+    fn alternating_bits_fail() {
+        // Exactly half of the bits are one, but they alternate far too often
+        let stats = Statistics::of(&[0x55; 4096]);
+        assert!(test(&stats, "monobit").passed);
+        assert!(!test(&stats, "runs").passed);
+        assert!(!test(&stats, "byte frequency").passed);
+        assert_eq!(stats.entropy, 0.0);
+    }
+
+    #[test]
+    fn every_byte_value_equally_often() {
+        let data: Vec<u8> = (0..=255).cycle().take(256 * 16).collect();
+        let stats = Statistics::of(&data);
+        assert_eq!(stats.entropy, 8.0);
+        assert!(test(&stats, "monobit").passed);
+        assert_eq!(test(&stats, "byte frequency").statistic, 0.0);
+    }
+
+    #[test]
+    fn too_few_bytes() {
+        let stats = Statistics::of(&[0; MIN_BIT_TEST_BYTES - 1]);
+        assert!(stats.tests.is_empty());
+        assert!(stats.failures().is_empty());
+
+        let stats = Statistics::of(&[0; MIN_BYTE_TEST_BYTES - 1]);
+        let names: Vec<&str> = stats.tests.iter().map(|test| test.name).collect();
+        assert_eq!(names, ["monobit"]);
+
+        let stats = Statistics::of(&[]);
+        assert_eq!((stats.bytes, stats.entropy), (0, 0.0));
+        assert!(stats.tests.is_empty());
+    }
+}
@@ -0,0 +1,39 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Limits the rate at which requests are sent to a key store, shared by every connection or session used to list
+/// keys so that the limit applies to keyls as a whole.
+pub(crate) struct RateLimiter {
+    /// The minimum time between the start of two requests, or None if the rate is not limited.
+    interval: Option<Duration>,
+    next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(max_per_second: Option<u32>) -> Self {
+        Self {
+            interval: max_per_second
+                .filter(|&max| max > 0)
+                .map(|max| Duration::from_secs(1) / max),
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Wait until another request may be sent.
+    pub(crate) fn wait(&self) {
+        if let Some(interval) = self.interval {
+            let now = Instant::now();
+            let start = {
+                let mut next = self.next.lock().unwrap();
+                let start = (*next).max(now);
+                *next = start + interval;
+                start
+            };
+            if start > now {
+                std::thread::sleep(start - now);
+            }
+        }
+    }
+}
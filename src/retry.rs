@@ -0,0 +1,73 @@
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use tracing::{debug, debug_span, warn};
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(250);
+const MAX_BACKOFF: Duration = Duration::from_secs(8);
+
+/// Invoke `f` until it succeeds, fails with an error that `is_transient` rejects, or `retries` retries have been
+/// made, sleeping for an exponentially increasing interval between attempts.
+pub(crate) fn with_retries<T, F, P>(
+    retries: u32,
+    what: &str,
+    is_transient: P,
+    mut f: F,
+) -> Result<T>
+where
+    F: FnMut() -> Result<T>,
+    P: Fn(&anyhow::Error) -> bool,
+{
+    let _span = debug_span!("request", what).entered();
+    let mut backoff = INITIAL_BACKOFF;
+    let mut attempt = 0;
+    loop {
+        let start = Instant::now();
+        let res = f();
+        debug!(
+            attempt = attempt + 1,
+            elapsed = ?start.elapsed(),
+            ok = res.is_ok(),
+            "{} finished",
+            what
+        );
+        match res {
+            Ok(res) => return Ok(res),
+            Err(err) if attempt < retries && is_transient(&err) => {
+                attempt += 1;
+                warn!(
+                    "{} failed (attempt {} of {}), retrying in {:?}: {}",
+                    what,
+                    attempt,
+                    retries + 1,
+                    backoff,
+                    err
+                );
+                std::thread::sleep(backoff);
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Is this an I/O error that may not occur again if the operation is retried?
+pub(crate) fn is_transient_io_error(err: &anyhow::Error) -> bool {
+    use std::io::ErrorKind::*;
+
+    err.chain()
+        .filter_map(|cause| cause.downcast_ref::<std::io::Error>())
+        .any(|err| {
+            matches!(
+                err.kind(),
+                ConnectionRefused
+                    | ConnectionReset
+                    | ConnectionAborted
+                    | BrokenPipe
+                    | TimedOut
+                    | WouldBlock
+                    | Interrupted
+                    | UnexpectedEof
+            )
+        })
+}
@@ -0,0 +1,144 @@
+use std::{ops::ControlFlow, sync::Mutex};
+
+use anyhow::Result;
+use serde::Serialize;
+use tracing::warn;
+
+use crate::{
+    config::Opt,
+    error::{ClassifiedError, ErrorClass, KeyError},
+    key::Key,
+    timings::Timings,
+};
+
+/// The optional behaviour supported by a [KeySource].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Capabilities {
+    /// Keys can be retrieved in parallel using multiple connections or sessions.
+    pub parallel: bool,
+
+    /// Multiple keys can be retrieved per request.
+    pub batching: bool,
+
+    /// Keys can be listed from more than one token at once.
+    pub multiple_tokens: bool,
+}
+
+/// An algorithm with which a private key can sign data, see [KeySource::sign].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SignatureAlgorithm {
+    /// RSASSA-PKCS1-v1_5 with SHA-256.
+    RsaPkcs1Sha256,
+    /// ECDSA with SHA-256.
+    EcdsaSha256,
+    /// ECDSA with SHA-384.
+    EcdsaSha384,
+    /// ECDSA with SHA-512.
+    EcdsaSha512,
+}
+
+/// A key exported wrapped (i.e. encrypted) by another key, see [KeySource::export_wrapped].
+///
+/// Serializes to the metadata needed to later unwrap the key, which does not include the wrapped key itself.
+#[derive(Debug, Serialize)]
+pub struct WrappedKey {
+    /// The wrapped key.
+    #[serde(skip)]
+    pub blob: Vec<u8>,
+    /// The key that was wrapped, as it is listed.
+    pub key: Key,
+    /// The ID of the key that wrapped it.
+    pub wrapping_key: String,
+    /// How the key was wrapped, e.g. `CKM_AES_KEY_WRAP_PAD (RFC 5649)` for PKCS#11 or `NISTKeyWrap (RFC 3394)` for KMIP.
+    pub mechanism: String,
+    /// The encoding of the key before it was wrapped, e.g. `PKCS8`.
+    pub format: String,
+}
+
+/// A backend that can list the keys held by a particular kind of cryptographic token.
+pub trait KeySource<'a> {
+    /// Connect to the key source identified by the server option.
+    fn connect(opt: &'a Opt) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// The optional behaviour supported by this key source.
+    fn capabilities(&self) -> Capabilities;
+
+    /// How long this key source has spent retrieving keys so far.
+    fn timings(&self) -> &Timings;
+
+    /// Retrieve the keys held by the key source, passing each to `on_key` as soon as it has been retrieved.
+    ///
+    /// Keys that cannot be retrieved are skipped rather than causing the whole operation to fail, and are returned once
+    /// all other keys have been retrieved. If `on_key` returns [ControlFlow::Break] no further keys are retrieved,
+    /// though keys that are already being retrieved in parallel may still be passed to it.
+    fn get_keys(
+        &mut self,
+        on_key: &(dyn Fn(Key) -> ControlFlow<()> + Sync),
+    ) -> Result<Vec<KeyError>>;
+
+    /// Retrieve the keys with the given ID.
+    ///
+    /// This can be more than one key as e.g. the private and public key of a PKCS#11 key pair usually share an ID.
+    fn get_keys_by_id(&mut self, id: &str) -> Result<Vec<Key>>;
+
+    /// The public key of the key pair with the given ID, as a DER encoded SubjectPublicKeyInfo.
+    ///
+    /// Not all key sources support this, by default it fails with an [ErrorClass::Argument] error.
+    fn public_key(&mut self, _id: &str) -> Result<Vec<u8>> {
+        Err(unsupported("Getting the public key of a key pair"))
+    }
+
+    /// Sign the data with the private key with the given ID, returning the signature in the form used by X.509, i.e.
+    /// DER encoded for ECDSA.
+    ///
+    /// Not all key sources support this, by default it fails with an [ErrorClass::Argument] error.
+    fn sign(&mut self, _id: &str, _alg: SignatureAlgorithm, _data: &[u8]) -> Result<Vec<u8>> {
+        Err(unsupported("Signing"))
+    }
+
+    /// Store the DER encoded X.509 certificate with the key pair with the given ID, returning the ID of the new
+    /// certificate object.
+    ///
+    /// Not all key sources support this, by default it fails with an [ErrorClass::Argument] error.
+    fn import_cert(&mut self, _id: &str, _cert: &[u8]) -> Result<String> {
+        Err(unsupported("Importing a certificate"))
+    }
+
+    /// Export the key with the given ID wrapped by the key with the given wrapping key ID, e.g. for backup.
+    ///
+    /// Not all key sources support this, by default it fails with an [ErrorClass::Argument] error.
+    fn export_wrapped(&mut self, _id: &str, _wrapping_key_id: &str) -> Result<WrappedKey> {
+        Err(unsupported("Exporting a wrapped key"))
+    }
+
+    /// Generate the given number of bytes of random data with the random number generator of the key source.
+    ///
+    /// Not all key sources support this, by default it fails with an [ErrorClass::Argument] error.
+    fn random(&mut self, _len: u32) -> Result<Vec<u8>> {
+        Err(unsupported("Generating random data"))
+    }
+
+    /// Retrieve all of the keys held by the key source, logging and skipping those that cannot be retrieved.
+    fn list_keys(&mut self) -> Result<Vec<Key>> {
+        let keys = Mutex::new(Vec::new());
+        let failures = self.get_keys(&|key| {
+            keys.lock().unwrap().push(key);
+            ControlFlow::Continue(())
+        })?;
+        for failure in failures {
+            warn!("Skipped key: {}", failure);
+        }
+        Ok(keys.into_inner().unwrap())
+    }
+}
+
+/// The error for an operation that a kind of key source does not support.
+pub(crate) fn unsupported(what: &str) -> anyhow::Error {
+    ClassifiedError::new(
+        ErrorClass::Argument,
+        format!("{} is not supported by this kind of server", what),
+    )
+    .into()
+}
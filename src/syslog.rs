@@ -0,0 +1,80 @@
+//! Logging to the local syslog daemon (or journald) via its `/dev/log` socket, for `--log-syslog`.
+use std::{io::Write, os::unix::net::UnixDatagram};
+
+use anyhow::{Context, Result};
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+const SYSLOG_SOCKET: &str = "/dev/log";
+
+/// The LOG_USER facility, as keyls is neither a daemon nor part of the system.
+const FACILITY_USER: u8 = 1;
+
+/// Creates a writer per log event which sends the event to syslog as a single message with a severity matching the
+/// level of the event.
+pub(crate) struct SyslogMakeWriter {
+    socket: UnixDatagram,
+    tag: String,
+}
+
+pub(crate) struct SyslogWriter<'a> {
+    make_writer: &'a SyslogMakeWriter,
+    severity: u8,
+}
+
+impl SyslogMakeWriter {
+    pub(crate) fn connect() -> Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket
+            .connect(SYSLOG_SOCKET)
+            .with_context(|| format!("Cannot connect to syslog via {}", SYSLOG_SOCKET))?;
+        Ok(Self {
+            socket,
+            tag: format!("keyls[{}]", std::process::id()),
+        })
+    }
+}
+
+impl<'a> MakeWriter<'a> for SyslogMakeWriter {
+    type Writer = SyslogWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        SyslogWriter {
+            make_writer: self,
+            severity: 6,
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        let severity = match *meta.level() {
+            Level::ERROR => 3,
+            Level::WARN => 4,
+            Level::INFO => 6,
+            Level::DEBUG | Level::TRACE => 7,
+        };
+        SyslogWriter {
+            make_writer: self,
+            severity,
+        }
+    }
+}
+
+impl Write for SyslogWriter<'_> {
+    /// The fmt subscriber writes each formatted event with a single call, so each call is sent as one message.
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let msg = String::from_utf8_lossy(buf);
+        let line = format!(
+            "<{}>{}: {}",
+            FACILITY_USER * 8 + self.severity,
+            self.make_writer.tag,
+            msg.trim_end()
+        );
+        // A lost log message must not cause keyls itself to fail
+        let _ = self.make_writer.socket.send(line.as_bytes());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
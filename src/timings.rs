@@ -0,0 +1,101 @@
+//! Measurement of how long the phases of listing keys take, reported by `--timings`.
+use std::{fmt::Display, sync::Mutex, time::Duration};
+
+use serde::Serialize;
+
+/// The time spent enumerating the keys held by a key source and retrieving their attributes, shared by every
+/// connection or session used to list keys.
+#[derive(Debug, Default)]
+pub struct Timings {
+    enumeration: Mutex<Duration>,
+    keys: Mutex<Vec<Duration>>,
+}
+
+impl Timings {
+    /// Record time spent finding out which keys the key source holds.
+    pub(crate) fn record_enumeration(&self, elapsed: Duration) {
+        *self.enumeration.lock().unwrap() += elapsed;
+    }
+
+    /// Record the time taken to retrieve the attributes of `count` keys in a single request, sharing it equally
+    /// between the keys.
+    pub(crate) fn record_keys(&self, elapsed: Duration, count: usize) {
+        if count > 0 {
+            let per_key = elapsed / count as u32;
+            self.keys
+                .lock()
+                .unwrap()
+                .extend(std::iter::repeat_n(per_key, count));
+        }
+    }
+
+    /// Summarise the recorded times together with the given time taken to connect to the key source.
+    pub fn report(&self, connect: Duration) -> TimingReport {
+        let mut keys = self.keys.lock().unwrap().clone();
+        keys.sort();
+        let percentile = |p: usize| {
+            // Nearest rank, so that e.g. the 99th percentile of fewer than 100 keys is the slowest key
+            let rank = (keys.len() * p).div_ceil(100).max(1);
+            keys.get(rank - 1).copied().unwrap_or_default()
+        };
+
+        TimingReport {
+            connect: Millis(connect),
+            enumeration: Millis(*self.enumeration.lock().unwrap()),
+            attributes: Millis(keys.iter().sum()),
+            keys: keys.len(),
+            per_key_p50: Millis(percentile(50)),
+            per_key_p90: Millis(percentile(90)),
+            per_key_p99: Millis(percentile(99)),
+            per_key_max: Millis(keys.last().copied().unwrap_or_default()),
+        }
+    }
+}
+
+/// How long each phase of listing keys took, in milliseconds when serialized.
+#[derive(Debug, Serialize)]
+pub struct TimingReport {
+    pub connect: Millis,
+    pub enumeration: Millis,
+    /// The total time spent retrieving key attributes, across all connections or sessions.
+    pub attributes: Millis,
+    /// The number of keys whose attributes were retrieved.
+    pub keys: usize,
+    pub per_key_p50: Millis,
+    pub per_key_p90: Millis,
+    pub per_key_p99: Millis,
+    pub per_key_max: Millis,
+}
+
+impl Display for TimingReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Connection setup:     {}", self.connect)?;
+        writeln!(f, "Object enumeration:   {}", self.enumeration)?;
+        writeln!(
+            f,
+            "Attribute retrieval:  {} for {} keys",
+            self.attributes, self.keys
+        )?;
+        write!(
+            f,
+            "Per key:              p50 {}, p90 {}, p99 {}, max {}",
+            self.per_key_p50, self.per_key_p90, self.per_key_p99, self.per_key_max
+        )
+    }
+}
+
+/// A duration which is displayed and serialized as a number of milliseconds.
+#[derive(Clone, Copy, Debug)]
+pub struct Millis(pub Duration);
+
+impl Display for Millis {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:.3}ms", self.0.as_secs_f64() * 1000.0)
+    }
+}
+
+impl Serialize for Millis {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_f64(self.0.as_secs_f64() * 1000.0)
+    }
+}
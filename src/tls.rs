@@ -0,0 +1,371 @@
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs},
+};
+
+use anyhow::{anyhow, bail, Result};
+use kmip::{
+    client::{Client, ClientBuilder, ClientCertificate, ConnectionSettings},
+    Config,
+};
+use openssl::{
+    error::ErrorStack,
+    hash::MessageDigest,
+    ocsp::{OcspCertId, OcspCertStatus, OcspFlag, OcspResponse, OcspResponseStatus},
+    pkey::PKey,
+    ssl::{
+        SslConnector, SslFiletype, SslMethod, SslRef, SslStream, SslVerifyMode, SslVersion,
+        StatusType,
+    },
+    x509::{
+        store::X509Lookup,
+        verify::{X509CheckFlags, X509VerifyFlags},
+        X509NameRef, X509Ref, X509,
+    },
+};
+use tracing::{debug, enabled, error, info, Level};
+
+use crate::{
+    config::{Opt, TlsVersion},
+    error::{ClassifiedError, ErrorClass},
+    kmiptrace::TracingStream,
+    proxy,
+};
+
+const SSLKEYLOGFILE_ENV_VAR_NAME: &str = "SSLKEYLOGFILE";
+
+pub(crate) type KmipClient = Client<TracingStream<SslStream<TcpStream>>>;
+
+/// Clock skew in seconds to tolerate when checking the validity period of an OCSP response.
+const OCSP_VALIDITY_LEEWAY_SECS: u32 = 300;
+
+/// Connect to a KMIP server using TLS.
+///
+/// This mirrors the connection logic of the kmip-protocol crate OpenSSL "plugin" but builds the TLS connector itself
+/// so that TLS settings which the crate does not expose (e.g. protocol version bounds) can be applied.
+pub(crate) fn connect(conn_settings: &ConnectionSettings, opt: &Opt) -> Result<KmipClient> {
+    let resolved = resolve_override(conn_settings, opt);
+    info!(
+        host = %conn_settings.host,
+        port = conn_settings.port,
+        resolved = ?resolved,
+        "Connecting to KMIP server"
+    );
+    let tcp_stream = match proxy::proxy_for(opt, &conn_settings.host)? {
+        Some(proxy) => proxy::connect(
+            &proxy,
+            &conn_settings.host,
+            conn_settings.port,
+            resolved,
+            conn_settings.connect_timeout,
+        )?,
+        None => {
+            let addr = match resolved {
+                Some(addr) => addr,
+                None => format!("{}:{}", conn_settings.host, conn_settings.port)
+                    .to_socket_addrs()?
+                    .next()
+                    .ok_or_else(|| anyhow!("Failed to parse KMIP server address:port"))?,
+            };
+            match conn_settings.connect_timeout {
+                Some(timeout) => TcpStream::connect_timeout(&addr, timeout)?,
+                None => TcpStream::connect(addr)?,
+            }
+        }
+    };
+    tcp_stream.set_read_timeout(conn_settings.read_timeout)?;
+    tcp_stream.set_write_timeout(conn_settings.write_timeout)?;
+
+    let tls_connector = create_tls_connector(conn_settings, opt)?;
+    let mut tls_config = tls_connector.configure()?;
+    if opt.ocsp {
+        tls_config.set_status_type(StatusType::OCSP)?;
+    }
+
+    // Take control of SNI and hostname verification so that they can differ from the address connected to.
+    let sni = opt.sni.as_deref().unwrap_or(&conn_settings.host);
+    let verify_hostname = opt.verify_hostname.as_deref().unwrap_or(sni);
+    tls_config.set_use_server_name_indication(false);
+    tls_config.set_verify_hostname(false);
+    if sni.parse::<IpAddr>().is_err() {
+        // RFC 6066 section 3: literal IPv4 and IPv6 addresses are not permitted in SNI
+        tls_config.set_hostname(sni)?;
+    }
+    let param = tls_config.param_mut();
+    param.set_hostflags(X509CheckFlags::NO_PARTIAL_WILDCARDS);
+    match verify_hostname.parse::<IpAddr>() {
+        Ok(ip) => param.set_ip(ip)?,
+        Err(_) => param.set_host(verify_hostname)?,
+    }
+
+    if enabled!(Level::INFO) {
+        // Log the certificate that failed verification, if any, as the handshake error alone does not say which.
+        let verify_mode = tls_config.verify_mode();
+        tls_config.set_verify_callback(verify_mode, |preverify_ok, ctx| {
+            if !preverify_ok {
+                if let Some(cert) = ctx.current_cert() {
+                    info!(
+                        "TLS verification failed at depth {} ({}): {}",
+                        ctx.error_depth(),
+                        ctx.error(),
+                        describe_cert(cert)
+                    );
+                }
+            }
+            preverify_ok
+        });
+    }
+
+    let tls_stream = tls_config.connect(sni, tcp_stream).map_err(|err| {
+        ClassifiedError::new(
+            ErrorClass::Tls,
+            format!("Failed to establish TLS connection: {}", err),
+        )
+    })?;
+    log_tls_details(tls_stream.ssl());
+    let tls_stream = TracingStream::new(tls_stream, opt.trace_kmip.as_ref())?;
+
+    let mut client = ClientBuilder::new(tls_stream);
+    if let Some(username) = &conn_settings.username {
+        client = client.with_credentials(username.clone(), conn_settings.password.clone());
+    }
+    let mut reader_config = Config::default().with_read_buf();
+    if let Some(max_bytes) = conn_settings.max_response_bytes {
+        reader_config = reader_config.with_max_bytes(max_bytes);
+    }
+
+    Ok(client.with_reader_config(reader_config).build())
+}
+
+/// Log the negotiated TLS parameters and the certificate chain presented by the server.
+fn log_tls_details(ssl: &SslRef) {
+    if !enabled!(Level::INFO) {
+        return;
+    }
+
+    info!(
+        "Negotiated {} with cipher suite {}",
+        ssl.version_str(),
+        ssl.current_cipher().map(|c| c.name()).unwrap_or("unknown")
+    );
+    if let Some(chain) = ssl.peer_cert_chain() {
+        for (depth, cert) in chain.iter().enumerate() {
+            info!("Server certificate {}: {}", depth, describe_cert(cert));
+        }
+    }
+}
+
+fn describe_cert(cert: &X509Ref) -> String {
+    let fingerprint = cert
+        .digest(MessageDigest::sha256())
+        .map(hex::encode_upper)
+        .unwrap_or_else(|_| "unknown".to_string());
+    format!(
+        "subject [{}], issuer [{}], valid from {} until {}, SHA-256 fingerprint {}",
+        describe_name(cert.subject_name()),
+        describe_name(cert.issuer_name()),
+        cert.not_before(),
+        cert.not_after(),
+        fingerprint
+    )
+}
+
+fn describe_name(name: &X509NameRef) -> String {
+    name.entries()
+        .map(|entry| {
+            let key = entry.object().nid().short_name().unwrap_or("?");
+            let value = entry
+                .data()
+                .as_utf8()
+                .map(|v| v.to_string())
+                .unwrap_or_else(|_| "?".to_string());
+            format!("{}={}", key, value)
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Find the address given by --resolve, if any, to connect to instead of resolving the KMIP server host and port.
+fn resolve_override(conn_settings: &ConnectionSettings, opt: &Opt) -> Option<SocketAddr> {
+    opt.resolve
+        .iter()
+        .find(|r| r.port == conn_settings.port && r.host.eq_ignore_ascii_case(&conn_settings.host))
+        .map(|r| {
+            debug!(
+                "Resolving '{}:{}' to {} as instructed by --resolve",
+                r.host, r.port, r.addr
+            );
+            SocketAddr::new(r.addr, r.port)
+        })
+}
+
+fn create_tls_connector(conn_settings: &ConnectionSettings, opt: &Opt) -> Result<SslConnector> {
+    let mut tls_connector = SslConnector::builder(SslMethod::tls())?;
+
+    if let (Some(min), Some(max)) = (opt.tls_min_version, opt.tls_max_version) {
+        if min > max {
+            bail!(
+                "Minimum TLS version {} is higher than maximum TLS version {}",
+                min,
+                max
+            );
+        }
+    }
+    tls_connector.set_min_proto_version(opt.tls_min_version.map(ssl_version))?;
+    tls_connector.set_max_proto_version(opt.tls_max_version.map(ssl_version))?;
+
+    if conn_settings.insecure {
+        tls_connector.set_verify(SslVerifyMode::NONE);
+    } else {
+        if let Some(cert_bytes) = &conn_settings.server_cert {
+            for cert in parse_certs(cert_bytes, "server certificate")? {
+                tls_connector.cert_store_mut().add_cert(cert)?;
+            }
+        }
+        if let Some(cert_bytes) = &conn_settings.ca_cert {
+            for cert in parse_certs(cert_bytes, "CA certificate")? {
+                tls_connector.cert_store_mut().add_cert(cert)?;
+            }
+        }
+
+        if !opt.crl_paths.is_empty() {
+            let lookup = tls_connector
+                .cert_store_mut()
+                .add_lookup(X509Lookup::file())?;
+            for path in &opt.crl_paths {
+                let path_str = path
+                    .to_str()
+                    .ok_or_else(|| anyhow!("CRL path '{}' is not valid UTF-8", path.display()))?;
+                lookup
+                    .load_crl_file(path_str, SslFiletype::PEM)
+                    .map_err(|err| {
+                        anyhow!("Failed to load CRL file '{}': {}", path.display(), err)
+                    })?;
+            }
+            tls_connector
+                .cert_store_mut()
+                .set_flags(X509VerifyFlags::CRL_CHECK | X509VerifyFlags::CRL_CHECK_ALL)?;
+        }
+
+        if opt.ocsp {
+            tls_connector.set_status_callback(verify_ocsp_status)?;
+        }
+    }
+
+    match &conn_settings.client_cert {
+        None => {}
+        Some(ClientCertificate::CombinedPkcs12 { .. }) => {
+            bail!("PKCS#12 client certificate format is not supported")
+        }
+        Some(ClientCertificate::SeparatePem {
+            cert_bytes,
+            key_bytes,
+        }) => {
+            // The kmip-protocol crate calls these PEM but they can be either PEM or DER
+            let cert = parse_certs(cert_bytes, "client certificate")?.remove(0);
+            tls_connector.set_certificate(&cert)?;
+            if let Some(key_bytes) = key_bytes {
+                let pkey = if is_pem(key_bytes) {
+                    PKey::private_key_from_pem(key_bytes)
+                } else {
+                    PKey::private_key_from_der(key_bytes)
+                }
+                .map_err(|err| {
+                    anyhow!("Failed to parse client certificate private key: {}", err)
+                })?;
+                tls_connector.set_private_key(&pkey)?;
+            }
+        }
+    }
+
+    // Preserve the kmip-protocol crate behaviour of supporting TLS session key logging for use with Wireshark.
+    if std::env::var(SSLKEYLOGFILE_ENV_VAR_NAME).is_ok() {
+        tls_connector.set_keylog_callback(|_, line| {
+            if let Ok(path) = std::env::var(SSLKEYLOGFILE_ENV_VAR_NAME) {
+                if let Ok(mut file) = OpenOptions::new().append(true).open(path) {
+                    writeln!(file, "{}", line).ok();
+                }
+            }
+        });
+    }
+
+    Ok(tls_connector.build())
+}
+
+/// Parse the certificates in a PEM file, of which there can be more than one e.g. in a CA bundle, or the single
+/// certificate in a DER file.
+fn parse_certs(bytes: &[u8], what: &str) -> Result<Vec<X509>> {
+    let certs = if is_pem(bytes) {
+        X509::stack_from_pem(bytes)
+    } else {
+        X509::from_der(bytes).map(|cert| vec![cert])
+    }
+    .map_err(|err| anyhow!("Failed to parse {}: {}", what, err))?;
+    if certs.is_empty() {
+        bail!("Failed to parse {}: no certificates found", what);
+    }
+    Ok(certs)
+}
+
+/// Is the file PEM encoded rather than DER encoded, i.e. does it contain a PEM header such as
+/// `-----BEGIN CERTIFICATE-----`?
+fn is_pem(bytes: &[u8]) -> bool {
+    bytes.windows(11).any(|window| window == b"-----BEGIN ")
+}
+
+/// Verify the OCSP response stapled by the server, rejecting the connection if it is missing or does not show the
+/// server certificate as good.
+fn verify_ocsp_status(ssl: &mut SslRef) -> std::result::Result<bool, ErrorStack> {
+    let response = match ssl.ocsp_status() {
+        Some(bytes) => OcspResponse::from_der(bytes)?,
+        None => {
+            error!("OCSP check failed: the KMIP server did not staple an OCSP response");
+            return Ok(false);
+        }
+    };
+    if response.status() != OcspResponseStatus::SUCCESSFUL {
+        error!(
+            "OCSP check failed: unsuccessful OCSP response status {:?}",
+            response.status()
+        );
+        return Ok(false);
+    }
+
+    let chain = match ssl.verified_chain().or_else(|| ssl.peer_cert_chain()) {
+        Some(chain) if chain.len() >= 2 => chain,
+        _ => {
+            error!("OCSP check failed: the KMIP server certificate issuer is not known");
+            return Ok(false);
+        }
+    };
+    let (cert, issuer) = (&chain[0], &chain[1]);
+
+    let basic = response.basic()?;
+    basic.verify(chain, ssl.ssl_context().cert_store(), OcspFlag::empty())?;
+
+    let cert_id = OcspCertId::from_cert(MessageDigest::sha1(), cert, issuer)?;
+    match basic.find_status(&cert_id) {
+        Some(status) if status.status == OcspCertStatus::GOOD => {
+            status.check_validity(OCSP_VALIDITY_LEEWAY_SECS, None)?;
+            Ok(true)
+        }
+        Some(status) if status.status == OcspCertStatus::REVOKED => {
+            error!("OCSP check failed: the KMIP server certificate has been revoked");
+            Ok(false)
+        }
+        _ => {
+            error!("OCSP check failed: the KMIP server certificate status is unknown");
+            Ok(false)
+        }
+    }
+}
+
+fn ssl_version(version: TlsVersion) -> SslVersion {
+    match version {
+        TlsVersion::Tls1_0 => SslVersion::TLS1,
+        TlsVersion::Tls1_1 => SslVersion::TLS1_1,
+        TlsVersion::Tls1_2 => SslVersion::TLS1_2,
+        TlsVersion::Tls1_3 => SslVersion::TLS1_3,
+    }
+}
@@ -1,6 +1,11 @@
-use std::path::Path;
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::Path,
+    time::{Duration, UNIX_EPOCH},
+};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 
 pub fn load_binary_file(path: &Path) -> Result<Vec<u8>> {
     use std::{fs::File, io::Read};
@@ -10,3 +15,55 @@ pub fn load_binary_file(path: &Path) -> Result<Vec<u8>> {
 
     Ok(bytes)
 }
+
+/// Open the destination for a wire level trace, appending to the given file or if none writing to stderr.
+pub(crate) fn open_trace_output(path: Option<&Path>) -> Result<Box<dyn Write + Send>> {
+    match path {
+        None => Ok(Box::new(std::io::stderr())),
+        Some(path) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("Cannot open trace file '{}'", path.display()))?;
+            Ok(Box::new(file))
+        }
+    }
+}
+
+/// Format a number of seconds since the Unix epoch in RFC 3339 format, e.g. `2024-01-31T12:00:00Z`.
+pub(crate) fn format_unix_time(secs: u64) -> String {
+    humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(secs)).to_string()
+}
+
+/// Does the text match the glob pattern, in which `*` matches any number of characters and `?` matches any single
+/// character?
+pub(crate) fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+    let (mut p, mut t) = (0, 0);
+    // Where to resume matching if the text so far cannot be matched, i.e. just after the last `*` seen and the position
+    // in the text that it should next try to absorb.
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p + 1, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star_p, star_t)) => {
+                    p = star_p;
+                    t = star_t + 1;
+                    backtrack = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}